@@ -1,22 +1,88 @@
-use std::{
-    io::{Read, Write},
-    path::PathBuf,
-};
+use std::{collections::VecDeque, io::Read, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use crate::file::VictoryFile;
+use crate::{file::VictoryFile, utils::file_utils::file_write_atomic};
+
+/// Magic bytes every binary batch starts with, followed by [`BATCH_VERSION`]
+/// and the bincode encoded batch. Batches without them are YAML.
+/// bincode can not tell which fields were left out, so the batch types never
+/// skip serializing any.
+pub const BATCH_MAGIC: &[u8; 4] = b"VBAT";
+pub const BATCH_VERSION: u8 = 1;
+
+/// Limits a batch is closed at during discovery, whichever is reached first
+/// # Fields:
+/// - files: Files in a batch
+/// - bytes: Total size of the files in a batch, as listed, 0 for no limit.
+///   A file that reaches it on its own gets a batch of its own.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BatchLimits {
+    pub files: u64,
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+impl BatchLimits {
+    pub fn new(files: u64) -> BatchLimits {
+        BatchLimits { files, bytes: 0 }
+    }
+
+    pub fn with_bytes(mut self, bytes: u64) -> BatchLimits {
+        self.bytes = bytes;
+        self
+    }
+
+    /// Takes the files of the next batch off the front of `files`
+    pub fn take_batch(&self, files: &mut VecDeque<VictoryFile>) -> Vec<VictoryFile> {
+        let mut batch = Vec::new();
+        let mut bytes = 0;
+        while let Some(file) = files.front() {
+            let size = FileBatch::get_file_size(file);
+            let full = match self.bytes {
+                0 => false,
+                limit => !batch.is_empty() && bytes + size > limit,
+            };
+            if batch.len() as u64 >= self.files || full {
+                break;
+            }
+            bytes += size;
+            batch.extend(files.pop_front());
+        }
+        batch
+    }
+}
+
+/// Batches limited by file count only
+impl From<u64> for BatchLimits {
+    fn from(files: u64) -> BatchLimits {
+        BatchLimits::new(files)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileBatch {
     pub files: Vec<VictoryFile>,
     name: String,
+    /// Id of the source every file in the batch was discovered in
+    #[serde(default)]
+    source: String,
+    /// Entries the source filtered out while listing this batch
+    #[serde(default)]
+    filtered: usize,
 }
 
 impl FileBatch {
     pub fn new(name: String) -> FileBatch {
+        FileBatch::new_from_source(name, "".to_string())
+    }
+
+    pub fn new_from_source(name: String, source: String) -> FileBatch {
         FileBatch {
             files: Vec::new(),
-            name: name,
+            name,
+            source,
+            filtered: 0,
         }
     }
 
@@ -24,10 +90,35 @@ impl FileBatch {
         self.name.clone()
     }
 
+    pub fn get_source(&self) -> String {
+        self.source.clone()
+    }
+
+    pub fn get_filtered(&self) -> usize {
+        self.filtered
+    }
+
+    pub fn set_filtered(&mut self, filtered: usize) {
+        self.filtered = filtered;
+    }
+
     pub fn get_length(&self) -> usize {
         self.files.len()
     }
 
+    /// Listed size of the contents of every file in the batch
+    pub fn get_size(&self) -> u64 {
+        self.files.iter().map(FileBatch::get_file_size).sum()
+    }
+
+    /// Listed size of a file's contents, links and directories have none
+    fn get_file_size(file: &VictoryFile) -> u64 {
+        match file.kind.has_contents() {
+            true => file.size as u64,
+            false => 0,
+        }
+    }
+
     pub fn add_file(&mut self, file: VictoryFile) {
         self.files.push(file);
     }
@@ -38,65 +129,59 @@ impl FileBatch {
         }
     }
 
+    /// Saves the batch in the binary format, see [`BATCH_MAGIC`]
     pub fn save_batch(&self, path: PathBuf) -> Result<usize, String> {
-        match path.parent() {
-            Some(parent) => match std::fs::create_dir_all(parent) {
-                Ok(_) => (),
-                Err(err) => return Err(format!("save_batch Error: {:?}", err)),
-            },
-            None => return Err(format!("save_batch Error: Invalid path {:?}", path)),
+        if path.parent().is_none() {
+            return Err(format!("save_batch Error: Invalid path {:?}", path));
         }
 
-        let mut file = match std::fs::File::create(path) {
-            Ok(file) => file,
-            Err(err) => return Err(format!("Error: {:?}", err)),
-        };
-        /*
-        let serialized = match bincode::serialize(&self){
-            Ok(serialized) => serialized,
-            Err(err) => return Err(format!("Error: {:?}", err)),
-        };
-        match file.write_all(serialized.as_slice()){
-            Ok(_) => Ok(serialized.len()),
-            Err(err) => Err(format!("Error: {:?}", err)),
-        } */
-
-        // Save as Yaml
-        let yaml = serde_yaml::to_string(&self).expect("Error serializing plan");
-        match file.write_all(yaml.as_bytes()) {
-            Ok(_) => Ok(yaml.len()),
-            Err(err) => Err(format!("plan_save_error: {:?}", err)),
+        let mut serialized = Vec::new();
+        serialized.extend_from_slice(BATCH_MAGIC);
+        serialized.push(BATCH_VERSION);
+        if let Err(err) = bincode::serialize_into(&mut serialized, self) {
+            return Err(format!("save_batch Error: {:?}", err));
         }
+        // Replaced in a single step, a run killed while saving keeps the previous batch
+        file_write_atomic(&path, &serialized)?;
+        Ok(serialized.len())
     }
 
+    /// Loads a batch in the binary format, or in YAML as batches were saved before
     pub fn load_batch(path: PathBuf) -> Result<FileBatch, String> {
         let mut file = match std::fs::File::open(path.clone()) {
             Ok(file) => file,
             Err(err) => return Err(format!("Error: {:?}", err)),
         };
-        /*
-         let mut serialized = Vec::new();
-        match file.read_to_end(&mut serialized) {
-            Ok(_) => (),
-            Err(err) => return Err(format!("Error: {:?}", err)),
-        };
-        let batch = match bincode::deserialize(&serialized) {
-            Ok(batch) => batch,
-            Err(err) => return Err(format!("Error: {:?}", err)),
-        };
-         */
+        let mut serialized = Vec::new();
+        if let Err(err) = file.read_to_end(&mut serialized) {
+            return Err(format!("Error: {:?}", err));
+        }
 
-        // Load from Yaml
-        let mut yaml = String::new();
-        match file.read_to_string(&mut yaml) {
-            Ok(_) => (),
-            Err(err) => return Err(format!("Error: {:?}", err)),
+        let Some(binary) = serialized.strip_prefix(BATCH_MAGIC) else {
+            return match serde_yaml::from_slice(&serialized) {
+                Ok(batch) => Ok(batch),
+                Err(err) => Err(format!("Error: {:?}", err)),
+            };
         };
-        let batch: FileBatch = match serde_yaml::from_str(&yaml) {
-            Ok(batch) => batch,
-            Err(err) => return Err(format!("Error: {:?}", err)),
-        };
-        Ok(batch)
+        match binary.split_first() {
+            Some((&BATCH_VERSION, batch)) => match bincode::deserialize(batch) {
+                Ok(batch) => Ok(batch),
+                Err(err) => Err(format!("load_batch Error: {:?}", err)),
+            },
+            Some((version, _)) => Err(format!(
+                "load_batch Error: unsupported format version {} in {:?}",
+                version, path
+            )),
+            None => Err(format!("load_batch Error: truncated batch {:?}", path)),
+        }
+    }
+
+    /// Readable dump of the batch, e.g. to look into a binary batch
+    pub fn to_yaml(&self) -> Result<String, String> {
+        match serde_yaml::to_string(&self) {
+            Ok(yaml) => Ok(yaml),
+            Err(err) => Err(format!("Error: {:?}", err)),
+        }
     }
 
     pub fn get_files(&mut self) -> &mut Vec<VictoryFile> {
@@ -106,8 +191,13 @@ impl FileBatch {
 
 #[cfg(test)]
 mod file_batch_tests {
+    use std::path::Path;
+
     use super::*;
-    use crate::file::VictoryFile;
+    use crate::{
+        file::{FileKind, FileState, KeptCopy, VictoryFile},
+        metadata::FileMetadata,
+    };
 
     #[test]
     fn test_add_file() {
@@ -129,6 +219,108 @@ mod file_batch_tests {
         assert_eq!(batch.files[1], file2);
     }
 
+    #[test]
+    fn test_take_batch() {
+        let sized = |name: &str, size: usize| {
+            let mut file = VictoryFile::new(&PathBuf::from(name));
+            file.size = size;
+            file
+        };
+        let mut files: VecDeque<VictoryFile> = VecDeque::from(vec![
+            sized("a", 10),
+            sized("b", 10),
+            sized("huge", 500),
+            sized("c", 60),
+            sized("d", 60),
+            sized("e", 10),
+            sized("f", 10),
+            sized("g", 10),
+        ]);
+        let limits = BatchLimits::new(3).with_bytes(100);
+        let mut batches = Vec::new();
+        while !files.is_empty() {
+            let batch = limits.take_batch(&mut files);
+            batches.push(
+                batch
+                    .iter()
+                    .map(|file| file.name.clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(
+            batches,
+            vec![
+                vec!["a", "b"],
+                vec!["huge"],
+                vec!["c"],
+                vec!["d", "e", "f"],
+                vec!["g"]
+            ]
+        );
+
+        // No byte limit, only the file count
+        let mut files: VecDeque<VictoryFile> =
+            VecDeque::from(vec![sized("a", 10), sized("huge", 500), sized("b", 10)]);
+        assert_eq!(BatchLimits::from(2).take_batch(&mut files).len(), 2);
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_binary_format() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut batch = FileBatch::new_from_source("test".to_string(), "src".to_string());
+        let mut file = VictoryFile::new(Path::new(OsStr::from_bytes(b"caf\xe9.txt")));
+        file.size = 42;
+        file.hash = "abc123".to_string();
+        file.source = "src".to_string();
+        file.state = FileState::Stored;
+        file.contents = Some(b"\x00contents".to_vec());
+        file.reason = Some("unchanged".to_string());
+        file.kept_as = vec![KeptCopy {
+            dest: "dest".to_string(),
+            path: PathBuf::from(OsStr::from_bytes(b"caf\xe9 (1).txt")),
+        }];
+        let mut metadata = FileMetadata {
+            mode: 0o644,
+            ..Default::default()
+        };
+        metadata
+            .xattrs
+            .insert("user.tag".to_string(), b"\x00blue".to_vec());
+        file.metadata = Some(metadata);
+        batch.add_file(file);
+        let mut link = VictoryFile::new(&PathBuf::from("latest"));
+        link.kind = FileKind::Symlink(PathBuf::from("data/file_0"));
+        batch.add_file(link);
+        let mut hardlink = VictoryFile::new(&PathBuf::from("again"));
+        hardlink.kind = FileKind::Hardlink(PathBuf::from("data/file_0"));
+        batch.add_file(hardlink);
+        let mut folder = VictoryFile::new(&PathBuf::from("data"));
+        folder.kind = FileKind::Directory;
+        batch.add_file(folder);
+        batch.add_file(VictoryFile::new(&PathBuf::from("plain")));
+        batch.set_filtered(3);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("test_batch.vbak_batch");
+        batch.save_batch(path.clone()).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!(&saved[..4], BATCH_MAGIC);
+        assert_eq!(saved[4], BATCH_VERSION);
+        assert_eq!(FileBatch::load_batch(path.clone()).unwrap(), batch);
+
+        // Batches saved before the binary format are YAML
+        std::fs::write(&path, batch.to_yaml().unwrap()).unwrap();
+        assert_eq!(FileBatch::load_batch(path.clone()).unwrap(), batch);
+
+        let mut newer = saved.clone();
+        newer[4] = BATCH_VERSION + 1;
+        std::fs::write(&path, newer).unwrap();
+        let err = FileBatch::load_batch(path.clone()).unwrap_err();
+        assert!(err.contains("unsupported format version"), "{}", err);
+    }
+
     #[test]
     fn test_get_name() {
         let batch = FileBatch::new("test".to_string());
@@ -157,7 +349,6 @@ mod file_batch_tests {
         let temp_dir = tempfile::tempdir().unwrap();
         let path = temp_dir.path().join("test_batch.victory");
 
-        println!("Path: {:?}", path);
 
         batch.save_batch(path.clone()).unwrap();
         let batch2 = FileBatch::load_batch(path.clone()).unwrap();
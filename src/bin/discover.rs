@@ -1,4 +1,4 @@
-use log::LevelFilter;
+use log::{error, info, LevelFilter};
 use memory_stats::memory_stats;
 use simplelog::*;
 use std::{fs::File, path::Path};
@@ -36,6 +36,10 @@ fn main() {
 
     plan.save_plan(&test_meta_dir.to_path_buf()).unwrap();
     let res = Executor::discover(&mut plan, 50);
+    match res {
+        Ok(res) => info!("Discovered {} files in {} batches", res.files, res.batches),
+        Err(e) => error!("Discovery failed: {:?}", e),
+    }
 
     plan.save_plan(&test_meta_dir.to_path_buf()).unwrap();
 
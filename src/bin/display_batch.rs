@@ -0,0 +1,25 @@
+use std::{path::PathBuf, process::exit};
+
+use victory_archive::batch::FileBatch;
+
+/// Prints a saved batch as YAML, whichever format it was saved in
+///
+/// Usage: display_batch <path to .vbak_batch>
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprintln!("Usage: display_batch <path to .vbak_batch>");
+            exit(2);
+        }
+    };
+
+    let yaml = FileBatch::load_batch(path.clone()).and_then(|batch| batch.to_yaml());
+    match yaml {
+        Ok(yaml) => print!("{}", yaml),
+        Err(err) => {
+            eprintln!("Could not display batch {:?}: {}", path, err);
+            exit(1);
+        }
+    }
+}
@@ -1,7 +1,4 @@
-use std::{
-    fs::File,
-    path::{self, Path},
-};
+use std::{fs::File, path::Path};
 
 use log::{info, LevelFilter};
 use simplelog::*;
@@ -24,14 +21,13 @@ fn main() {
     ])
     .unwrap();
     let test_data_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data");
-    let test_dest_dir = test_data_dir.join("_dest");
     let test_meta_dir = test_data_dir.join("__vk");
     let test_plan_path = test_meta_dir.join("TestData.yaml");
     let saved_plan = plan::BackupPlan::load_saved(test_plan_path.to_path_buf()).unwrap();
 
     info!("Plan loaded: {:?}", saved_plan);
 
-    let mut plan = plan::BackupPlan::from_saved(saved_plan);
+    let plan = plan::BackupPlan::from_saved(saved_plan).unwrap();
 
     let res = Executor::run(&plan);
     match res {
@@ -1,70 +1,1062 @@
 use std::{
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
     fs,
-    path::{self, Path, PathBuf},
+    io::{BufWriter, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use log::debug;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
 
-use crate::file::VictoryFile;
+use crate::{
+    file::{FileKind, VictoryFile},
+    metadata::{read_xattrs, FileMetadata},
+    middleware::filter_glob::{GlobFilter, GlobFilterConfig},
+    utils::os_path,
+};
+
+use super::{
+    registry::BackendConfig, Backend, ConflictPolicy, Sink, SinkWriter, Source, WriteDecision,
+};
+
+/// Options for the `filesystem` backend
+/// # Fields:
+/// - path: Root folder files are listed from or written to
+/// - include / exclude: Gitignore style patterns applied while listing files
+/// - xattrs: Capture extended attributes (incl. ACLs, capabilities, SELinux labels) while listing files
+/// - follow_links: Back up what symlinks point to instead of the links themselves
+/// - on_conflict: What to do when writing over a file that already exists (default: overwrite)
+/// - versions: Keep every replaced file in `.vversions/<path>@<timestamp>` instead of losing it
+/// - walk_threads: Threads walking the folder while listing files, 1 walks it one entry at a time
+///   (default: 0, one per CPU)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileSystemOptions {
+    #[serde(with = "crate::utils::os_path")]
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub xattrs: bool,
+    #[serde(default)]
+    pub follow_links: bool,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    #[serde(default)]
+    pub versions: bool,
+    #[serde(default)]
+    pub walk_threads: usize,
+}
+
+/// An earlier version of a file, kept when it was replaced
+/// # Fields:
+/// - path: Path of the file, relative to the destination root
+/// - replaced_at: When the version was replaced by a newer one
+/// - stored_at: Where the version is kept inside the version store
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileVersion {
+    pub path: PathBuf,
+    pub replaced_at: SystemTime,
+    pub stored_at: PathBuf,
+}
 
-use super::Destination;
+/// A file read by either walker, with its inode if it has more names that
+/// still have to be linked to the first one listed
+type ListedEntry = (VictoryFile, Option<(u64, u64)>);
 
+/// What a walker does with an entry it found
 #[derive(Debug)]
-pub struct FileSystemDestination {
-    path: String,
+enum Walked {
+    Listed(Box<ListedEntry>),
+    /// Left out by the patterns, filtered directories are never entered
+    Filtered,
+    /// The version store, never entered
+    Pruned,
+    /// The root folder, special files and entries that could not be read
+    Ignored,
+}
+
+/// Bytes a writer collects before writing them to its temp file
+const WRITE_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Folders a parallel walk lists ahead of the one being handed out, so
+/// listings waiting for a slow folder before them never pile up in memory
+const WALK_AHEAD: usize = 64;
+
+/// Listed folder of a parallel walk: its path relative to the root, and what
+/// the walker does with each of its entries, sorted by file name
+type WalkedFolder = (PathBuf, Vec<Walked>);
+
+/// What the threads of a parallel walk need to read entries
+#[derive(Debug)]
+struct WalkSettings {
+    root: PathBuf,
+    filter: Option<GlobFilter>,
+    xattrs: bool,
+    follow_links: bool,
+}
+
+/// Folder waiting to be listed by a walking thread
+/// # Fields:
+/// - path: Path of the folder relative to the root
+/// - depth: Depth of its entries, the root's own entries are at 1
+/// - ancestors: (device, inode) of the folder and its parents, so followed
+///   links looping back to one of them are not walked again
+#[derive(Debug)]
+struct WalkJob {
+    path: PathBuf,
+    depth: usize,
+    ancestors: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct WalkQueueState {
+    /// Folders left to list, the last one is taken first
+    jobs: Vec<WalkJob>,
+    /// Folders being listed, that may still add their subfolders
+    listing: usize,
+    /// Folders taken to be listed and not handed out yet, at most [`WALK_AHEAD`]
+    /// besides the wanted one
+    ahead: usize,
+    /// Folder whose entries are handed out next, taken even when enough
+    /// folders are listed ahead
+    wanted: Option<PathBuf>,
+    stopped: bool,
+}
+
+/// Folders left to list, shared by the threads of a parallel walk. Every
+/// idle thread takes the next one, so a large folder never holds up the others.
+#[derive(Debug, Default)]
+struct WalkQueue {
+    state: Mutex<WalkQueueState>,
+    changed: Condvar,
+}
+
+impl WalkQueue {
+    /// Waits for a folder to list, none once every folder was listed or the walk stopped
+    fn next_job(&self) -> Option<WalkJob> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return None;
+            }
+            let wanted = state
+                .jobs
+                .iter()
+                .rposition(|job| Some(&job.path) == state.wanted.as_ref());
+            let next = match wanted {
+                Some(wanted) => Some(state.jobs.remove(wanted)),
+                None if state.ahead < WALK_AHEAD => state.jobs.pop(),
+                None => None,
+            };
+            if let Some(job) = next {
+                state.listing += 1;
+                state.ahead += 1;
+                return Some(job);
+            }
+            if state.jobs.is_empty() && state.listing == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Marks the folder whose entries are handed out next
+    fn want(&self, folder: &Path) {
+        self.state.lock().unwrap().wanted = Some(folder.to_path_buf());
+        self.changed.notify_all();
+    }
+
+    /// Frees the place of a listed folder once its entries are handed out
+    fn hand_out(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.ahead -= 1;
+        state.wanted = None;
+        self.changed.notify_all();
+    }
+
+    /// Adds the subfolders of a listed folder, the first one by name is taken next
+    fn finish_job(&self, subfolders: Vec<WalkJob>) {
+        let mut state = self.state.lock().unwrap();
+        state.listing -= 1;
+        state.jobs.extend(subfolders.into_iter().rev());
+        self.changed.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+    }
+}
+
+/// A parallel walk in progress. Threads list folders as they go and send each
+/// one through a bounded channel, entries are handed out in the order the
+/// sequential walk lists them as soon as their folder arrived. Threads only
+/// list [`WALK_AHEAD`] folders ahead of the ones handed out. Dropping it
+/// stops the threads.
+#[derive(Debug)]
+struct ParallelWalk {
+    queue: Arc<WalkQueue>,
+    receiver: Receiver<WalkedFolder>,
+    /// Folders that arrived before the ones listed ahead of them were handed out
+    received: HashMap<PathBuf, Vec<Walked>>,
+    /// Entries left to hand out of every folder being handed out, innermost last
+    pending: Vec<VecDeque<Walked>>,
+    /// Folder whose entries are handed out next
+    next_folder: Option<PathBuf>,
+}
+
+/// Entry of a folder listed by a walking thread
+struct WalkerPath {
+    path: PathBuf,
+    depth: usize,
+    file_type: fs::FileType,
+    through_symlink: bool,
+    follow_links: bool,
+}
+
+/// Entries found by the sequential (`walkdir`) and the parallel (`ignore`) walker
+trait WalkerEntry {
+    fn entry_path(&self) -> &Path;
+    fn entry_depth(&self) -> usize;
+    fn entry_type(&self) -> Option<fs::FileType>;
+    /// Whether the entry was reached through a followed symlink
+    fn through_symlink(&self) -> bool;
+    fn entry_metadata(&self) -> Result<fs::Metadata, String>;
+}
+
+impl WalkerEntry for walkdir::DirEntry {
+    fn entry_path(&self) -> &Path {
+        self.path()
+    }
+
+    fn entry_depth(&self) -> usize {
+        self.depth()
+    }
+
+    fn entry_type(&self) -> Option<fs::FileType> {
+        Some(self.file_type())
+    }
+
+    fn through_symlink(&self) -> bool {
+        self.path_is_symlink()
+    }
+
+    fn entry_metadata(&self) -> Result<fs::Metadata, String> {
+        self.metadata()
+            .map_err(|err| format!("metadata Error: {:?}", err))
+    }
+}
+
+impl WalkerEntry for WalkerPath {
+    fn entry_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn entry_depth(&self) -> usize {
+        self.depth
+    }
+
+    fn entry_type(&self) -> Option<fs::FileType> {
+        Some(self.file_type)
+    }
+
+    fn through_symlink(&self) -> bool {
+        self.through_symlink
+    }
+
+    fn entry_metadata(&self) -> Result<fs::Metadata, String> {
+        let metadata = match self.follow_links {
+            true => fs::metadata(&self.path),
+            false => fs::symlink_metadata(&self.path),
+        };
+        metadata.map_err(|err| format!("metadata Error: {:?}", err))
+    }
+}
+
+impl ParallelWalk {
+    fn start(settings: WalkSettings, threads: usize) -> ParallelWalk {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        };
+        let ancestors = match fs::metadata(&settings.root) {
+            Ok(root) => vec![(root.dev(), root.ino())],
+            Err(_) => Vec::new(),
+        };
+        let queue = Arc::new(WalkQueue::default());
+        queue.state.lock().unwrap().jobs.push(WalkJob {
+            path: PathBuf::new(),
+            depth: 1,
+            ancestors,
+        });
+        let settings = Arc::new(settings);
+        let (sender, receiver) = mpsc::sync_channel(WALK_AHEAD);
+        for _ in 0..threads {
+            let (settings, queue, sender) = (settings.clone(), queue.clone(), sender.clone());
+            std::thread::spawn(move || ParallelWalk::walk_folders(&settings, &queue, &sender));
+        }
+        ParallelWalk {
+            queue,
+            receiver,
+            received: HashMap::new(),
+            pending: Vec::new(),
+            next_folder: Some(PathBuf::new()),
+        }
+    }
+
+    /// Lists folders until none are left, run by every walking thread
+    fn walk_folders(settings: &WalkSettings, queue: &WalkQueue, sender: &SyncSender<WalkedFolder>) {
+        while let Some(job) = queue.next_job() {
+            let (walked, subfolders) = ParallelWalk::list_folder(settings, &job);
+            // Nobody hands out the entries anymore
+            if sender.send((job.path, walked)).is_err() {
+                queue.stop();
+            }
+            queue.finish_job(subfolders);
+        }
+    }
+
+    /// Reads the entries of a folder sorted by file name, with the subfolders
+    /// to walk into. A folder that can not be read has no entries.
+    fn list_folder(settings: &WalkSettings, job: &WalkJob) -> (Vec<Walked>, Vec<WalkJob>) {
+        let full_path = settings.root.join(&job.path);
+        let mut entries: Vec<fs::DirEntry> = match fs::read_dir(&full_path) {
+            Ok(entries) => entries
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry),
+                    Err(err) => {
+                        log::warn!("ListError: {:?}", err);
+                        None
+                    }
+                })
+                .collect(),
+            Err(err) => {
+                log::warn!("ListError: {:?} with path {:?}", err, full_path);
+                return (Vec::new(), Vec::new());
+            }
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut walked = Vec::new();
+        let mut subfolders = Vec::new();
+        for entry in entries {
+            let path = entry.path();
+            let mut file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    log::warn!("ListError: {:?} with path {:?}", err, path);
+                    continue;
+                }
+            };
+            let through_symlink = settings.follow_links && file_type.is_symlink();
+            if through_symlink {
+                file_type = match fs::metadata(&path) {
+                    Ok(target) => target.file_type(),
+                    Err(err) => {
+                        log::warn!("ListError: {:?} with path {:?}", err, path);
+                        continue;
+                    }
+                };
+            }
+            let entry = WalkerPath {
+                path,
+                depth: job.depth,
+                file_type,
+                through_symlink,
+                follow_links: settings.follow_links,
+            };
+            let walked_entry = FileSystemDestination::walk_entry(
+                &settings.root,
+                settings.filter.as_ref(),
+                settings.xattrs,
+                &entry,
+            );
+            match &walked_entry {
+                Walked::Listed(listed) if listed.0.kind == FileKind::Directory => {
+                    let mut ancestors = job.ancestors.clone();
+                    if settings.follow_links {
+                        let folder = match fs::metadata(&entry.path) {
+                            Ok(folder) => (folder.dev(), folder.ino()),
+                            Err(err) => {
+                                log::warn!("ListError: {:?} with path {:?}", err, entry.path);
+                                continue;
+                            }
+                        };
+                        if ancestors.contains(&folder) {
+                            log::warn!("ListError: {:?} links back to a parent folder", entry.path);
+                            continue;
+                        }
+                        ancestors.push(folder);
+                    }
+                    subfolders.push(WalkJob {
+                        path: listed.0.path.clone(),
+                        depth: job.depth + 1,
+                        ancestors,
+                    });
+                }
+                _ => (),
+            }
+            walked.push(walked_entry);
+        }
+        (walked, subfolders)
+    }
+
+    /// Next entry in the order of the sequential walk, waiting for its folder
+    /// to be listed if needed
+    fn next(&mut self) -> Option<Walked> {
+        if let Some(folder) = self.next_folder.take() {
+            let walked = self.take_folder(&folder);
+            self.pending.push(walked.into());
+        }
+        loop {
+            let walked = match self.pending.last_mut()?.pop_front() {
+                Some(walked) => walked,
+                None => {
+                    self.pending.pop();
+                    continue;
+                }
+            };
+            // Entries of a folder come right after it
+            if let Walked::Listed(listed) = &walked {
+                if listed.0.kind == FileKind::Directory {
+                    self.next_folder = Some(listed.0.path.clone());
+                }
+            }
+            return Some(walked);
+        }
+    }
+
+    fn take_folder(&mut self, folder: &Path) -> Vec<Walked> {
+        self.queue.want(folder);
+        loop {
+            if let Some(walked) = self.received.remove(folder) {
+                self.queue.hand_out();
+                return walked;
+            }
+            match self.receiver.recv() {
+                Ok((path, walked)) => {
+                    self.received.insert(path, walked);
+                }
+                // Every thread is done without listing it
+                Err(_) => return Vec::new(),
+            }
+        }
+    }
+}
+
+impl Drop for ParallelWalk {
+    fn drop(&mut self) {
+        self.queue.stop();
+    }
+}
+
+/// How far listing the folder got, behind a lock so the folder can be listed
+/// while workers read from it
+#[derive(Debug)]
+struct Listing {
     walk_itr: walkdir::IntoIter,
+    /// Parallel walk, started on the first `list_files_next`
+    walked: Option<ParallelWalk>,
+    filtered: usize,
+    /// First path listed for every (device, inode) with more than one link
+    inodes: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Listing {
+    fn new(path: &Path, follow_links: bool) -> Listing {
+        Listing {
+            walk_itr: FileSystemDestination::walk(path, follow_links),
+            walked: None,
+            filtered: 0,
+            inodes: HashMap::new(),
+        }
+    }
+
+    /// Later names of an inode link to the first one listed instead of copying it
+    fn link_inode(&mut self, (mut file, inode): ListedEntry) -> VictoryFile {
+        if let Some(inode) = inode {
+            match self.inodes.get(&inode) {
+                Some(first) => file.kind = FileKind::Hardlink(first.clone()),
+                None => {
+                    self.inodes.insert(inode, file.path.clone());
+                }
+            }
+        }
+        file
+    }
+}
+
+#[derive(Debug)]
+pub struct FileSystemDestination {
+    path: PathBuf,
+    /// Id set in the config, see [`Backend::get_id`]
+    id: Option<String>,
+    listing: Mutex<Listing>,
+    /// Threads of the parallel walker, 1 lists through `walkdir` instead
+    walk_threads: usize,
+    /// Patterns set for this source only, saved back into its config
+    filter_config: GlobFilterConfig,
+    /// Own patterns combined with any added through `Source::add_filter`
+    filter: Option<GlobFilter>,
+    /// Whether listed files get their extended attributes captured
+    xattrs: bool,
+    follow_links: bool,
+    on_conflict: ConflictPolicy,
+    /// Whether replaced files are kept in the version store
+    versions: bool,
 }
 
 impl FileSystemDestination {
-    pub fn new(path: String) -> FileSystemDestination {
+    pub const KIND: &'static str = "filesystem";
+    /// Folder in the root holding replaced files, never listed as a file itself
+    pub const VERSIONS_DIR: &'static str = ".vversions";
+
+    pub fn new(path: impl Into<PathBuf>) -> FileSystemDestination {
+        let path = path.into();
         debug!("Creating FileSystemDestination: {:?}", path);
-        let walk_itr = walkdir::WalkDir::new(path.clone())
-            .sort_by_file_name()
-            .into_iter();
+        let listing = Mutex::new(Listing::new(&path, false));
         FileSystemDestination {
-            path: path,
-            walk_itr: walk_itr,
+            path,
+            id: None,
+            listing,
+            walk_threads: 0,
+            filter_config: GlobFilterConfig::default(),
+            filter: None,
+            xattrs: false,
+            follow_links: false,
+            on_conflict: ConflictPolicy::default(),
+            versions: false,
+        }
+    }
+
+    fn walk(path: &Path, follow_links: bool) -> walkdir::IntoIter {
+        walkdir::WalkDir::new(path)
+            .follow_links(follow_links)
+            .sort_by_file_name()
+            .into_iter()
+    }
+
+    pub fn from_config(config: &BackendConfig) -> Result<FileSystemDestination, String> {
+        let options: FileSystemOptions = config.parse_options()?;
+        FileSystemDestination::new(options.path)
+            .with_id(config.id.clone())
+            .with_xattrs(options.xattrs)
+            .with_follow_links(options.follow_links)
+            .with_conflict_policy(options.on_conflict)
+            .with_versions(options.versions)
+            .with_walk_threads(options.walk_threads)
+            .with_filter(GlobFilterConfig::new(options.include, options.exclude))
+    }
+
+    /// Sets the id the backend is known by in its plan, instead of the one
+    /// derived from its path
+    pub fn with_id(mut self, id: Option<String>) -> FileSystemDestination {
+        self.id = id;
+        self
+    }
+
+    /// Sets whether extended attributes are captured while listing files.
+    /// Written files always get the attributes they were captured with.
+    pub fn with_xattrs(mut self, xattrs: bool) -> FileSystemDestination {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Sets whether symlinks are followed while listing files. Followed links
+    /// are backed up as the files they point to, otherwise they are stored as
+    /// links to their target. Restarts listing from the first file.
+    pub fn with_follow_links(mut self, follow_links: bool) -> FileSystemDestination {
+        self.follow_links = follow_links;
+        self.listing = Mutex::new(Listing::new(&self.path, follow_links));
+        self
+    }
+
+    /// Sets how many threads walk the folder while listing files, 0 for one
+    /// per CPU. Parallel walks start on the first listing and hand files out
+    /// in the order a sequential walk (1 thread) lists them, as soon as their
+    /// folder was read.
+    pub fn with_walk_threads(mut self, walk_threads: usize) -> FileSystemDestination {
+        self.walk_threads = walk_threads;
+        self
+    }
+
+    /// Sets how files already at the destination are handled when writing
+    pub fn with_conflict_policy(mut self, on_conflict: ConflictPolicy) -> FileSystemDestination {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Sets whether files are moved into the version store before they are
+    /// replaced, see [`FileSystemDestination::list_versions`]
+    pub fn with_versions(mut self, versions: bool) -> FileSystemDestination {
+        self.versions = versions;
+        self
+    }
+
+    fn versions_root(&self) -> Option<PathBuf> {
+        match self.versions {
+            true => Some(Path::new(&self.path).join(Self::VERSIONS_DIR)),
+            false => None,
+        }
+    }
+
+    /// Keeps whatever is at `full_path` in the version store as
+    /// `<dest_path>@<seconds>.<nanoseconds>`, before it is replaced
+    ///
+    /// The version is a hardlink, so the file stays in place until the new one
+    /// is renamed over it. Version stores on another filesystem get a copy.
+    fn keep_version(
+        versions_root: &Path,
+        dest_path: &Path,
+        full_path: &Path,
+    ) -> Result<(), String> {
+        let existing = match fs::symlink_metadata(full_path) {
+            Ok(existing) if existing.is_dir() => return Ok(()),
+            Ok(existing) => existing,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(format!("metadata Error: {:?}", err)),
+        };
+        let replaced_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut name = OsString::from(dest_path.file_name().unwrap_or_default());
+        name.push(format!(
+            "@{}.{:09}",
+            replaced_at.as_secs(),
+            replaced_at.subsec_nanos()
+        ));
+        let version_path = versions_root.join(dest_path).with_file_name(name);
+        FileSystemDestination::create_parent(&version_path)?;
+
+        debug!(
+            "[WriteFile] Keeping version {:?} as {:?}",
+            full_path, version_path
+        );
+        let kept = match fs::hard_link(full_path, &version_path) {
+            Err(_) if existing.is_file() => fs::copy(full_path, &version_path).map(|_| ()),
+            kept => kept,
+        };
+        match kept {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                log::warn!("version Error: {:?} with path {:?}", err, version_path);
+                Err(format!("version Error: {:?}", err))
+            }
+        }
+    }
+
+    /// Lists the kept versions of a file, oldest first
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the file relative to the destination root, e.g. `source/notes.txt`
+    pub fn list_versions(&self, path: &Path) -> Result<Vec<FileVersion>, String> {
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.as_encoded_bytes(),
+            None => return Err(format!("{:?} is not a file", path)),
+        };
+        let stored = Path::new(&self.path).join(Self::VERSIONS_DIR).join(path);
+        let entries = match fs::read_dir(stored.parent().unwrap()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(format!("read_dir Error: {:?}", err)),
+        };
+
+        let mut versions = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Err(format!("read_dir Error: {:?}", err)),
+            };
+            let name = entry.file_name();
+            let timestamp = match name.as_encoded_bytes().strip_prefix(file_name) {
+                Some(rest) => rest.strip_prefix(b"@"),
+                None => None,
+            };
+            let replaced_at = timestamp
+                .and_then(|timestamp| std::str::from_utf8(timestamp).ok())
+                .and_then(|timestamp| timestamp.split_once('.'))
+                .and_then(|(secs, nanos)| Some((secs.parse().ok()?, nanos.parse().ok()?)));
+            if let Some((secs, nanos)) = replaced_at {
+                versions.push(FileVersion {
+                    path: path.to_path_buf(),
+                    replaced_at: UNIX_EPOCH + Duration::new(secs, nanos),
+                    stored_at: entry.path(),
+                });
+            }
+        }
+        versions.sort_by_key(|version| version.replaced_at);
+        Ok(versions)
+    }
+
+    /// Puts an earlier version of a file back in place, with the metadata it
+    /// had. The copy it replaces is kept as a version in turn.
+    pub fn restore_version(&self, version: &FileVersion) -> Result<(), String> {
+        debug!(
+            "[RestoreVersion] {:?} from {:?}",
+            version.path, version.stored_at
+        );
+        let mut file = VictoryFile::new(&version.path);
+        let mut metadata = FileMetadata::from_path(&version.stored_at)?;
+        if self.xattrs {
+            metadata.xattrs = read_xattrs(&version.stored_at)?;
+        }
+        file.metadata = Some(metadata);
+
+        if let Ok(target) = fs::read_link(&version.stored_at) {
+            file.kind = FileKind::Symlink(target);
+            return self.write_link(&file);
+        }
+        let mut stored = match fs::File::open(&version.stored_at) {
+            Ok(stored) => stored,
+            Err(err) => return Err(format!("read Error: {:?}", err)),
+        };
+        let mut writer = self.create_writer(&file)?;
+        if let Err(err) = std::io::copy(&mut stored, &mut writer) {
+            log::warn!("write Error: {:?}", err);
+            return Err(format!("write Error: {:?}", err));
+        }
+        writer.commit()
+    }
+
+    /// Whether `full_path` already is the link a file describes: a symlink to
+    /// the same target, or the same inode as the file a hardlink points to
+    fn is_same_link(&self, file: &VictoryFile, full_path: &Path) -> bool {
+        match &file.kind {
+            FileKind::Symlink(target) => match fs::read_link(full_path) {
+                Ok(existing) => existing == *target,
+                Err(_) => false,
+            },
+            FileKind::Hardlink(target) => {
+                let target = Path::new(&self.path).join(&file.source).join(target);
+                match (fs::symlink_metadata(full_path), fs::metadata(target)) {
+                    (Ok(existing), Ok(target)) => {
+                        existing.is_file()
+                            && (existing.dev(), existing.ino()) == (target.dev(), target.ino())
+                    }
+                    _ => false,
+                }
+            }
+            FileKind::File | FileKind::Directory => false,
+        }
+    }
+
+    /// First numbered variant of a file's path (`notes (1).txt`, `notes (2).txt`, ...)
+    /// that does not exist at the destination yet
+    fn free_path(&self, file: &VictoryFile) -> PathBuf {
+        let stem = file.path.file_stem().unwrap_or_default();
+        let subtree = Path::new(&self.path).join(&file.source);
+        let mut idx = 1;
+        loop {
+            let mut name = stem.to_os_string();
+            name.push(format!(" ({})", idx));
+            if let Some(extension) = file.path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            let candidate = file.path.with_file_name(name);
+            if fs::symlink_metadata(subtree.join(&candidate)).is_err() {
+                return candidate;
+            }
+            idx += 1;
+        }
+    }
+
+    /// Sets the include/exclude patterns of this source
+    pub fn with_filter(
+        mut self,
+        filter_config: GlobFilterConfig,
+    ) -> Result<FileSystemDestination, String> {
+        self.filter = match filter_config.is_empty() {
+            true => None,
+            false => Some(GlobFilter::new(&filter_config)?),
+        };
+        self.filter_config = filter_config;
+        Ok(self)
+    }
+
+    /// Creates the folders a file is written into
+    fn create_parent(full_path: &Path) -> Result<(), String> {
+        let parent = full_path.parent().unwrap();
+        if !parent.exists() {
+            debug!("[WriteFile] Creating dir: {:?}", parent);
+            match fs::create_dir_all(parent) {
+                Ok(_) => (),
+                Err(err) => {
+                    log::warn!("create_dir_all Error: {:?} with path {:?}", err, full_path);
+                    return Err(format!("create_dir_all Error: {:?}", err));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the metadata captured at discovery to a written file, warning
+    /// about every extended attribute the destination could not hold
+    fn apply_metadata(
+        metadata: &Option<FileMetadata>,
+        written: &fs::File,
+        full_path: &Path,
+    ) -> Result<(), String> {
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+        };
+        for dropped in metadata.apply(written)? {
+            log::warn!(
+                "Dropped extended attribute {:?} of {:?}: not supported by the destination",
+                dropped,
+                full_path
+            );
+        }
+        Ok(())
+    }
+
+    /// Decides what to do with an entry found by either walker, reading it if
+    /// it is listed. Free of `self`, so walking threads can call it.
+    fn walk_entry(
+        root: &Path,
+        filter: Option<&GlobFilter>,
+        xattrs: bool,
+        entry: &impl WalkerEntry,
+    ) -> Walked {
+        // Delete the root section of the file path before saving
+        let relative_path = entry.entry_path().strip_prefix(root).unwrap();
+        let file_type = match entry.entry_type() {
+            Some(file_type) => file_type,
+            None => return Walked::Ignored,
+        };
+        let is_dir = file_type.is_dir();
+        let depth = entry.entry_depth();
+        if depth == 1 && is_dir && relative_path == Path::new(Self::VERSIONS_DIR) {
+            return Walked::Pruned;
+        }
+        if depth > 0 && filter.is_some_and(|filter| filter.is_excluded(relative_path, is_dir)) {
+            debug!("Filtered out: {:?}", relative_path);
+            return Walked::Filtered;
+        }
+        if !(file_type.is_file() || file_type.is_symlink() || (is_dir && depth > 0)) {
+            return Walked::Ignored;
+        }
+        match FileSystemDestination::read_entry(entry, relative_path, file_type, xattrs) {
+            Ok(listed) => Walked::Listed(Box::new(listed)),
+            Err(err) => {
+                log::warn!("ListError: {:?}", err);
+                Walked::Ignored
+            }
+        }
+    }
+
+    /// Turns a listed entry into a file, with its metadata (including its
+    /// extended attributes if enabled) and the kind of link it is
+    fn read_entry(
+        entry: &impl WalkerEntry,
+        relative_path: &Path,
+        file_type: fs::FileType,
+        xattrs: bool,
+    ) -> Result<ListedEntry, String> {
+        let mut file = VictoryFile::new(relative_path);
+        let fs_metadata = entry.entry_metadata()?;
+        let mut metadata = FileMetadata::from_fs(&fs_metadata);
+        if xattrs {
+            metadata.xattrs = read_xattrs(entry.entry_path())?;
+        }
+        file.metadata = Some(metadata);
+
+        let mut inode = None;
+        if file_type.is_dir() {
+            file.kind = FileKind::Directory;
+        } else if file_type.is_symlink() {
+            file.kind = match fs::read_link(entry.entry_path()) {
+                Ok(target) => FileKind::Symlink(target),
+                Err(err) => return Err(format!("read_link Error: {:?}", err)),
+            };
+        } else {
+            // Listed size, so runs can bound the bytes in flight before reading
+            file.size = fs_metadata.len() as usize;
+            if fs_metadata.nlink() > 1 && !entry.through_symlink() {
+                inode = Some((fs_metadata.dev(), fs_metadata.ino()));
+            }
+        }
+        Ok((file, inode))
+    }
+
+    /// Lists the next files of a parallel walk, starting it if needed
+    fn list_walked(&self, listing: &mut Listing, count: usize) -> Vec<VictoryFile> {
+        if listing.walked.is_none() {
+            let settings = WalkSettings {
+                root: self.path.clone(),
+                filter: self.filter.clone(),
+                xattrs: self.xattrs,
+                follow_links: self.follow_links,
+            };
+            listing.walked = Some(ParallelWalk::start(settings, self.walk_threads));
+        }
+        let mut files = Vec::new();
+        while files.len() < count {
+            let walked = match listing.walked.as_mut().unwrap().next() {
+                Some(walked) => walked,
+                None => break,
+            };
+            match walked {
+                Walked::Listed(entry) => files.push(listing.link_inode(*entry)),
+                Walked::Filtered => listing.filtered += 1,
+                Walked::Pruned | Walked::Ignored => (),
+            }
+        }
+        files
+    }
+}
+
+/// Streams a file into a temp file next to its final path
+///
+/// `commit` syncs the temp file to disk and renames it over the final path,
+/// so the destination holds either the previous version or the complete new
+/// one, never a partial write. Dropped before `commit`, the temp file is removed.
+pub struct FileSystemWriter {
+    path: PathBuf,
+    file: BufWriter<NamedTempFile>,
+    metadata: Option<FileMetadata>,
+    /// Version store and path of the file in it, if the replaced file is kept
+    versions: Option<(PathBuf, PathBuf)>,
+}
+
+impl Write for FileSystemWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SinkWriter for FileSystemWriter {
+    fn commit(self: Box<Self>) -> Result<(), String> {
+        let temp = match self.file.into_inner() {
+            Ok(temp) => temp,
+            Err(err) => {
+                log::warn!("write Error: {:?}", err.error());
+                return Err(format!("write Error: {:?}", err.error()));
+            }
+        };
+        FileSystemDestination::apply_metadata(&self.metadata, temp.as_file(), &self.path)?;
+        if let Err(err) = temp.as_file().sync_all() {
+            log::warn!("fsync Error: {:?} with path {:?}", err, temp.path());
+            return Err(format!("fsync Error: {:?}", err));
+        }
+
+        if let Some((versions_root, dest_path)) = &self.versions {
+            FileSystemDestination::keep_version(versions_root, dest_path, &self.path)?;
+        }
+        debug!("[WriteFile] Renaming {:?} to {:?}", temp.path(), self.path);
+        if let Err(err) = temp.persist(&self.path) {
+            log::warn!("rename Error: {:?} with path {:?}", err.error, self.path);
+            return Err(format!("rename Error: {:?}", err.error));
+        }
+        // Makes the rename itself survive a crash
+        let parent = self.path.parent().unwrap();
+        match fs::File::open(parent).and_then(|dir| dir.sync_all()) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                log::warn!("fsync Error: {:?} with path {:?}", err, parent);
+                Err(format!("fsync Error: {:?}", err))
+            }
         }
     }
 }
 
-impl Destination for FileSystemDestination {
-    fn list_files_next(&mut self, count: u64) -> Result<Vec<VictoryFile>, String> {
+impl Backend for FileSystemDestination {
+    fn get_name(&self) -> String {
+        os_path::escaped(&self.path)
+    }
+
+    fn get_config(&self) -> BackendConfig {
+        let options = FileSystemOptions {
+            path: self.path.clone(),
+            include: self.filter_config.include.clone(),
+            exclude: self.filter_config.exclude.clone(),
+            xattrs: self.xattrs,
+            follow_links: self.follow_links,
+            on_conflict: self.on_conflict,
+            versions: self.versions,
+            walk_threads: self.walk_threads,
+        };
+        BackendConfig::from_options(Self::KIND, &options)
+            .expect("FileSystemOptions is a mapping")
+            .with_id(self.id.clone())
+    }
+
+    fn get_local_path(&self) -> Option<PathBuf> {
+        Some(self.path.clone())
+    }
+}
+
+impl Source for FileSystemDestination {
+    fn list_files_next(&self, count: u64) -> Result<Vec<VictoryFile>, String> {
+        let mut listing = self.listing.lock().unwrap();
+        let listing = &mut *listing;
+        if self.walk_threads != 1 {
+            return Ok(self.list_walked(listing, count as usize));
+        }
         let mut files = Vec::new();
         //TODO: Replace with chunk
         let mut count = count;
         while count > 0 {
-            let file = match self.walk_itr.next() {
+            let file = match listing.walk_itr.next() {
                 Some(file) => file,
                 None => return Ok(files),
             };
             let file = match file {
-                Ok(file) => Some(file),
+                Ok(file) => file,
                 Err(err) => {
                     log::warn!("ListError: {:?}", err);
-                    None
+                    continue;
                 }
             };
-            match file {
-                Some(file) => {
-                    debug!("Found file: {:?}", file);
-                    if file.file_type().is_file() {
-                        // Delete self.path section of the file path before saving
-                        let self_path = Path::new(&self.path);
-                        let relative_path = file.path().strip_prefix(self_path).unwrap();
-                        let file = VictoryFile::new(&relative_path.to_path_buf());
-                        files.push(file);
-                        count -= 1;
+            debug!("Found file: {:?}", file);
+            let root = Path::new(&self.path);
+            match FileSystemDestination::walk_entry(root, self.filter.as_ref(), self.xattrs, &file)
+            {
+                Walked::Listed(entry) => {
+                    files.push(listing.link_inode(*entry));
+                    count -= 1;
+                }
+                Walked::Filtered => {
+                    listing.filtered += 1;
+                    if file.file_type().is_dir() {
+                        // Never walk into excluded directories
+                        listing.walk_itr.skip_current_dir();
                     }
                 }
-                None => (),
+                Walked::Pruned => listing.walk_itr.skip_current_dir(),
+                Walked::Ignored => (),
             }
         }
         Ok(files)
     }
 
-    fn get_name(&self) -> String {
-        self.path.clone()
+    fn add_filter(&mut self, filter: &GlobFilterConfig) -> Result<(), String> {
+        if filter.is_empty() {
+            return Ok(());
+        }
+        let combined = self.filter_config.merge(filter);
+        self.filter = Some(GlobFilter::new(&combined)?);
+        Ok(())
+    }
+
+    fn take_filtered_count(&self) -> usize {
+        std::mem::take(&mut self.listing.lock().unwrap().filtered)
     }
 
     fn read_file(&self, file: &mut VictoryFile) -> Result<(), String> {
@@ -79,42 +1071,164 @@ impl Destination for FileSystemDestination {
             Ok(c) => c,
             Err(err) => {
                 log::warn!("ReadError: {:?}", err);
-                Vec::new()
+                return Err(format!("read Error: {:?}", err));
             }
         };
         file.load_contents(contents)?;
         Ok(())
     }
 
+    fn open_file(&self, file: &mut VictoryFile) -> Result<Box<dyn Read + '_>, String> {
+        let full_path = Path::new(&self.path).join(&file.path);
+        debug!("[OpenFile] Opening file: {:?}", full_path);
+        match fs::File::open(&full_path) {
+            Ok(opened) => Ok(Box::new(opened)),
+            Err(err) => {
+                log::warn!("ReadError: {:?}", err);
+                Err(format!("read Error: {:?}", err))
+            }
+        }
+    }
+}
+
+impl Sink for FileSystemDestination {
     fn write_file(&self, file: &mut VictoryFile) -> Result<(), String> {
         debug!("[WriteFile] Destination Path: {:?}", self.path);
         let contents = file.get_contents()?;
-        let file_path: &Path = Path::new(&file.path);
 
-        debug!("[WriteFile] File Path: {:?}", file_path);
-        let full_path = Path::new(&self.path).join(file_path);
-        // if directory, create
-        debug!("[WriteFile] Writing file {:?}", full_path);
+        // Same temp file and rename as streamed files
+        let mut writer = self.create_writer(file)?;
+        if let Err(err) = writer.write_all(&contents) {
+            log::warn!("write Error: {:?}", err);
+            return Err(format!("write Error: {:?}", err));
+        }
+        writer.commit()
+    }
 
-        if !full_path.parent().unwrap().exists() {
-            debug!("[WriteFile] Creating dir: {:?}", full_path);
-            match fs::create_dir_all(&full_path) {
-                Ok(_) => (),
-                Err(err) => {
-                    log::warn!(
-                        "create_dir_all Error: {:?} with path {:?}",
-                        err,
-                        full_path.clone()
-                    );
-                    return Err(format!("create_dir_all Error: {:?}", err));
+    fn decide_write(&self, file: &VictoryFile) -> Result<WriteDecision, String> {
+        let full_path = Path::new(&self.path).join(file.get_dest_path());
+        let existing = match fs::symlink_metadata(&full_path) {
+            Ok(existing) => existing,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(WriteDecision::Write)
+            }
+            Err(err) => return Err(format!("metadata Error: {:?}", err)),
+        };
+        match self.on_conflict {
+            ConflictPolicy::Overwrite => Ok(WriteDecision::Overwrite),
+            ConflictPolicy::Skip => Ok(WriteDecision::Skip("already exists".to_string())),
+            ConflictPolicy::NewerWins => match &file.metadata {
+                Some(metadata)
+                    if (metadata.mtime, metadata.mtime_nsec as i64)
+                        <= (existing.mtime(), existing.mtime_nsec()) =>
+                {
+                    Ok(WriteDecision::Skip("destination is not older".to_string()))
                 }
+                _ => Ok(WriteDecision::Overwrite),
+            },
+            ConflictPolicy::KeepBoth => Ok(WriteDecision::KeepBoth(self.free_path(file))),
+            ConflictPolicy::Fail => Ok(WriteDecision::Fail(format!(
+                "{:?} already exists",
+                full_path
+            ))),
+        }
+    }
+
+    fn has_file(&self, file: &VictoryFile) -> bool {
+        fs::symlink_metadata(Path::new(&self.path).join(file.get_dest_path())).is_ok()
+    }
+
+    fn buffered_bytes(&self, _file: &VictoryFile) -> usize {
+        WRITE_BUFFER_SIZE
+    }
+
+    fn write_link(&self, file: &VictoryFile) -> Result<(), String> {
+        if !file.kind.is_link() {
+            return Err(format!("{:?} is not a link", file.path));
+        }
+        let full_path = Path::new(&self.path).join(file.get_dest_path());
+        debug!("[WriteFile] Linking {:?} as {:?}", full_path, file.kind);
+        FileSystemDestination::create_parent(&full_path)?;
+
+        // Links are never written through, whatever is in the way is replaced
+        // unless it already is the same link
+        if !self.is_same_link(file, &full_path) {
+            if let Some(versions_root) = self.versions_root() {
+                FileSystemDestination::keep_version(
+                    &versions_root,
+                    &file.get_dest_path(),
+                    &full_path,
+                )?;
+            }
+            if let Ok(existing) = fs::symlink_metadata(&full_path) {
+                if !existing.is_dir() {
+                    if let Err(err) = fs::remove_file(&full_path) {
+                        return Err(format!("remove_file Error: {:?}", err));
+                    }
+                }
+            }
+
+            let linked = match &file.kind {
+                FileKind::Symlink(target) => std::os::unix::fs::symlink(target, &full_path),
+                FileKind::Hardlink(target) => {
+                    let target = Path::new(&self.path).join(&file.source).join(target);
+                    fs::hard_link(target, &full_path)
+                }
+                FileKind::File | FileKind::Directory => unreachable!(),
+            };
+            if let Err(err) = linked {
+                log::warn!("link Error: {:?}", err);
+                return Err(format!("link Error: {:?}", err));
             }
         }
 
-        // write file
-        log::debug!("Writing file: {:?}", &full_path);
-        match std::fs::write(&full_path, contents) {
+        // Hardlinks share the metadata of the file they link to
+        match (&file.kind, &file.metadata) {
+            (FileKind::Symlink(_), Some(metadata)) => metadata.apply_to_link(&full_path),
+            _ => Ok(()),
+        }
+    }
+
+    fn create_dir(&self, file: &VictoryFile) -> Result<(), String> {
+        let full_path = Path::new(&self.path).join(file.get_dest_path());
+        debug!("[WriteFile] Creating dir: {:?}", full_path);
+        match fs::create_dir_all(&full_path) {
             Ok(_) => Ok(()),
+            Err(err) => {
+                log::warn!("create_dir_all Error: {:?} with path {:?}", err, full_path);
+                Err(format!("create_dir_all Error: {:?}", err))
+            }
+        }
+    }
+
+    fn finish_dir(&self, file: &VictoryFile) -> Result<(), String> {
+        let full_path = Path::new(&self.path).join(file.get_dest_path());
+        match fs::File::open(&full_path) {
+            Ok(dir) => FileSystemDestination::apply_metadata(&file.metadata, &dir, &full_path),
+            Err(err) => {
+                log::warn!("open Error: {:?} with path {:?}", err, full_path);
+                Err(format!("open Error: {:?}", err))
+            }
+        }
+    }
+
+    fn create_writer(&self, file: &VictoryFile) -> Result<Box<dyn SinkWriter + '_>, String> {
+        let full_path = Path::new(&self.path).join(file.get_dest_path());
+        debug!("[WriteFile] Streaming file {:?}", full_path);
+        FileSystemDestination::create_parent(&full_path)?;
+        // In the same directory, so renaming it over the final path is atomic
+        let temp = tempfile::Builder::new()
+            .prefix(".vtmp_")
+            .tempfile_in(full_path.parent().unwrap());
+        match temp {
+            Ok(temp) => Ok(Box::new(FileSystemWriter {
+                path: full_path,
+                file: BufWriter::with_capacity(WRITE_BUFFER_SIZE, temp),
+                metadata: file.metadata.clone(),
+                versions: self
+                    .versions_root()
+                    .map(|versions_root| (versions_root, file.get_dest_path())),
+            })),
             Err(err) => {
                 log::warn!("write Error: {:?}", err);
                 Err(format!("write Error: {:?}", err))
@@ -124,10 +1238,13 @@ impl Destination for FileSystemDestination {
 }
 
 #[cfg(test)]
-
 mod fs_dest_tests {
 
-    use crate::utils::file_utils::file_cwd;
+    use std::path::PathBuf;
+
+    use crate::utils::file_utils::{
+        file_cwd, file_generates, file_generates_folder, file_remove_all, file_test_dir,
+    };
 
     use super::*;
 
@@ -137,22 +1254,22 @@ mod fs_dest_tests {
 
     #[test]
     fn test_list_files_next_count() {
-        let mut dest = FileSystemDestination::new(file_cwd());
+        let dest = FileSystemDestination::new(file_cwd());
         let files = dest.list_files_next(1000).unwrap();
-        assert!(files.len() > 0);
+        assert!(!files.is_empty());
 
-        let mut dest = FileSystemDestination::new(file_cwd());
+        let dest = FileSystemDestination::new(file_cwd());
         let files = dest.list_files_next(1).unwrap();
         assert_eq!(files.len(), 1);
 
-        let mut dest = FileSystemDestination::new(file_cwd());
+        let dest = FileSystemDestination::new(file_cwd());
         let files = dest.list_files_next(0).unwrap();
         assert_eq!(files.len(), 0);
     }
 
     #[test]
     fn test_list_files_next_filedata() {
-        let mut dest = FileSystemDestination::new(file_cwd() + "/src/destination");
+        let dest = FileSystemDestination::new(file_cwd() + "/src/destination");
 
         let mut files = dest.list_files_next(1).unwrap();
         let mut file = files.pop().unwrap();
@@ -165,13 +1282,587 @@ mod fs_dest_tests {
     #[test]
     fn test_read_file() {
         //Make a temp file
-        let mut dest = FileSystemDestination::new(file_cwd());
-        let mut files = dest.list_files_next(10).unwrap();
+        let dest = FileSystemDestination::new(file_cwd());
+        let files = dest.list_files_next(10).unwrap();
 
-        let mut file = files.pop().unwrap();
+        let mut file = files
+            .into_iter()
+            .rfind(|file| file.kind == FileKind::File)
+            .unwrap();
         dest.read_file(&mut file).unwrap();
         assert!(file.size > 0);
 
         assert!(file.state == crate::file::FileState::Read);
     }
+
+    #[test]
+    fn test_stream_file() {
+        let test_dir = file_test_dir("test_fs_stream_file".to_string());
+        fs::create_dir_all(test_dir.join("source")).unwrap();
+        file_generates(&test_dir.join("source").join("big"), 300_000).unwrap();
+        let source =
+            FileSystemDestination::new(test_dir.join("source").to_str().unwrap().to_string());
+        let dest = FileSystemDestination::new(test_dir.join("dest").to_str().unwrap().to_string());
+
+        let mut file = VictoryFile::new(&PathBuf::from("big"));
+        file.source = "src".to_string();
+        let mut reader = source.open_file(&mut file).unwrap();
+        let mut writer = dest.create_writer(&file).unwrap();
+        assert_eq!(std::io::copy(&mut reader, &mut writer).unwrap(), 300_000);
+        writer.commit().unwrap();
+        assert_eq!(
+            fs::read(test_dir.join("dest").join("src").join("big")).unwrap(),
+            fs::read(test_dir.join("source").join("big")).unwrap()
+        );
+
+        // An abandoned write leaves nothing behind
+        file.path = PathBuf::from("abandoned");
+        let mut writer = dest.create_writer(&file).unwrap();
+        writer.write_all(b"partial").unwrap();
+        drop(writer);
+        assert!(!test_dir.join("dest").join("src").join("abandoned").exists());
+
+        assert!(source.open_file(&mut file).is_err());
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_preserves_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_dir = file_test_dir("test_fs_preserves_metadata".to_string());
+        file_generates_folder(&test_dir.join("source"), 10, 1).unwrap();
+        let script = test_dir.join("source").join("file_0");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o751)).unwrap();
+        let mut old = FileMetadata::from_path(&script).unwrap();
+        old.mtime = 1_234_567_890;
+        old.apply(&fs::File::open(&script).unwrap()).unwrap();
+
+        let source =
+            FileSystemDestination::new(test_dir.join("source").to_str().unwrap().to_string());
+        let dest = FileSystemDestination::new(test_dir.join("dest").to_str().unwrap().to_string());
+        let mut file = source.list_files_next(1).unwrap().pop().unwrap();
+        let metadata = file.metadata.clone().unwrap();
+        assert_eq!(metadata.mode, 0o751);
+        assert_eq!(metadata.mtime, 1_234_567_890);
+
+        let mut reader = source.open_file(&mut file).unwrap();
+        let mut writer = dest.create_writer(&file).unwrap();
+        std::io::copy(&mut reader, &mut writer).unwrap();
+        writer.commit().unwrap();
+        let written = FileMetadata::from_path(&test_dir.join("dest").join("file_0")).unwrap();
+        assert_eq!(written.mode, 0o751);
+        assert_eq!(written.mtime, 1_234_567_890);
+        assert_eq!(written.mtime_nsec, metadata.mtime_nsec);
+
+        // Whole file writes keep it as well
+        file.path = PathBuf::from("whole");
+        file.load_contents(b"data".to_vec()).unwrap();
+        dest.write_file(&mut file).unwrap();
+        let written = FileMetadata::from_path(&test_dir.join("dest").join("whole")).unwrap();
+        assert_eq!(written.mode, 0o751);
+        assert_eq!(written.mtime, 1_234_567_890);
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_preserves_xattrs() {
+        let test_dir = file_test_dir("test_fs_preserves_xattrs".to_string());
+        file_generates_folder(&test_dir.join("source"), 10, 1).unwrap();
+        let mut labelled =
+            FileMetadata::from_path(&test_dir.join("source").join("file_0")).unwrap();
+        labelled
+            .xattrs
+            .insert("user.victory.label".to_string(), b"archive".to_vec());
+        let opened = fs::File::open(test_dir.join("source").join("file_0")).unwrap();
+        if !labelled.apply(&opened).unwrap().is_empty() {
+            // No user xattrs on the filesystem holding the test dir
+            file_remove_all(&test_dir).unwrap();
+            return;
+        }
+
+        let source_path = test_dir.join("source").to_str().unwrap().to_string();
+        let plain = FileSystemDestination::new(source_path.clone());
+        let file = plain.list_files_next(1).unwrap().pop().unwrap();
+        assert!(file.metadata.unwrap().xattrs.is_empty());
+
+        let source = FileSystemDestination::new(source_path).with_xattrs(true);
+        let options: FileSystemOptions = source.get_config().parse_options().unwrap();
+        assert!(options.xattrs);
+        let mut file = source.list_files_next(1).unwrap().pop().unwrap();
+        assert_eq!(file.metadata.as_ref().unwrap().xattrs, labelled.xattrs);
+
+        let dest = FileSystemDestination::new(test_dir.join("dest").to_str().unwrap().to_string());
+        let mut reader = source.open_file(&mut file).unwrap();
+        let mut writer = dest.create_writer(&file).unwrap();
+        std::io::copy(&mut reader, &mut writer).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(
+            read_xattrs(&test_dir.join("dest").join("file_0")).unwrap(),
+            labelled.xattrs
+        );
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_links() {
+        let test_dir = file_test_dir("test_fs_list_links".to_string());
+        file_remove_all(&test_dir).unwrap();
+        let source_path = test_dir.join("source");
+        file_generates_folder(&source_path, 10, 1).unwrap();
+        std::os::unix::fs::symlink("file_0", source_path.join("link")).unwrap();
+        fs::hard_link(source_path.join("file_0"), source_path.join("twin")).unwrap();
+
+        let source = FileSystemDestination::new(source_path.to_str().unwrap().to_string());
+        let files = source.list_files_next(10).unwrap();
+        let kinds: Vec<(PathBuf, FileKind)> = files
+            .iter()
+            .map(|file| (file.path.clone(), file.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (PathBuf::from("file_0"), FileKind::File),
+                (
+                    PathBuf::from("link"),
+                    FileKind::Symlink(PathBuf::from("file_0"))
+                ),
+                (
+                    PathBuf::from("twin"),
+                    FileKind::Hardlink(PathBuf::from("file_0"))
+                ),
+            ]
+        );
+
+        let dest = FileSystemDestination::new(test_dir.join("dest").to_str().unwrap().to_string());
+        let mut copy = files[0].clone();
+        copy.load_contents(b"data".to_vec()).unwrap();
+        dest.write_file(&mut copy).unwrap();
+        dest.write_link(&files[1]).unwrap();
+        dest.write_link(&files[2]).unwrap();
+        // Written again, e.g. by the next run
+        dest.write_link(&files[2]).unwrap();
+        assert!(dest.write_link(&files[0]).is_err());
+
+        let dest_path = test_dir.join("dest");
+        assert_eq!(
+            fs::read_link(dest_path.join("link")).unwrap(),
+            PathBuf::from("file_0")
+        );
+        assert_eq!(
+            fs::metadata(dest_path.join("twin")).unwrap().ino(),
+            fs::metadata(dest_path.join("file_0")).unwrap().ino()
+        );
+
+        // Followed links are listed as the files they point to
+        let following = FileSystemDestination::new(source_path.to_str().unwrap().to_string())
+            .with_follow_links(true);
+        let options: FileSystemOptions = following.get_config().parse_options().unwrap();
+        assert!(options.follow_links);
+        let files = following.list_files_next(10).unwrap();
+        assert_eq!(files[1].path, PathBuf::from("link"));
+        assert_eq!(files[1].kind, FileKind::File);
+        assert_eq!(files[2].kind, FileKind::Hardlink(PathBuf::from("file_0")));
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_dir = file_test_dir("test_fs_directories".to_string());
+        let source_path = test_dir.join("source");
+        fs::create_dir_all(source_path.join("empty")).unwrap();
+        fs::set_permissions(source_path.join("empty"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let source = FileSystemDestination::new(source_path.to_str().unwrap().to_string());
+        let files = source.list_files_next(10).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("empty"));
+        assert_eq!(files[0].kind, FileKind::Directory);
+        assert_eq!(files[0].metadata.as_ref().unwrap().mode, 0o700);
+
+        let dest = FileSystemDestination::new(test_dir.join("dest").to_str().unwrap().to_string());
+        let mut dir = files[0].clone();
+        dir.metadata.as_mut().unwrap().mtime = 1_000_000_000;
+        dest.create_dir(&dir).unwrap();
+        // Writing children changes the times, finishing sets them again
+        fs::write(test_dir.join("dest").join("empty").join("child"), b"data").unwrap();
+        dest.finish_dir(&dir).unwrap();
+
+        let written = FileMetadata::from_path(&test_dir.join("dest").join("empty")).unwrap();
+        assert_eq!(written.mode, 0o700);
+        assert_eq!(written.mtime, 1_000_000_000);
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_dir = file_test_dir("test_fs_atomic_write".to_string());
+        let dest = FileSystemDestination::new(test_dir.to_str().unwrap().to_string());
+        let mut file = VictoryFile::new(&PathBuf::from("nested/deeper/notes.txt"));
+        file.load_contents(b"first".to_vec()).unwrap();
+        dest.write_file(&mut file).unwrap();
+        let full_path = test_dir.join("nested").join("deeper").join("notes.txt");
+        assert!(full_path.is_file());
+        assert_eq!(fs::read(&full_path).unwrap(), b"first");
+
+        // An interrupted write keeps the previous version
+        let mut writer = dest.create_writer(&file).unwrap();
+        writer.write_all(b"second, but never finished").unwrap();
+        drop(writer);
+        assert_eq!(fs::read(&full_path).unwrap(), b"first");
+        let entries = fs::read_dir(full_path.parent().unwrap()).unwrap().count();
+        assert_eq!(entries, 1);
+
+        // Read-only files are replaced as a whole instead of written into
+        fs::set_permissions(&full_path, fs::Permissions::from_mode(0o444)).unwrap();
+        file.load_contents(b"second".to_vec()).unwrap();
+        dest.write_file(&mut file).unwrap();
+        assert_eq!(fs::read(&full_path).unwrap(), b"second");
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let test_dir = file_test_dir("test_fs_conflict_policies".to_string());
+        let dest_path = test_dir.to_str().unwrap().to_string();
+        let mut file = VictoryFile::new(&PathBuf::from("notes.txt"));
+        file.source = "src".to_string();
+        file.load_contents(b"old".to_vec()).unwrap();
+        FileSystemDestination::new(dest_path.clone())
+            .write_file(&mut file)
+            .unwrap();
+        let existing = FileMetadata::from_path(&test_dir.join("src").join("notes.txt")).unwrap();
+
+        let mut new = VictoryFile::new(&PathBuf::from("new.txt"));
+        new.source = "src".to_string();
+        let mut older = file.clone();
+        older.metadata = Some(existing.clone());
+        older.metadata.as_mut().unwrap().mtime -= 10;
+        let mut newer = older.clone();
+        newer.metadata.as_mut().unwrap().mtime += 20;
+
+        let decide = |policy: ConflictPolicy, file: &VictoryFile| {
+            FileSystemDestination::new(dest_path.clone())
+                .with_conflict_policy(policy)
+                .decide_write(file)
+        };
+        for policy in [
+            ConflictPolicy::Overwrite,
+            ConflictPolicy::Fail,
+            ConflictPolicy::Skip,
+        ] {
+            assert_eq!(decide(policy, &new), Ok(WriteDecision::Write));
+        }
+        assert_eq!(
+            decide(ConflictPolicy::Overwrite, &file),
+            Ok(WriteDecision::Overwrite)
+        );
+        assert!(matches!(
+            decide(ConflictPolicy::Skip, &file),
+            Ok(WriteDecision::Skip(_))
+        ));
+        assert!(matches!(
+            decide(ConflictPolicy::Fail, &file),
+            Ok(WriteDecision::Fail(_))
+        ));
+        assert!(matches!(
+            decide(ConflictPolicy::NewerWins, &older),
+            Ok(WriteDecision::Skip(_))
+        ));
+        assert_eq!(
+            decide(ConflictPolicy::NewerWins, &newer),
+            Ok(WriteDecision::Overwrite)
+        );
+        assert_eq!(
+            decide(ConflictPolicy::KeepBoth, &file),
+            Ok(WriteDecision::KeepBoth(PathBuf::from("notes (1).txt")))
+        );
+        fs::write(test_dir.join("src").join("notes (1).txt"), b"").unwrap();
+        assert_eq!(
+            decide(ConflictPolicy::KeepBoth, &file),
+            Ok(WriteDecision::KeepBoth(PathBuf::from("notes (2).txt")))
+        );
+
+        let config: FileSystemOptions =
+            serde_yaml::from_str("path: /backup\non_conflict: newer_wins\n").unwrap();
+        assert_eq!(config.on_conflict, ConflictPolicy::NewerWins);
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_versions() {
+        let test_dir = file_test_dir("test_fs_versions".to_string());
+        let dest =
+            FileSystemDestination::new(test_dir.to_str().unwrap().to_string()).with_versions(true);
+        let mut file = VictoryFile::new(&PathBuf::from("notes.txt"));
+        file.source = "src".to_string();
+        let dest_path = file.get_dest_path();
+        for contents in [b"first", b"secnd", b"third"] {
+            file.load_contents(contents.to_vec()).unwrap();
+            dest.write_file(&mut file).unwrap();
+        }
+        let mut link = VictoryFile::new(&PathBuf::from("notes.lnk"));
+        link.source = "src".to_string();
+        link.kind = FileKind::Symlink(PathBuf::from("notes.txt"));
+        dest.write_link(&link).unwrap();
+        dest.write_link(&link).unwrap();
+        // An unchanged link is left as it is
+        assert!(dest
+            .list_versions(&link.get_dest_path())
+            .unwrap()
+            .is_empty());
+        let mut hardlink = VictoryFile::new(&PathBuf::from("notes.hard"));
+        hardlink.source = "src".to_string();
+        hardlink.kind = FileKind::Hardlink(PathBuf::from("notes.txt"));
+        dest.write_link(&hardlink).unwrap();
+        dest.write_link(&hardlink).unwrap();
+        assert!(dest
+            .list_versions(&hardlink.get_dest_path())
+            .unwrap()
+            .is_empty());
+        link.kind = FileKind::Symlink(PathBuf::from("other.txt"));
+        dest.write_link(&link).unwrap();
+
+        let versions = dest.list_versions(&dest_path).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].replaced_at <= versions[1].replaced_at);
+        assert_eq!(fs::read(&versions[0].stored_at).unwrap(), b"first");
+        assert_eq!(fs::read(&versions[1].stored_at).unwrap(), b"secnd");
+        let link_versions = dest.list_versions(&link.get_dest_path()).unwrap();
+        assert_eq!(link_versions.len(), 1);
+        assert_eq!(
+            fs::read_link(&link_versions[0].stored_at).unwrap(),
+            PathBuf::from("notes.txt")
+        );
+
+        // Restoring keeps the replaced copy as a version too
+        dest.restore_version(&versions[0]).unwrap();
+        assert_eq!(fs::read(test_dir.join(&dest_path)).unwrap(), b"first");
+        let versions = dest.list_versions(&dest_path).unwrap();
+        assert_eq!(versions.len(), 3);
+        assert_eq!(fs::read(&versions[2].stored_at).unwrap(), b"third");
+
+        // The version store is never listed as files
+        let listed = FileSystemDestination::new(test_dir.to_str().unwrap().to_string());
+        let paths: Vec<PathBuf> = listed
+            .list_files_next(100)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("src"),
+                PathBuf::from("src/notes.hard"),
+                PathBuf::from("src/notes.lnk"),
+                PathBuf::from("src/notes.txt")
+            ]
+        );
+
+        // Without versioning nothing is kept
+        let unversioned = FileSystemDestination::new(test_dir.to_str().unwrap().to_string());
+        unversioned.write_file(&mut file).unwrap();
+        assert_eq!(dest.list_versions(&dest_path).unwrap().len(), 3);
+        assert!(dest
+            .list_versions(Path::new("src/missing.txt"))
+            .unwrap()
+            .is_empty());
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_files_next_filtered() {
+        let test_dir = file_test_dir("test_list_files_next_filtered".to_string());
+        file_generates_folder(&test_dir.join("keep"), 10, 2).unwrap();
+        file_generates(&test_dir.join("keep").join("run.log"), 10).unwrap();
+        file_generates_folder(
+            &test_dir.join("web").join("node_modules").join("dep"),
+            10,
+            3,
+        )
+        .unwrap();
+
+        let filter = GlobFilterConfig::new(Vec::new(), vec!["node_modules/".to_string()]);
+        let mut dest = FileSystemDestination::new(test_dir.to_str().unwrap().to_string())
+            .with_filter(filter)
+            .unwrap();
+        dest.add_filter(&GlobFilterConfig::new(
+            Vec::new(),
+            vec!["*.log".to_string()],
+        ))
+        .unwrap();
+
+        let files = dest.list_files_next(100).unwrap();
+        let paths: Vec<PathBuf> = files.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("keep"),
+                PathBuf::from("keep/file_0"),
+                PathBuf::from("keep/file_1"),
+                PathBuf::from("web"),
+            ]
+        );
+        assert_eq!(files[3].kind, FileKind::Directory);
+        // node_modules is counted once as it is never walked into
+        assert_eq!(dest.take_filtered_count(), 2);
+        assert_eq!(dest.take_filtered_count(), 0);
+
+        // Only the source's own patterns are saved back into its config
+        let options: FileSystemOptions = dest.get_config().parse_options().unwrap();
+        assert_eq!(options.exclude, vec!["node_modules/"]);
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_non_utf8_root() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let test_dir = file_test_dir("test_fs_non_utf8_root".to_string());
+        let root = test_dir.join(OsStr::from_bytes(b"r\xe9seau"));
+        file_generates_folder(&root, 10, 1).unwrap();
+
+        // The root is saved as its bytes and listed from again after loading
+        let config = FileSystemDestination::new(root.clone()).get_config();
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        let config: BackendConfig = serde_yaml::from_str(&yaml).unwrap();
+        let dest = FileSystemDestination::from_config(&config).unwrap();
+        assert_eq!(dest.get_local_path(), Some(root.clone()));
+        assert!(dest.get_name().ends_with("r\\xE9seau"));
+        let files = dest.list_files_next(10).unwrap();
+        assert_eq!(files[0].path, PathBuf::from("file_0"));
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_walk_is_deterministic() {
+        let test_dir = file_test_dir("test_fs_parallel_walk".to_string());
+        file_remove_all(&test_dir).unwrap_or(());
+        for folder in ["a", "a.b", "a/b", "a/b/c", "z", "z/skip", ".vversions"] {
+            file_generates_folder(&test_dir.join(folder), 10, 3).unwrap();
+        }
+        file_generates(&test_dir.join("a").join("run.log"), 10).unwrap();
+        file_generates(&test_dir.join("z").join("run.log"), 10).unwrap();
+        fs::hard_link(
+            test_dir.join("z").join("file_0"),
+            test_dir.join("a").join("again"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("a/b", test_dir.join("link")).unwrap();
+        // Only walked into again when following links
+        std::os::unix::fs::symlink("..", test_dir.join("a/b/c/up")).unwrap();
+
+        let list = |walk_threads: usize, follow_links: bool| {
+            let dest = FileSystemDestination::new(test_dir.to_str().unwrap().to_string())
+                .with_walk_threads(walk_threads)
+                .with_follow_links(follow_links)
+                .with_filter(GlobFilterConfig::new(
+                    Vec::new(),
+                    vec!["*.log".to_string(), "skip/".to_string()],
+                ))
+                .unwrap();
+            let mut batches = Vec::new();
+            loop {
+                let files = dest.list_files_next(4).unwrap();
+                let filtered = dest.take_filtered_count();
+                let paths: Vec<(PathBuf, FileKind)> = files
+                    .iter()
+                    .map(|file| (file.path.clone(), file.kind.clone()))
+                    .collect();
+                batches.push((paths, filtered));
+                if files.is_empty() {
+                    return batches;
+                }
+            }
+        };
+        let sequential = list(1, false);
+        let listed: Vec<&PathBuf> = sequential
+            .iter()
+            .flat_map(|(paths, _)| paths)
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(listed.len(), 23);
+        assert!(!listed.iter().any(|path| path.starts_with(".vversions")));
+        // The first name listed is kept, whichever thread found it
+        let again = sequential
+            .iter()
+            .flat_map(|(paths, _)| paths)
+            .find(|(path, _)| path.ends_with("again"));
+        assert_eq!(again.unwrap().1, FileKind::File);
+        let filtered: usize = sequential.iter().map(|(_, filtered)| filtered).sum();
+        assert_eq!(filtered, 3);
+        for walk_threads in [0, 2, 8] {
+            assert_eq!(list(walk_threads, false), sequential);
+        }
+        // Links looping back to a parent folder are left out by both walkers
+        let following = list(1, true);
+        let listed = following.iter().flat_map(|(paths, _)| paths).count();
+        assert_eq!(listed, 29);
+        for walk_threads in [0, 2, 8] {
+            assert_eq!(list(walk_threads, true), following);
+        }
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_walk_lists_bounded_ahead() {
+        let test_dir = file_test_dir("test_fs_walk_ahead".to_string());
+        file_remove_all(&test_dir).unwrap_or(());
+        let folders = 3 * WALK_AHEAD;
+        for folder in 0..folders {
+            let folder = test_dir.join(format!("folder_{:03}", folder));
+            fs::create_dir_all(&folder).unwrap();
+            file_generates(&folder.join("file"), 10).unwrap();
+        }
+
+        let settings = WalkSettings {
+            root: test_dir.clone(),
+            filter: None,
+            xattrs: false,
+            follow_links: false,
+        };
+        let mut walk = ParallelWalk::start(settings, 4);
+        // Nothing handed out yet, as while the first folder is slow to list
+        let idle = |walk: &ParallelWalk| {
+            let state = walk.queue.state.lock().unwrap();
+            state.listing == 0 && state.ahead == WALK_AHEAD
+        };
+        let started = std::time::Instant::now();
+        while !idle(&walk) {
+            assert!(started.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        {
+            let state = walk.queue.state.lock().unwrap();
+            assert_eq!(state.ahead, WALK_AHEAD);
+            assert_eq!(state.jobs.len(), folders + 1 - WALK_AHEAD);
+        }
+
+        let mut listed = 0;
+        while let Some(walked) = walk.next() {
+            assert!(matches!(walked, Walked::Listed(_)));
+            assert!(walk.received.len() <= WALK_AHEAD);
+            assert!(walk.queue.state.lock().unwrap().ahead <= WALK_AHEAD + 1);
+            listed += 1;
+        }
+        assert_eq!(listed, 2 * folders);
+
+        file_remove_all(&test_dir).unwrap();
+    }
 }
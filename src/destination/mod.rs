@@ -1,10 +1,340 @@
-use crate::file::VictoryFile;
+use std::{
+    io::{Cursor, Read, Write},
+    path::PathBuf,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::VictoryFile, middleware::filter_glob::GlobFilterConfig, utils::file_utils::file_safe_name,
+};
+
+use self::registry::BackendConfig;
 
 pub mod filesystem_dest;
+pub mod registry;
+
+/// Functionality shared by every backend, no matter which direction files flow.
+pub trait Backend {
+    fn get_name(&self) -> String;
+    /// Config the backend can be rebuilt from through a [`registry::BackendRegistry`]
+    fn get_config(&self) -> BackendConfig;
+
+    /// Folder on the local filesystem the backend lists or stores files in,
+    /// used to find sources and destinations nested in each other
+    fn get_local_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Identifies the backend within its plan: files and batches discovered
+    /// from a source, which destinations also use as the name of the subtree
+    /// the source's files are written to. Set through the `id` of the config,
+    /// derived from the name otherwise.
+    fn get_id(&self) -> String {
+        match self.get_config().id {
+            Some(id) => file_safe_name(&id),
+            None => file_safe_name(&self.get_name()),
+        }
+    }
+}
 
-pub trait Destination {
-    fn list_files_next(&mut self, count: u64) -> Result<Vec<VictoryFile>, String>;
+/// A backend files can be discovered in and read from (e.g. a local folder, an archive, a database dump).
+/// Every source of a plan is listed on its own thread during discovery, and
+/// read from by every worker of a run at once, while it is still being listed
+/// when both happen together.
+pub trait Source: Backend + Send + Sync {
+    fn list_files_next(&self, count: u64) -> Result<Vec<VictoryFile>, String>;
     fn read_file(&self, file: &mut VictoryFile) -> Result<(), String>;
+
+    /// Opens a reader over a file's contents, so large files never have to fit
+    /// in memory. Defaults to reading the whole file with `read_file`.
+    fn open_file(&self, file: &mut VictoryFile) -> Result<Box<dyn Read + '_>, String> {
+        self.read_file(file)?;
+        let contents = file.contents.take().unwrap_or_default();
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    /// Adds include/exclude patterns on top of the source's own ones,
+    /// applied while listing files
+    fn add_filter(&mut self, filter: &GlobFilterConfig) -> Result<(), String> {
+        if !filter.is_empty() {
+            warn!(
+                "Source {:?} does not support glob filters, applying them after listing instead",
+                self.get_name()
+            );
+        }
+        Ok(())
+    }
+
+    /// Number of entries filtered out since the last call
+    fn take_filtered_count(&self) -> usize {
+        0
+    }
+}
+
+/// How a sink handles a file that already exists at the destination
+/// # Variants:
+/// - overwrite: Replace the existing file (default)
+/// - skip: Keep the existing file
+/// - newer_wins: Replace the existing file only if the new one was modified later
+/// - keep_both: Write the new file next to the existing one under a numbered name
+/// - fail: Fail the write
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    #[default]
+    Overwrite,
+    Skip,
+    NewerWins,
+    KeepBoth,
+    Fail,
+}
+
+/// What a sink decided to do with a file, following its [`ConflictPolicy`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteDecision {
+    /// Nothing was in the way
+    Write,
+    /// Replaced the existing file
+    Overwrite,
+    /// Kept the existing file, with the reason
+    Skip(String),
+    /// Kept the existing file and wrote the new one to this path, relative to the source's subtree
+    KeepBoth(PathBuf),
+    /// Kept the existing file and failed the new one, with the reason
+    Fail(String),
+}
+
+/// A backend files can be written to (e.g. a local folder, an append-only store).
+/// Every worker of a run writes to it at once.
+pub trait Sink: Backend + Send + Sync {
     fn write_file(&self, file: &mut VictoryFile) -> Result<(), String>;
-    fn get_name(&self) -> String;
+
+    /// Decides how to write a file (or link) given what already exists at the
+    /// destination. Errors if the destination could not be checked. Sinks
+    /// that can not tell always write.
+    fn decide_write(&self, _file: &VictoryFile) -> Result<WriteDecision, String> {
+        Ok(WriteDecision::Write)
+    }
+
+    /// Whether the destination still holds a file it stored before, e.g. to
+    /// write it again to a drive that was swapped. Sinks that can not tell
+    /// assume they do.
+    fn has_file(&self, _file: &VictoryFile) -> bool {
+        true
+    }
+
+    /// Bytes a writer of this sink holds in memory for a file before they
+    /// reach the destination. Defaults to the whole file, as collected by
+    /// [`BufferedSinkWriter`].
+    fn buffered_bytes(&self, file: &VictoryFile) -> usize {
+        file.size
+    }
+
+    /// Recreates a symlink or hardlink, see [`crate::file::FileKind`].
+    /// Sinks that can not hold links fail every one of them.
+    fn write_link(&self, file: &VictoryFile) -> Result<(), String> {
+        Err(format!(
+            "{:?} can not store links ({:?})",
+            self.get_name(),
+            file.kind
+        ))
+    }
+
+    /// Creates a directory, see [`crate::file::FileKind::Directory`]. Its
+    /// metadata is applied later by `finish_dir`. Sinks without directories
+    /// ignore them.
+    fn create_dir(&self, _file: &VictoryFile) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Applies the metadata of a directory created by `create_dir`, called
+    /// once every file of the run was written, children before their parents
+    fn finish_dir(&self, _file: &VictoryFile) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Opens a writer the contents of a file are streamed into. Defaults to
+    /// buffering the contents and handing them to `write_file` on commit.
+    fn create_writer(&self, file: &VictoryFile) -> Result<Box<dyn SinkWriter + '_>, String> {
+        Ok(Box::new(BufferedSinkWriter::new(self, file)))
+    }
+}
+
+/// Writer for the contents of a single file at a [`Sink`]
+///
+/// Contents only count as written once `commit` succeeds. A writer dropped
+/// without committing, e.g. because reading the source failed halfway,
+/// must not leave the file behind.
+pub trait SinkWriter: Write {
+    fn commit(self: Box<Self>) -> Result<(), String>;
+}
+
+/// Collects streamed contents in memory for sinks that can only write whole files
+pub struct BufferedSinkWriter<'a, S: Sink + ?Sized> {
+    sink: &'a S,
+    file: VictoryFile,
+    buffer: Vec<u8>,
+}
+
+impl<'a, S: Sink + ?Sized> BufferedSinkWriter<'a, S> {
+    pub fn new(sink: &'a S, file: &VictoryFile) -> BufferedSinkWriter<'a, S> {
+        BufferedSinkWriter {
+            sink,
+            file: file.clone(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<S: Sink + ?Sized> Write for BufferedSinkWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Sink + ?Sized> SinkWriter for BufferedSinkWriter<'_, S> {
+    fn commit(mut self: Box<Self>) -> Result<(), String> {
+        self.file.contents = Some(std::mem::take(&mut self.buffer));
+        self.sink.write_file(&mut self.file)
+    }
+}
+
+/// A backend that can be both read from and written to.
+///
+/// Implemented automatically for anything that is both a [`Source`] and a [`Sink`].
+pub trait Destination: Source + Sink {}
+
+impl<T: Source + Sink + ?Sized> Destination for T {}
+
+#[cfg(test)]
+mod destination_tests {
+    use std::{path::PathBuf, sync::Mutex};
+
+    use super::*;
+    use crate::plan::BackupPlan;
+
+    /// Read-only source that serves a fixed list of files from memory
+    struct MemorySource {
+        files: Mutex<Vec<VictoryFile>>,
+    }
+
+    impl Backend for MemorySource {
+        fn get_name(&self) -> String {
+            "memory_source".to_string()
+        }
+
+        fn get_config(&self) -> BackendConfig {
+            BackendConfig::new("memory_source", serde_yaml::Mapping::new())
+        }
+    }
+
+    impl Source for MemorySource {
+        fn list_files_next(&self, count: u64) -> Result<Vec<VictoryFile>, String> {
+            let mut files = self.files.lock().unwrap();
+            let count = (count as usize).min(files.len());
+            Ok(files.drain(..count).collect())
+        }
+
+        fn read_file(&self, file: &mut VictoryFile) -> Result<(), String> {
+            file.load_contents(file.name.as_bytes().to_vec())
+        }
+    }
+
+    /// Write-only sink that keeps the names of written files
+    struct MemorySink {
+        written: Mutex<Vec<String>>,
+    }
+
+    impl Backend for MemorySink {
+        fn get_name(&self) -> String {
+            "memory_sink".to_string()
+        }
+
+        fn get_config(&self) -> BackendConfig {
+            BackendConfig::new("memory_sink", serde_yaml::Mapping::new())
+        }
+    }
+
+    impl Sink for MemorySink {
+        fn write_file(&self, file: &mut VictoryFile) -> Result<(), String> {
+            self.written.lock().unwrap().push(file.name.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_split_source_sink() {
+        let source = MemorySource {
+            files: Mutex::new(vec![
+                VictoryFile::new(&PathBuf::from("a.txt")),
+                VictoryFile::new(&PathBuf::from("b.txt")),
+            ]),
+        };
+        let sink = MemorySink {
+            written: Mutex::new(Vec::new()),
+        };
+
+        let mut files = source.list_files_next(10).unwrap();
+        assert_eq!(files.len(), 2);
+        for file in &mut files {
+            source.read_file(file).unwrap();
+            sink.write_file(file).unwrap();
+        }
+        assert_eq!(*sink.written.lock().unwrap(), vec!["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn test_default_streaming() {
+        let source = MemorySource {
+            files: Mutex::new(Vec::new()),
+        };
+        let sink = MemorySink {
+            written: Mutex::new(Vec::new()),
+        };
+
+        let mut file = VictoryFile::new(&PathBuf::from("a.txt"));
+        let mut contents = Vec::new();
+        source
+            .open_file(&mut file)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"a.txt");
+
+        // The whole file is held in memory until it is committed
+        file.size = contents.len();
+        assert_eq!(sink.buffered_bytes(&file), 5);
+
+        // Nothing is written until the writer is committed
+        let mut writer = sink.create_writer(&file).unwrap();
+        writer.write_all(&contents).unwrap();
+        drop(writer);
+        assert!(sink.written.lock().unwrap().is_empty());
+
+        let mut writer = sink.create_writer(&file).unwrap();
+        writer.write_all(&contents).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(*sink.written.lock().unwrap(), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn test_plan_accepts_one_way_backends() {
+        let mut plan = BackupPlan::new("test_plan_accepts_one_way_backends".to_string());
+        plan.add_source(Box::new(MemorySource {
+            files: Mutex::new(Vec::new()),
+        }));
+        plan.add_destination(Box::new(MemorySink {
+            written: Mutex::new(Vec::new()),
+        }));
+
+        let saved = plan.get_saved();
+        assert_eq!(saved.sources[0].kind, "memory_source");
+        assert_eq!(saved.destinations[0].kind, "memory_sink");
+    }
 }
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+
+use super::{filesystem_dest::FileSystemDestination, Sink, Source};
+
+/// Savable description of a backend, stored in the plan YAML
+/// # Fields:
+/// - kind: Which backend implementation to build (e.g. `filesystem`)
+/// - id: Identifies the backend within its plan, see [`super::Backend::get_id`]
+///   (default: derived from its name)
+/// - options: Backend specific options, parsed by the backend itself
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BackendConfig {
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub options: serde_yaml::Mapping,
+}
+
+/// On-disk shapes accepted for a backend config. Older plans stored a bare
+/// path string, which is read as a `filesystem` backend.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackendConfigRepr {
+    Path(String),
+    Config {
+        kind: String,
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        options: serde_yaml::Mapping,
+    },
+}
+
+impl<'de> Deserialize<'de> for BackendConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match BackendConfigRepr::deserialize(deserializer)? {
+            BackendConfigRepr::Path(path) => {
+                let mut options = serde_yaml::Mapping::new();
+                options.insert("path".into(), path.into());
+                Ok(BackendConfig::new(FileSystemDestination::KIND, options))
+            }
+            BackendConfigRepr::Config { kind, id, options } => {
+                Ok(BackendConfig { kind, id, options })
+            }
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn new(kind: &str, options: serde_yaml::Mapping) -> BackendConfig {
+        BackendConfig {
+            kind: kind.to_string(),
+            id: None,
+            options,
+        }
+    }
+
+    /// Sets the id of the backend, e.g. to tell apart two sources whose
+    /// names map to the same default id
+    pub fn with_id(mut self, id: Option<String>) -> BackendConfig {
+        self.id = id;
+        self
+    }
+
+    /// Builds a config from a serializable options struct
+    pub fn from_options<T: Serialize>(kind: &str, options: &T) -> Result<BackendConfig, String> {
+        match serde_yaml::to_value(options) {
+            Ok(serde_yaml::Value::Mapping(options)) => Ok(BackendConfig::new(kind, options)),
+            Ok(other) => Err(format!(
+                "BackendConfig Error: options for '{}' must be a mapping, got {:?}",
+                kind, other
+            )),
+            Err(err) => Err(format!("BackendConfig Error: {:?}", err)),
+        }
+    }
+
+    /// Parses the options into the backend specific options struct
+    pub fn parse_options<T: DeserializeOwned>(&self) -> Result<T, String> {
+        match serde_yaml::from_value(serde_yaml::Value::Mapping(self.options.clone())) {
+            Ok(options) => Ok(options),
+            Err(err) => Err(format!(
+                "BackendConfig Error: invalid options for '{}' backend: {}",
+                self.kind, err
+            )),
+        }
+    }
+}
+
+pub type SourceFactory = Box<dyn Fn(&BackendConfig) -> Result<Box<dyn Source>, String>>;
+pub type SinkFactory = Box<dyn Fn(&BackendConfig) -> Result<Box<dyn Sink>, String>>;
+
+/// Maps backend kinds to the factories that build them from a [`BackendConfig`]
+///
+/// `BackendRegistry::default()` knows about every backend built into the crate,
+/// new kinds can be added with [`BackendRegistry::register_source`] and
+/// [`BackendRegistry::register_sink`].
+pub struct BackendRegistry {
+    sources: HashMap<String, SourceFactory>,
+    sinks: HashMap<String, SinkFactory>,
+}
+
+impl BackendRegistry {
+    /// Creates a registry with no backends registered
+    pub fn new() -> BackendRegistry {
+        BackendRegistry {
+            sources: HashMap::new(),
+            sinks: HashMap::new(),
+        }
+    }
+
+    pub fn register_source<F>(&mut self, kind: &str, factory: F)
+    where
+        F: Fn(&BackendConfig) -> Result<Box<dyn Source>, String> + 'static,
+    {
+        debug!("Registering source backend: {:?}", kind);
+        self.sources.insert(kind.to_string(), Box::new(factory));
+    }
+
+    pub fn register_sink<F>(&mut self, kind: &str, factory: F)
+    where
+        F: Fn(&BackendConfig) -> Result<Box<dyn Sink>, String> + 'static,
+    {
+        debug!("Registering sink backend: {:?}", kind);
+        self.sinks.insert(kind.to_string(), Box::new(factory));
+    }
+
+    pub fn build_source(&self, config: &BackendConfig) -> Result<Box<dyn Source>, String> {
+        match self.sources.get(&config.kind) {
+            Some(factory) => factory(config),
+            None => Err(format!(
+                "BackendRegistry Error: unknown source kind '{}' (known kinds: {})",
+                config.kind,
+                Self::known_kinds(self.sources.keys())
+            )),
+        }
+    }
+
+    pub fn build_sink(&self, config: &BackendConfig) -> Result<Box<dyn Sink>, String> {
+        match self.sinks.get(&config.kind) {
+            Some(factory) => factory(config),
+            None => Err(format!(
+                "BackendRegistry Error: unknown destination kind '{}' (known kinds: {})",
+                config.kind,
+                Self::known_kinds(self.sinks.keys())
+            )),
+        }
+    }
+
+    fn known_kinds<'a>(kinds: impl Iterator<Item = &'a String>) -> String {
+        let mut kinds: Vec<&str> = kinds.map(|kind| kind.as_str()).collect();
+        kinds.sort();
+        kinds.join(", ")
+    }
+}
+
+impl Default for BackendRegistry {
+    fn default() -> Self {
+        let mut registry = BackendRegistry::new();
+        registry.register_source(FileSystemDestination::KIND, |config| {
+            Ok(Box::new(FileSystemDestination::from_config(config)?))
+        });
+        registry.register_sink(FileSystemDestination::KIND, |config| {
+            Ok(Box::new(FileSystemDestination::from_config(config)?))
+        });
+        registry
+    }
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::destination::filesystem_dest::FileSystemOptions;
+
+    #[test]
+    fn test_build_filesystem() {
+        let registry = BackendRegistry::default();
+        let config = BackendConfig::from_options(
+            FileSystemDestination::KIND,
+            &FileSystemOptions {
+                path: PathBuf::from("./src"),
+                include: Vec::new(),
+                exclude: vec!["target/".to_string()],
+                xattrs: false,
+                follow_links: false,
+                on_conflict: Default::default(),
+                versions: false,
+                walk_threads: 0,
+            },
+        )
+        .unwrap();
+
+        let source = registry.build_source(&config).unwrap();
+        assert_eq!(source.get_name(), "./src");
+        assert_eq!(source.get_config(), config);
+
+        let sink = registry.build_sink(&config).unwrap();
+        assert_eq!(sink.get_config(), config);
+    }
+
+    #[test]
+    fn test_unknown_kind() {
+        let registry = BackendRegistry::default();
+        let config = BackendConfig::new("tape_drive", serde_yaml::Mapping::new());
+
+        let err = registry.build_source(&config).err().unwrap();
+        assert!(err.contains("unknown source kind 'tape_drive'"));
+        assert!(err.contains("filesystem"));
+
+        let err = registry.build_sink(&config).err().unwrap();
+        assert!(err.contains("unknown destination kind 'tape_drive'"));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let registry = BackendRegistry::default();
+        let config = BackendConfig::new(FileSystemDestination::KIND, serde_yaml::Mapping::new());
+
+        let err = registry.build_source(&config).err().unwrap();
+        assert!(err.contains("invalid options for 'filesystem'"));
+    }
+
+    #[test]
+    fn test_register_custom_kind() {
+        let mut registry = BackendRegistry::new();
+        registry.register_sink("renamed_fs", |config| {
+            let mut config = config.clone();
+            config.kind = FileSystemDestination::KIND.to_string();
+            Ok(Box::new(FileSystemDestination::from_config(&config)?))
+        });
+
+        let mut options = serde_yaml::Mapping::new();
+        options.insert("path".into(), "./target".into());
+        let config = BackendConfig::new("renamed_fs", options);
+        assert!(registry.build_sink(&config).is_ok());
+        assert!(registry.build_source(&config).is_err());
+    }
+
+    #[test]
+    fn test_explicit_id() {
+        let registry = BackendRegistry::default();
+        let configs: Vec<BackendConfig> = serde_yaml::from_str(
+            "- kind: filesystem\n  options:\n    path: /data/photos\n- kind: filesystem\n  id: photos archive\n  options:\n    path: /data_photos\n",
+        )
+        .unwrap();
+        let derived = registry.build_source(&configs[0]).unwrap();
+        assert_eq!(derived.get_id(), "data_photos");
+        assert!(!serde_yaml::to_string(&derived.get_config())
+            .unwrap()
+            .contains("id:"));
+
+        let explicit = registry.build_source(&configs[1]).unwrap();
+        assert_eq!(explicit.get_id(), "photos_archive");
+        assert_eq!(explicit.get_config().id, Some("photos archive".to_string()));
+        assert_eq!(
+            registry.build_sink(&configs[1]).unwrap().get_id(),
+            "photos_archive"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_legacy_path() {
+        let configs: Vec<BackendConfig> = serde_yaml::from_str(
+            "- /data/photos\n- kind: filesystem\n  options:\n    path: /data/docs\n",
+        )
+        .unwrap();
+        assert_eq!(configs[0].kind, FileSystemDestination::KIND);
+        assert_eq!(
+            configs[0]
+                .parse_options::<FileSystemOptions>()
+                .unwrap()
+                .path,
+            PathBuf::from("/data/photos")
+        );
+        assert_eq!(
+            configs[1]
+                .parse_options::<FileSystemOptions>()
+                .unwrap()
+                .path,
+            PathBuf::from("/data/docs")
+        );
+    }
+}
@@ -1,23 +1,180 @@
 use std::{
-    path::PathBuf,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use num_format::{Locale, ToFormattedString};
 
 use crate::{
-    batch::{self, FileBatch},
-    plan::BackupPlan,
+    batch::{BatchLimits, FileBatch},
+    destination::{Sink, SinkWriter, Source, WriteDecision},
+    file::{FileKind, FileState, VictoryFile},
+    middleware::{
+        checkpoint::{Checkpoint, CheckpointState},
+        filter_glob::GlobFilterConfig,
+        Inspector, MiddlewareAction, MiddlewareChain,
+    },
+    plan::{BackupPlan, PathOverlap},
+    workers::{Budget, WorkQueue},
 };
 
 pub struct Executor {}
 
+/// Bytes read from a source or written to a destination at once, so memory
+/// use does not depend on the size of a file
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Names of the batches saved for a source, files listed and entries filtered
+type SourceDiscovery = (Vec<String>, usize, usize);
+
+/// Called with the name and path of every batch once it is saved
+type OnBatch<'a> = &'a (dyn Fn(&str, PathBuf) -> Result<(), String> + Sync);
+
+/// Decision of a sink about a file, with the writer to stream it into unless
+/// the sink keeps what it has
+type OpenedWriter<'a> = (WriteDecision, Option<Box<dyn SinkWriter + 'a>>);
+
+/// A batch whose files are being processed by the workers of a run. Files
+/// are put back into the batch as they are done.
+struct BatchRun<'a> {
+    name: String,
+    path: PathBuf,
+    source: &'a dyn Source,
+    batch: Mutex<FileBatch>,
+    /// Files not done yet, the worker taking this to 0 saves the batch
+    pending: AtomicUsize,
+    /// Files the checkpoint already finished
+    resumed: AtomicUsize,
+    results: Mutex<ExecutorDiscoveryResults>,
+    start_time: Instant,
+}
+
+/// A file of a batch, handed to a worker by position in the batch
+struct FileTask<'a> {
+    run: Arc<BatchRun<'a>>,
+    idx: usize,
+    /// Path of the file at the destinations, see `VictoryFile::get_dest_path`
+    path: PathBuf,
+    /// Path of the file a hardlink links to, which it waits for to be written
+    link_target: Option<PathBuf>,
+}
+
+/// Counts the bytes read through it, e.g. to size a file while it streams
+struct CountingReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    count: Rc<Cell<usize>>,
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read);
+        Ok(read)
+    }
+}
+
+/// Inspectors seeing the contents of a file as it streams, and whether they saw all of it
+type SharedInspectors = Rc<RefCell<(Vec<Box<dyn Inspector>>, bool)>>;
+
+/// Shows the contents read through it to inspectors, e.g. to hash a file
+/// while it is written
+struct InspectingReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    inspectors: SharedInspectors,
+}
+
+impl Read for InspectingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let (inspectors, complete) = &mut *self.inspectors.borrow_mut();
+        *complete = read == 0;
+        for inspector in inspectors {
+            inspector.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Outcome of writing files to a single destination
+/// # Fields:
+/// - name: Name of the destination
+/// - written: Files written successfully
+/// - failed: Files that did not make it to this destination (read or write error, or
+///   its conflict policy failed them)
+/// - skipped: Files not written as the destination's conflict policy kept the existing file
+/// - conflicts: Decision for every file that already existed at the destination, by destination path
+/// - errors: Why every file that could not be read or written failed, by destination path
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestinationResults {
+    pub name: String,
+    pub written: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub conflicts: Vec<(PathBuf, WriteDecision)>,
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl DestinationResults {
+    pub fn new(name: String) -> DestinationResults {
+        DestinationResults {
+            name,
+            written: 0,
+            failed: 0,
+            skipped: 0,
+            conflicts: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Counts a file that could not be read or written, keeping why
+    pub fn record_error(&mut self, file: &VictoryFile, err: &str) {
+        self.failed += 1;
+        self.errors.push((file.get_dest_path(), err.to_string()));
+    }
+
+    /// Counts a file the destination handled, keeping the decision if it
+    /// ran into an existing file
+    pub fn record(&mut self, file: &VictoryFile, decision: WriteDecision) {
+        match decision {
+            WriteDecision::Skip(_) => self.skipped += 1,
+            WriteDecision::Fail(_) => self.failed += 1,
+            _ => self.written += 1,
+        }
+        if decision != WriteDecision::Write {
+            info!(
+                "Executor: {:?} already exists at {:?}: {:?}",
+                file.get_dest_path(),
+                self.name,
+                decision
+            );
+            self.conflicts.push((file.get_dest_path(), decision));
+        }
+    }
+}
+
 pub struct ExecutorDiscoveryResults {
     pub files: usize,
     pub batches: usize,
+    /// Entries dropped by include/exclude filters during discovery
+    pub filtered: usize,
+    /// Files skipped because they did not change since the previous run
+    pub skipped: usize,
+    /// Bytes middleware transforms (e.g. compression) saved before writing,
+    /// negative if stored files ended up larger than read
+    pub bytes_saved: i64,
     pub batch_time: Duration,
     pub total_time: Duration,
+    /// Per destination counts, in the same order as `BackupPlan::destinations`
+    pub destinations: Vec<DestinationResults>,
 }
 
 impl ExecutorDiscoveryResults {
@@ -29,291 +186,2656 @@ impl ExecutorDiscoveryResults {
         total_time: Instant,
     ) -> ExecutorDiscoveryResults {
         ExecutorDiscoveryResults {
-            files: files,
-            batches: batches,
+            files,
+            batches,
+            filtered: 0,
+            skipped: 0,
+            bytes_saved: 0,
             batch_time: batch_time.duration_since(start_time),
             total_time: total_time.duration_since(start_time),
+            destinations: Vec::new(),
+        }
+    }
+
+    /// Adds the counts and times of another result into this one,
+    /// matching destinations by position
+    pub fn add(&mut self, other: &ExecutorDiscoveryResults) {
+        self.files += other.files;
+        self.batches += other.batches;
+        self.filtered += other.filtered;
+        self.skipped += other.skipped;
+        self.bytes_saved += other.bytes_saved;
+        self.batch_time += other.batch_time;
+        self.total_time += other.total_time;
+        for (idx, dest) in other.destinations.iter().enumerate() {
+            match self.destinations.get_mut(idx) {
+                Some(combined) => {
+                    combined.written += dest.written;
+                    combined.failed += dest.failed;
+                    combined.skipped += dest.skipped;
+                    combined.conflicts.extend(dest.conflicts.iter().cloned());
+                    combined.errors.extend(dest.errors.iter().cloned());
+                }
+                None => self.destinations.push(dest.clone()),
+            }
         }
     }
 }
 
 impl Executor {
+    /// Lists every source into batches of up to `batch_size` files, going
+    /// through the discovery stages of the plan's middleware
     pub fn discover(
         plan: &mut BackupPlan,
         batch_size: u64,
     ) -> Result<ExecutorDiscoveryResults, String> {
-        // Store start time
-        let total_start_time = std::time::Instant::now();
+        Executor::discover_with_limits(plan, BatchLimits::new(batch_size))
+    }
 
-        let mut total_files = 0;
-        let mut batch_idx = 0;
-        //TODO: Multithread this
-        for source in &mut plan.sources {
-            //TODO: Make ID also show destintation, such as plan_dest_batch..
-            loop {
-                let batch_start_time = std::time::Instant::now();
-                let source = source.as_mut();
-                let mut batch =
-                    FileBatch::new(plan.name.to_string() + "_" + batch_idx.to_string().as_str());
+    /// Lists every source into batches limited by file count and total size,
+    /// going through the discovery stages of the plan's middleware
+    pub fn discover_with_limits(
+        plan: &mut BackupPlan,
+        limits: BatchLimits,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        let chain = MiddlewareChain::discovery_from_plan(plan)?;
+        Executor::discover_with_chain(plan, limits, &chain)
+    }
 
-                let files = match source.list_files_next(batch_size) {
-                    Ok(files) => files,
-                    Err(err) => {
-                        error!("list_files_next ERROR: {:?}", err);
-                        Vec::new()
-                    }
-                };
+    /// Lists every source into batches, going through the given middleware
+    pub fn discover_with_chain(
+        plan: &mut BackupPlan,
+        limits: BatchLimits,
+        chain: &MiddlewareChain,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        // Store start time
+        let total_start_time = std::time::Instant::now();
 
-                if files.len() == 0 {
-                    break;
-                }
+        let filters = Executor::source_filters(plan, chain)?;
+        // Batches are discovered again, progress through the old ones no longer applies
+        Checkpoint::remove_path(&plan.get_checkpoint_path())?;
 
-                batch.add_files(files);
-                let batch_end_time = std::time::Instant::now();
-                batch_idx += 1;
-                total_files += batch.get_length();
+        let (plan_name, plan_path) = (plan.name.clone(), plan.path.clone());
+        // Every source is walked on its own thread. Batches are named and
+        // numbered per source and joined in source order, so the plan comes
+        // out the same whichever source finishes first.
+        let discovered: Vec<Result<SourceDiscovery, String>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = plan
+                .sources
+                .iter_mut()
+                .zip(filters)
+                .map(|(source, filter)| {
+                    let (plan_name, plan_path) = (&plan_name, &plan_path);
+                    scope.spawn(move || {
+                        source.add_filter(&filter)?;
+                        Executor::discover_source(
+                            plan_name,
+                            plan_path,
+                            source.as_ref(),
+                            &limits,
+                            chain,
+                            None,
+                        )
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| match handle.join() {
+                    Ok(discovered) => discovered,
+                    Err(_) => Err("Executor: Discovery thread panicked".to_string()),
+                })
+                .collect()
+        });
 
-                plan.batches.push(batch.get_name());
-                info!("path: {:?}", plan.path);
-                let batch_path = plan
-                    .path
-                    .join(".vbatches/")
-                    .join(batch.get_name().to_string() + ".vbak_batch");
-                // Save batch
-                let save_size = match batch.save_batch(batch_path.clone()) {
-                    Ok(res) => res,
-                    Err(err) => {
-                        error!("save_batch ERROR: {:?}", err);
-                        0
-                    }
-                };
-                let batch_save_time = std::time::Instant::now();
-
-                info!(
-                    "Batch {}:
-                    \t- Length: {}
-                    \t- Disk size: {} kb
-                    \t- Time to discover: {:.2}ms
-                    \t- Time to save: {:.2}ms
-                    \t- Path: {:?}",
-                    batch.get_name(),
-                    (batch.get_length() as u64).to_formatted_string(&Locale::en),
-                    save_size / 1024,
-                    batch_end_time.duration_since(batch_start_time).as_micros() as f64 / 1000.,
-                    batch_save_time.duration_since(batch_end_time).as_micros() as f64 / 1000.,
-                    batch_path.clone()
-                );
-            }
+        let mut total_files = 0;
+        let mut total_filtered = 0;
+        let mut batch_idx = 0;
+        for discovered in discovered {
+            let (batches, files, filtered) = discovered?;
+            batch_idx += batches.len();
+            total_files += files;
+            total_filtered += filtered;
+            plan.batches.extend(batches);
         }
 
         let total_end_time = std::time::Instant::now();
 
         info!(
-            "Total time to discover {} batches with {} files: {}ms",
+            "Total time to discover {} batches with {} files ({} filtered): {}ms",
             batch_idx,
             total_files.to_formatted_string(&Locale::en),
+            total_filtered.to_formatted_string(&Locale::en),
             total_end_time.duration_since(total_start_time).as_millis()
         );
 
-        Ok(ExecutorDiscoveryResults::new(
+        let mut results = ExecutorDiscoveryResults::new(
             total_files,
             batch_idx,
             total_start_time,
             total_end_time,
             total_end_time,
-        ))
+        );
+        results.filtered = total_filtered;
+        Ok(results)
     }
 
-    pub fn process_batch(
+    /// Patterns every source of the plan is listed with, in source order: the
+    /// discovery stages' ones and whatever of the plan is nested in the source
+    fn source_filters(
         plan: &BackupPlan,
-        batch_path: &PathBuf,
-    ) -> Result<ExecutorDiscoveryResults, String> {
-        let plan_path = plan.path.clone();
+        chain: &MiddlewareChain,
+    ) -> Result<Vec<GlobFilterConfig>, String> {
+        // Files are tracked by source id, so two sources sharing an id would
+        // end up reading from (and writing to) the same place
+        let mut source_ids: Vec<String> = Vec::new();
+        for source in &plan.sources {
+            let source_id = source.get_id();
+            if source_ids.contains(&source_id) {
+                return Err(format!(
+                    "Executor: Source {:?} has the same id as another source: {:?}, set a different `id` in its config",
+                    source.get_name(),
+                    source_id
+                ));
+            }
+            source_ids.push(source_id);
+        }
 
-        info!("Executor: Loading batch: {:?}", batch_path);
-        let batch_start_time = std::time::Instant::now();
-        let mut batch = match FileBatch::load_batch(batch_path.clone()) {
-            Ok(batch) => batch,
-            Err(err) => {
-                error!("Executor: Error loading batch: {:?}", err);
-                return Err(err);
+        // Destinations and metadata inside a source would get backed up by every
+        // later run, so they are left out. A source inside a destination can not be.
+        let mut exclusions: HashMap<String, GlobFilterConfig> = HashMap::new();
+        for overlap in plan.find_overlaps()? {
+            match &overlap {
+                PathOverlap::Nested { source, path, .. } => {
+                    warn!("Executor: {}, leaving it out of the backup", overlap);
+                    let exclusion = exclusions.entry(source.clone()).or_default();
+                    *exclusion = exclusion.merge(&GlobFilterConfig::exclude_dir(path));
+                }
+                PathOverlap::Contains { .. } => {
+                    return Err(format!(
+                        "Executor: Refusing to back up, {}. Move the destination or metadata outside of the source.",
+                        overlap
+                    ))
+                }
             }
-        };
+        }
 
-        let mut writen = 0;
-        for file in batch.get_files() {
-            // Read file from source
+        let discovery_filter = chain.get_discovery_filter();
+        Ok(source_ids
+            .iter()
+            .map(|source_id| match exclusions.get(source_id) {
+                Some(exclusion) => discovery_filter.merge(exclusion),
+                None => discovery_filter.clone(),
+            })
+            .collect())
+    }
 
-            match plan.sources[0].read_file(file) {
-                Ok(file_contents) => file_contents,
-                Err(err) => {
-                    error!("Executor: Error reading file {:?}: {:?}", file.name, err);
-                    continue;
-                }
-            };
+    /// Lists one source into batches saved under the plan's path, handing
+    /// the name and path of every saved batch to `on_batch` if set
+    fn discover_source(
+        plan_name: &str,
+        plan_path: &Path,
+        source: &dyn Source,
+        limits: &BatchLimits,
+        chain: &MiddlewareChain,
+        on_batch: Option<OnBatch>,
+    ) -> Result<SourceDiscovery, String> {
+        if limits.files == 0 {
+            return Err("Executor: Batches need room for at least one file".to_string());
+        }
+        let source_id = source.get_id();
+        let mut batches = Vec::new();
+        let mut total_files = 0;
+        let mut total_filtered = 0;
+        let mut source_batch_idx = 0;
+        // Files listed but not batched yet, e.g. behind a file that filled the
+        // previous batch's bytes, and entries filtered since the previous batch
+        let mut pending: VecDeque<VictoryFile> = VecDeque::new();
+        let mut filtered = 0;
+        let mut listed_all = false;
+        loop {
+            let batch_start_time = std::time::Instant::now();
+            if !listed_all && (pending.len() as u64) < limits.files {
+                let mut files = match source.list_files_next(limits.files - pending.len() as u64) {
+                    Ok(files) => files,
+                    Err(err) => {
+                        error!("list_files_next ERROR: {:?}", err);
+                        Vec::new()
+                    }
+                };
+                filtered += source.take_filtered_count();
+                listed_all = files.is_empty();
 
-            // replace name by replacing source path with destination path
-            //file.path = file.path.replace(self.sources[0].get_name().as_str(), self.destinations[0].get_name().as_str());
+                for file in &mut files {
+                    file.source = source_id.clone();
+                }
+                let listed = files.len();
+                files.retain_mut(|file| match chain.on_discover(file) {
+                    MiddlewareAction::Continue => true,
+                    MiddlewareAction::Skip(_) => false,
+                    MiddlewareAction::Error(reason) => {
+                        error!("Executor: Not backing up {:?}: {}", file.path, reason);
+                        false
+                    }
+                });
+                filtered += listed - files.len();
+                pending.extend(files);
+            }
 
-            match plan.destinations[0].write_file(file) {
-                Ok(_) => {
-                    writen += 1;
+            if pending.is_empty() {
+                if listed_all {
+                    total_filtered += filtered;
+                    break;
                 }
+                continue;
+            }
+
+            let mut batch = FileBatch::new_from_source(
+                format!("{}_{}_{}", plan_name, source_id, source_batch_idx),
+                source_id.clone(),
+            );
+            total_filtered += filtered;
+            batch.set_filtered(filtered);
+            filtered = 0;
+            batch.add_files(limits.take_batch(&mut pending));
+            let batch_end_time = std::time::Instant::now();
+            source_batch_idx += 1;
+            total_files += batch.get_length();
+
+            batches.push(batch.get_name());
+            info!("path: {:?}", plan_path);
+            let batch_path = plan_path
+                .join(".vbatches/")
+                .join(batch.get_name().to_string() + ".vbak_batch");
+            // Save batch
+            let save_size = match batch.save_batch(batch_path.clone()) {
+                Ok(res) => res,
                 Err(err) => {
-                    error!("Executor: Error writing file {:?}: {:?}", file.path, err);
-                    continue;
+                    error!("save_batch ERROR: {:?}", err);
+                    0
                 }
             };
+            let batch_save_time = std::time::Instant::now();
+
+            info!(
+                "Batch {}:
+                \t- Length: {}
+                \t- Size: {} kb
+                \t- Filtered: {}
+                \t- Disk size: {} kb
+                \t- Time to discover: {:.2}ms
+                \t- Time to save: {:.2}ms
+                \t- Path: {:?}",
+                batch.get_name(),
+                (batch.get_length() as u64).to_formatted_string(&Locale::en),
+                (batch.get_size() / 1024).to_formatted_string(&Locale::en),
+                batch.get_filtered().to_formatted_string(&Locale::en),
+                save_size / 1024,
+                batch_end_time.duration_since(batch_start_time).as_micros() as f64 / 1000.,
+                batch_save_time.duration_since(batch_end_time).as_micros() as f64 / 1000.,
+                batch_path.clone()
+            );
+            if let Some(on_batch) = on_batch {
+                on_batch(&batch.get_name(), batch_path)?;
+            }
         }
+        Ok((batches, total_files, total_filtered))
+    }
 
-        info!(
-            "Wrote {} files in {:.4}s",
-            writen,
-            batch_start_time.elapsed().as_secs_f64()
-        );
-        return Ok(ExecutorDiscoveryResults {
-            files: writen,
-            batches: 1,
-            batch_time: batch_start_time.elapsed().clone(),
-            total_time: batch_start_time.elapsed().clone(),
-        });
+    /// Reads every file of a batch from its source, passes it through the
+    /// middleware chain and writes it to every destination.
+    /// The batch is saved back afterwards with each file's hash, state and reason,
+    /// and marked done in the checkpoint.
+    /// Files the checkpoint already finished are not read again.
+    pub fn process_batch(
+        plan: &BackupPlan,
+        batch_path: &Path,
+        chain: &MiddlewareChain,
+        checkpoint: &Checkpoint,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        Executor::process_batches(plan, &[batch_path.to_path_buf()], chain, checkpoint)
     }
 
-    pub fn run(plan: &BackupPlan) -> Result<ExecutorDiscoveryResults, String> {
-        let plan_path = plan.path.clone();
+    /// Processes batches on the plan's worker pool, see [`Executor::process_batch`]
+    pub fn process_batches(
+        plan: &BackupPlan,
+        batch_paths: &[PathBuf],
+        chain: &MiddlewareChain,
+        checkpoint: &Checkpoint,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        Executor::process_queued(plan, batch_paths.iter().cloned(), chain, checkpoint)
+    }
 
-        debug!("Executor: Running backup plan {}", plan.name);
-        let mut combined_results = ExecutorDiscoveryResults::new(
-            0,
-            0,
-            std::time::Instant::now(),
-            std::time::Instant::now(),
-            std::time::Instant::now(),
+    /// Processes batches on the plan's worker pool as they come out of
+    /// `batch_paths`, e.g. while they are still being discovered.
+    /// Files are handed to the workers in order, each holding its size and
+    /// the files it opens from the pool's budgets while it is processed.
+    /// Hardlinks wait for the file they link to if it is still being written.
+    /// Batches are loaded one at a time, as the workers reach them.
+    fn process_queued(
+        plan: &BackupPlan,
+        batch_paths: impl Iterator<Item = PathBuf> + Send,
+        chain: &MiddlewareChain,
+        checkpoint: &Checkpoint,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        let total = Mutex::new(Executor::empty_results(plan));
+        let tasks = batch_paths.flat_map(|batch_path| {
+            let run = match Executor::start_batch(plan, &batch_path) {
+                Ok(run) => Arc::new(run),
+                Err(err) => return vec![Err(err)],
+            };
+            let paths: Vec<(PathBuf, Option<PathBuf>)> = run
+                .batch
+                .lock()
+                .unwrap()
+                .files
+                .iter()
+                .map(|file| {
+                    let link_target = match &file.kind {
+                        FileKind::Hardlink(target) => Some(Path::new(&file.source).join(target)),
+                        _ => None,
+                    };
+                    (file.get_dest_path(), link_target)
+                })
+                .collect();
+            if paths.is_empty() {
+                Executor::finish_batch(&run, chain, checkpoint, &total);
+            }
+            paths
+                .into_iter()
+                .enumerate()
+                .map(|(idx, (path, link_target))| {
+                    Ok(FileTask {
+                        run: run.clone(),
+                        idx,
+                        path,
+                        link_target,
+                    })
+                })
+                .collect()
+        });
+        let queue = WorkQueue::new(
+            tasks,
+            |task| task.as_ref().ok().map(|task| task.path.clone()),
+            |task| task.as_ref().ok().and_then(|task| task.link_target.clone()),
+        );
+        let budgets = (
+            Budget::new(plan.workers.max_in_flight_bytes),
+            Budget::new(plan.workers.max_open_files),
         );
-        for batch in &plan.batches {
-            let batch_path = plan_path
-                .join(".vbatches/")
-                .join(batch.to_string() + ".vbak_batch");
-            let batch_res = Executor::process_batch(plan, &batch_path);
 
-            match batch_res {
-                Ok(res) => {
-                    combined_results.files += res.files;
-                    combined_results.batches += res.batches;
-                    combined_results.batch_time += res.batch_time;
-                    combined_results.total_time += res.total_time;
-                }
-                Err(err) => {
-                    error!("Executor: Error processing batch: {:?}", err);
-                    return Err(err);
-                }
+        let threads = plan.workers.get_threads();
+        debug!("Executor: Processing batches on {} workers", threads);
+        let outcomes: Vec<Result<(), String>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope
+                        .spawn(|| Executor::work(plan, &queue, chain, checkpoint, &budgets, &total))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| match worker.join() {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err("Executor: Worker thread panicked".to_string()),
+                })
+                .collect()
+        });
+        for outcome in outcomes {
+            if let Err(err) = outcome {
+                error!("Executor: Error processing batches: {:?}", err);
+                return Err(err);
             }
         }
-        info!(
-            "Executor: Total time to process {} batches with {} files: {}ms",
-            combined_results.batches,
-            combined_results.files.to_formatted_string(&Locale::en),
-            combined_results.total_time.as_millis()
-        );
-        return Ok(combined_results);
+        Ok(total.into_inner().unwrap())
     }
-}
-
-#[cfg(test)]
-mod executor_tests {
-    use std::path::PathBuf;
-
-    use crate::{
-        destination::filesystem_dest::FileSystemDestination,
-        executor::Executor,
-        utils::file_utils::{file_generates_folder, file_remove_all, file_test_dir},
-    };
-
 
-    #[test]
-    fn test_discover() {
-        let n_files = 200;
-        let batch_size = 10;
-        let test_dir: std::path::PathBuf = file_test_dir("test_discover".to_string());
+    /// Results without any files yet, counting for every destination of the plan
+    fn empty_results(plan: &BackupPlan) -> ExecutorDiscoveryResults {
+        let now = Instant::now();
+        let mut results = ExecutorDiscoveryResults::new(0, 0, now, now, now);
+        results.destinations = plan
+            .destinations
+            .iter()
+            .map(|dest| DestinationResults::new(dest.get_name()))
+            .collect();
+        results
+    }
 
-        let mut plan = crate::plan::BackupPlan::new("plan__test_discover".to_string());
-        let mut source_path = test_dir.clone();
-        source_path.push("source");
+    /// Loads a batch and finds the source its files are read from
+    fn start_batch<'a>(plan: &'a BackupPlan, batch_path: &Path) -> Result<BatchRun<'a>, String> {
+        info!("Executor: Loading batch: {:?}", batch_path);
+        let start_time = Instant::now();
+        let batch = match FileBatch::load_batch(batch_path.to_path_buf()) {
+            Ok(batch) => batch,
+            Err(err) => {
+                error!("Executor: Error loading batch: {:?}", err);
+                return Err(err);
+            }
+        };
 
-        let mut dest_path = test_dir.clone();
-        dest_path.push("dest");
+        let batch_source = batch.get_source();
+        let source = match plan.get_source(&batch_source) {
+            Some(source) => source,
+            // Batches saved before sources were tracked only ever had one source
+            None if batch_source.is_empty() && plan.sources.len() == 1 => {
+                warn!(
+                    "Executor: Batch {:?} has no source, using {:?}",
+                    batch.get_name(),
+                    plan.sources[0].get_name()
+                );
+                plan.sources[0].as_ref()
+            }
+            None => {
+                let err = format!(
+                    "Executor: Batch {:?} was discovered in unknown source {:?}",
+                    batch.get_name(),
+                    batch_source
+                );
+                error!("{}", err);
+                return Err(err);
+            }
+        };
 
-        let _ = file_generates_folder(&source_path, 100, n_files);
+        let mut results = Executor::empty_results(plan);
+        results.batches = 1;
+        results.filtered = batch.get_filtered();
+        Ok(BatchRun {
+            name: batch.get_name(),
+            path: batch_path.to_path_buf(),
+            source,
+            pending: AtomicUsize::new(batch.files.len()),
+            resumed: AtomicUsize::new(0),
+            batch: Mutex::new(batch),
+            results: Mutex::new(results),
+            start_time,
+        })
+    }
 
-        plan.add_source(Box::new(FileSystemDestination::new(
-            source_path.to_str().unwrap().to_string(),
-        )));
-        plan.add_destination(Box::new(FileSystemDestination::new(
-            dest_path.to_str().unwrap().to_string(),
-        )));
-        plan.save_plan(&test_dir);
-        {
-            let res = Executor::discover(&mut plan, batch_size);
+    /// Takes files off the queue until it runs out, the worker finishing the
+    /// last file of a batch also finishes the batch
+    fn work<'a, I: Iterator<Item = Result<FileTask<'a>, String>>>(
+        plan: &BackupPlan,
+        queue: &WorkQueue<I, PathBuf>,
+        chain: &MiddlewareChain,
+        checkpoint: &Checkpoint,
+        (bytes, open_files): &(Budget, Budget),
+        total: &Mutex<ExecutorDiscoveryResults>,
+    ) -> Result<(), String> {
+        while let Some((task, _in_flight)) = queue.next() {
+            let task = match task {
+                Ok(task) => task,
+                Err(err) => {
+                    queue.close();
+                    return Err(err);
+                }
+            };
+            let run = &task.run;
+            let mut file = run.batch.lock().unwrap().files[task.idx].clone();
 
-            match res {
-                Ok(res) => {
-                    assert_eq!(res.files, n_files);
-                    assert_eq!(res.batches, n_files / batch_size as usize);
+            // Finished before the previous run was interrupted
+            if let Some(finished) = checkpoint.get_finished(&run.name, &file) {
+                file.state = finished.state;
+                file.hash = finished.hash;
+                file.reason = finished.reason;
+                run.resumed.fetch_add(1, Ordering::Relaxed);
+            } else {
+                let mut results = Executor::empty_results(plan);
+                {
+                    // Always bytes before files, so two workers never wait on each other
+                    let _permits = file.kind.has_contents().then(|| {
+                        (
+                            bytes.acquire(Executor::buffered_bytes(plan, &file)),
+                            open_files.acquire(1 + plan.destinations.len()),
+                        )
+                    });
+                    Executor::process_file(plan, run.source, &mut file, chain, &mut results);
                 }
-                Err(err) => {
-                    panic!("Error: {:?}", err);
+                run.results.lock().unwrap().add(&results);
+                if let Err(err) = checkpoint.record_file(&run.name, &file, chain) {
+                    warn!("Executor: Error saving checkpoint: {:?}", err);
                 }
             }
-        }
 
-        assert_eq!(plan.batches.len(), n_files / batch_size as usize);
+            run.batch.lock().unwrap().files[task.idx] = file;
+            if run.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+                Executor::finish_batch(run, chain, checkpoint, total);
+            }
+        }
+        Ok(())
+    }
 
-        file_remove_all(&test_dir.clone()).expect("Could not remove dest dir");
+    /// Bytes held in memory while a file is streamed: a chunk read from its
+    /// source, plus what every destination buffers before writing
+    fn buffered_bytes(plan: &BackupPlan, file: &VictoryFile) -> usize {
+        let buffered: usize = plan
+            .destinations
+            .iter()
+            .map(|dest| dest.buffered_bytes(file))
+            .sum();
+        STREAM_CHUNK_SIZE + buffered
     }
 
-    #[test]
-    fn test_process_batch() {
-        let n_files = 200;
-        let batch_size = 10;
-        let test_dir: std::path::PathBuf = file_test_dir("test_process_batch".to_string());
+    /// Reads a single file from its source, passes it through the middleware
+    /// chain and writes it to every destination, counting it in `results`
+    fn process_file(
+        plan: &BackupPlan,
+        source: &dyn Source,
+        file: &mut VictoryFile,
+        chain: &MiddlewareChain,
+        results: &mut ExecutorDiscoveryResults,
+    ) {
+        let dest_results = &mut results.destinations;
 
-        let mut plan = crate::plan::BackupPlan::new("plan__test_process_batch".to_string());
-        let mut source_path = test_dir.clone();
-        source_path.push("source");
+        // Links and directories have no contents, every destination
+        // recreates them instead
+        if !file.kind.has_contents() {
+            results.files += 1;
+            let all_written = Executor::write_entry(plan, file, dest_results);
+            file.clear_contents();
+            if !all_written {
+                file.mark_error("not written to every destination".to_string());
+            }
+            return;
+        }
 
-        let mut dest_path = test_dir.clone();
-        dest_path.push("dest");
+        // Hash of the previous run, set again as the contents are read
+        file.hash.clear();
 
-        let _ = file_generates_folder(&source_path, 100, n_files);
-        plan.add_source(Box::new(FileSystemDestination::new(
-            source_path.to_str().unwrap().to_string(),
-        )));
-        plan.add_destination(Box::new(FileSystemDestination::new(
-            dest_path.to_str().unwrap().to_string(),
-        )));
-        let plan_res = plan.save_plan(&test_dir);
-        {
-            let res = Executor::discover(&mut plan, batch_size);
+        // Stages that look at the contents (e.g. hashing) see them in a first
+        // pass if they may leave the file out once they did, otherwise while
+        // it is written
+        let mut inspectors = chain.inspect(file);
+        let first_pass = plan
+            .destinations
+            .iter()
+            .any(|dest| chain.may_skip_destination(file, &dest.get_id()));
+        if first_pass && !inspectors.is_empty() {
+            let inspectors = std::mem::take(&mut inspectors);
+            if let Err(err) = Executor::inspect_file(source, file, inspectors) {
+                error!("Executor: Error reading file {:?}: {:?}", file.name, err);
+                for result in dest_results {
+                    result.record_error(file, &err);
+                }
+                file.mark_error(err);
+                return;
+            }
+        }
 
-            match res {
-                Ok(res) => {
-                    assert_eq!(res.files, n_files);
-                    assert_eq!(res.batches, n_files / batch_size as usize);
+        // Read file from source once, then fan it out to every destination
+        let reader = match source.open_file(file) {
+            Ok(reader) => reader,
+            Err(err) => {
+                error!("Executor: Error reading file {:?}: {:?}", file.name, err);
+                for result in dest_results {
+                    result.record_error(file, &err);
                 }
-                Err(err) => {
-                    panic!("Error: {:?}", err);
+                file.mark_error(err);
+                return;
+            }
+        };
+        file.state = FileState::Read;
+        results.files += 1;
+
+        match chain.on_read(file) {
+            MiddlewareAction::Continue => (),
+            MiddlewareAction::Skip(_) => {
+                results.skipped += 1;
+                return;
+            }
+            MiddlewareAction::Error(reason) => {
+                for result in dest_results {
+                    result.record_error(file, &reason);
                 }
+                return;
             }
         }
-        let _ = plan.save_plan(&test_dir);
 
-        let batch_path = plan.path
-                .join(".vbatches/")
-                .join(plan.batches[1].clone() + ".vbak_batch");
+        // Destinations that still hold the same file are left out, the file
+        // only counts as skipped if every destination is
+        let left_out: Vec<Option<String>> = plan
+            .destinations
+            .iter()
+            .map(|dest| {
+                chain
+                    .skip_destination(file, &dest.get_id())
+                    .filter(|_| dest.has_file(file))
+            })
+            .collect();
+        if let Some(Some(reason)) = left_out.first() {
+            if left_out.iter().all(|reason| reason.is_some()) {
+                debug!("Executor: Skipping {:?}: {}", file.path, reason);
+                file.mark_skipped(reason.clone());
+                results.skipped += 1;
+                let stored: Vec<String> =
+                    plan.destinations.iter().map(|dest| dest.get_id()).collect();
+                chain.after_write(file, &stored);
+                return;
+            }
+        }
 
-        // Test plan_res is Ok
-        let plan_res = Executor::process_batch(&plan, &batch_path);
+        let inspected: SharedInspectors = Rc::new(RefCell::new((inspectors, false)));
+        let reader = InspectingReader {
+            inner: reader,
+            inspectors: inspected.clone(),
+        };
+        let read_size = Rc::new(Cell::new(0));
+        let reader = CountingReader {
+            inner: Box::new(reader),
+            count: read_size.clone(),
+        };
+        let reader = match chain.before_write(file, Box::new(reader)) {
+            Ok(reader) => reader,
+            Err(err) => {
+                for result in dest_results {
+                    result.record_error(file, &err);
+                }
+                return;
+            }
+        };
 
-        // file_remove_all(&test_dir.clone()).expect("Could not remove dest dir");
+        let (stored_size, stored) =
+            Executor::write_streamed(plan, file, reader, &left_out, dest_results);
+        if let Some(stored_size) = stored_size {
+            file.size = read_size.get();
+            results.bytes_saved += read_size.get() as i64 - stored_size as i64;
+        }
+        let (inspectors, complete) = inspected.take();
+        if complete {
+            for inspector in inspectors {
+                inspector.finish(file);
+            }
+        }
+
+        file.clear_contents();
+        chain.after_write(file, &stored);
+        if stored.len() < plan.destinations.len() {
+            file.mark_error("not written to every destination".to_string());
+        }
+    }
+
+    /// Saves a batch once all of its files are done, marks it done in the
+    /// checkpoint and adds its results to the run's
+    fn finish_batch(
+        run: &BatchRun,
+        chain: &MiddlewareChain,
+        checkpoint: &Checkpoint,
+        total: &Mutex<ExecutorDiscoveryResults>,
+    ) {
+        let resumed = run.resumed.load(Ordering::Relaxed);
+        if resumed > 0 {
+            info!(
+                "Executor: {} files of batch {} were finished by the previous run",
+                resumed, run.name
+            );
+        }
+
+        if let Err(err) = run.batch.lock().unwrap().save_batch(run.path.clone()) {
+            warn!("Executor: Error saving processed batch: {:?}", err);
+        }
+
+        let mut results = run.results.lock().unwrap();
+        results.batch_time = run.start_time.elapsed();
+        results.total_time = run.start_time.elapsed();
+        if results.bytes_saved != 0 {
+            info!(
+                "Middleware saved {} kb in batch {}",
+                results.bytes_saved / 1024,
+                run.name
+            );
+        }
+        for result in &results.destinations {
+            info!(
+                "Wrote {} files to {:?} ({} failed, {} skipped, {} kept existing) in {:.4}s",
+                result.written,
+                result.name,
+                result.failed,
+                results.skipped,
+                result.skipped,
+                results.batch_time.as_secs_f64()
+            );
+        }
+        if let Err(err) = checkpoint.complete_batch(&run.name, chain) {
+            warn!("Executor: Error saving checkpoint: {:?}", err);
+        }
+        total.lock().unwrap().add(&results);
+    }
+
+    /// Reads a file through the given inspectors without keeping its contents
+    fn inspect_file(
+        source: &dyn Source,
+        file: &mut VictoryFile,
+        inspectors: Vec<Box<dyn Inspector>>,
+    ) -> Result<(), String> {
+        let mut inspectors = inspectors;
+        let mut reader = source.open_file(file)?;
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut size = 0;
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(format!("read Error: {:?}", err)),
+            };
+            size += read;
+            for inspector in &mut inspectors {
+                inspector.update(&buf[..read]);
+            }
+        }
+        for inspector in inspectors {
+            inspector.finish(file);
+        }
+        file.size = size;
+        Ok(())
+    }
+
+    /// Recreates an entry without contents (a link or directory) at a sink
+    fn write_entry_to(sink: &dyn Sink, file: &VictoryFile) -> Result<WriteDecision, String> {
+        if file.kind == FileKind::Directory {
+            sink.create_dir(file)?;
+            return Ok(WriteDecision::Write);
+        }
+        let decision = sink.decide_write(file)?;
+        match &decision {
+            WriteDecision::Skip(_) | WriteDecision::Fail(_) => (),
+            WriteDecision::KeepBoth(path) => sink.write_link(&file.with_path(path))?,
+            _ => sink.write_link(file)?,
+        }
+        Ok(decision)
+    }
+
+    /// Opens a writer for a file at a sink, following the sink's conflict
+    /// policy. No writer is opened if the sink keeps an existing file.
+    fn open_writer<'a>(
+        sink: &'a dyn Sink,
+        file: &VictoryFile,
+    ) -> Result<OpenedWriter<'a>, String> {
+        let decision = sink.decide_write(file)?;
+        let writer = match &decision {
+            WriteDecision::Skip(_) | WriteDecision::Fail(_) => None,
+            WriteDecision::KeepBoth(path) => Some(sink.create_writer(&file.with_path(path))?),
+            _ => Some(sink.create_writer(file)?),
+        };
+        Ok((decision, writer))
+    }
+
+    /// Remembers the other path a destination stored the file under, so it
+    /// is restored from there
+    fn keep_decision(file: &mut VictoryFile, dest: &str, decision: &WriteDecision) {
+        match decision {
+            WriteDecision::KeepBoth(path) => file.set_kept_as(dest, Some(path)),
+            WriteDecision::Skip(_) | WriteDecision::Fail(_) => (),
+            _ => file.set_kept_as(dest, None),
+        }
+    }
+
+    /// Recreates a link or directory at every destination of the plan
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether every destination stored the entry
+    fn write_entry(
+        plan: &BackupPlan,
+        file: &mut VictoryFile,
+        dest_results: &mut [DestinationResults],
+    ) -> bool {
+        let mut all_written = true;
+        for (dest, result) in plan.destinations.iter().zip(dest_results.iter_mut()) {
+            match Executor::write_entry_to(dest.as_ref(), file) {
+                Ok(decision) => {
+                    all_written &= !matches!(decision, WriteDecision::Fail(_));
+                    Executor::keep_decision(file, &dest.get_id(), &decision);
+                    result.record(file, decision);
+                }
+                Err(err) => {
+                    error!(
+                        "Executor: Error creating {:?} at {:?}: {:?}",
+                        file.path, result.name, err
+                    );
+                    result.record_error(file, &err);
+                    all_written = false;
+                }
+            }
+        }
+        all_written
+    }
+
+    /// Streams the contents of a file to every destination of the plan that
+    /// is not left out, a chunk at a time. A destination that fails is
+    /// dropped without keeping a partial file, the others carry on.
+    ///
+    /// # Returns
+    ///
+    /// * `(Option<usize>, Vec<String>)` - Bytes streamed (none if every destination kept
+    ///   its existing file), and the id of every destination holding the file,
+    ///   left out ones included
+    fn write_streamed(
+        plan: &BackupPlan,
+        file: &mut VictoryFile,
+        reader: Box<dyn Read + '_>,
+        left_out: &[Option<String>],
+        dest_results: &mut [DestinationResults],
+    ) -> (Option<usize>, Vec<String>) {
+        let mut reader = reader;
+        let mut writers: Vec<Option<Result<OpenedWriter, String>>> = plan
+            .destinations
+            .iter()
+            .zip(left_out)
+            .map(|(dest, reason)| match reason {
+                Some(_) => None,
+                None => Some(Executor::open_writer(dest.as_ref(), file)),
+            })
+            .collect();
+
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut size = 0;
+        let mut read_error = None;
+        // Nothing to read if every destination keeps what it has
+        let writing = writers
+            .iter()
+            .any(|writer| matches!(writer, Some(Ok((_, Some(_))))));
+        if writing {
+            loop {
+                let read = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        read_error = Some(format!("read Error: {:?}", err));
+                        break;
+                    }
+                };
+                size += read;
+                for writer in writers.iter_mut().flatten() {
+                    if let Ok((_, Some(open))) = writer {
+                        if let Err(err) = open.write_all(&buf[..read]) {
+                            *writer = Err(format!("write Error: {:?}", err));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut stored = Vec::new();
+        for ((writer, result), dest) in writers
+            .into_iter()
+            .zip(dest_results.iter_mut())
+            .zip(&plan.destinations)
+        {
+            let writer = match writer {
+                Some(writer) => writer,
+                None => {
+                    stored.push(dest.get_id());
+                    continue;
+                }
+            };
+            let res = match (writer, &read_error) {
+                (Ok((decision, None)), _) => Ok(decision),
+                // Dropping the writer discards what was streamed so far
+                (Ok(_), Some(err)) => Err(err.clone()),
+                (Ok((decision, Some(writer))), None) => writer.commit().map(|_| decision),
+                (Err(err), _) => Err(err),
+            };
+            match res {
+                Ok(decision) => {
+                    if !matches!(decision, WriteDecision::Fail(_)) {
+                        stored.push(dest.get_id());
+                    }
+                    Executor::keep_decision(file, &dest.get_id(), &decision);
+                    result.record(file, decision);
+                }
+                Err(err) => {
+                    error!(
+                        "Executor: Error writing file {:?} to {:?}: {:?}",
+                        file.path, result.name, err
+                    );
+                    result.record_error(file, &err);
+                }
+            }
+        }
+        (writing.then_some(size), stored)
+    }
+
+    /// Applies the metadata of every stored directory of the plan's batches,
+    /// once all of their children were written. Children go before their
+    /// parents, so a read-only parent never keeps its children from being updated.
+    pub fn finish_directories(plan: &BackupPlan, sinks: &[&dyn Sink]) -> Result<(), String> {
+        for batch_name in plan.batches.iter().rev() {
+            let batch_path = plan
+                .path
+                .join(".vbatches/")
+                .join(batch_name.to_string() + ".vbak_batch");
+            let mut batch = FileBatch::load_batch(batch_path)?;
+            for file in batch.get_files().iter().rev() {
+                if file.kind != FileKind::Directory || file.state != FileState::Stored {
+                    continue;
+                }
+                for sink in sinks {
+                    if let Err(err) = sink.finish_dir(file) {
+                        warn!(
+                            "Executor: Error setting metadata of {:?} at {:?}: {:?}",
+                            file.path,
+                            sink.get_name(),
+                            err
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes every batch of the plan through the plan's middleware
+    pub fn run(plan: &BackupPlan) -> Result<ExecutorDiscoveryResults, String> {
+        let chain = MiddlewareChain::from_plan(plan)?;
+        Executor::run_with_chain(plan, &chain)
+    }
+
+    /// Processes every batch of the plan through the given middleware
+    pub fn run_with_chain(
+        plan: &BackupPlan,
+        chain: &MiddlewareChain,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        let plan_path = plan.path.clone();
+
+        debug!(
+            "Executor: Running backup plan {} with {} middleware stages",
+            plan.name,
+            chain.get_length()
+        );
+        let run_start_time = Instant::now();
+        let checkpoint = Checkpoint::load(plan.get_checkpoint_path(), &plan.name, &plan.batches)?;
+        checkpoint.resume(chain)?;
+        let mut batch_paths = Vec::new();
+        for batch in &plan.batches {
+            if checkpoint.is_batch_done(batch) {
+                debug!("Executor: Batch {} was finished by the previous run", batch);
+                continue;
+            }
+            batch_paths.push(
+                plan_path
+                    .join(".vbatches/")
+                    .join(batch.to_string() + ".vbak_batch"),
+            );
+        }
+        let mut combined_results =
+            Executor::process_batches(plan, &batch_paths, chain, &checkpoint)?;
+        // Batches overlap, the run took as long as it did on the clock
+        combined_results.total_time = run_start_time.elapsed();
+        Executor::finish_run(plan, chain, &checkpoint, &combined_results)?;
+        Ok(combined_results)
+    }
+
+    /// Discovers and runs the plan at once through the plan's middleware,
+    /// see [`Executor::discover_and_run_with_chain`]
+    pub fn discover_and_run(
+        plan: &mut BackupPlan,
+        limits: BatchLimits,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        let chain = MiddlewareChain::from_plan(plan)?;
+        Executor::discover_and_run_with_chain(plan, limits, &chain)
+    }
+
+    /// Discovers and runs the plan at once, so files get copied while the
+    /// sources are still being listed. The plan's sources are listed and read
+    /// from at the same time.
+    ///
+    /// Every batch is added to the saved plan and to the checkpoint as soon
+    /// as it is saved, then queued for the workers. An interrupted run can be
+    /// resumed with [`Executor::run`], for the batches discovered until then.
+    pub fn discover_and_run_with_chain(
+        plan: &mut BackupPlan,
+        limits: BatchLimits,
+        chain: &MiddlewareChain,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        let run_start_time = Instant::now();
+        let filters = Executor::source_filters(plan, chain)?;
+        Checkpoint::remove_path(&plan.get_checkpoint_path())?;
+        for (source, filter) in plan.sources.iter_mut().zip(&filters) {
+            source.add_filter(filter)?;
+        }
+
+        debug!(
+            "Executor: Discovering and running backup plan {} with {} middleware stages",
+            plan.name,
+            chain.get_length()
+        );
+        let checkpoint = Checkpoint::new(
+            plan.get_checkpoint_path(),
+            CheckpointState::new(&plan.name, &[]),
+        );
+        let (sender, receiver) = mpsc::channel();
+        let shared: &BackupPlan = plan;
+        let (discovered, processed) = std::thread::scope(|scope| {
+            let discovery = scope.spawn(|| {
+                // Both are written through a temp file, so an interrupted
+                // run finds every batch cut until then
+                let save_batches = |batches: &[String]| {
+                    if shared.path != Path::new("") {
+                        let mut saved = shared.get_saved();
+                        saved.batches = batches.to_vec();
+                        BackupPlan::write_saved(&saved, &shared.path)?;
+                    }
+                    checkpoint.set_batches(batches);
+                    checkpoint.save(chain)
+                };
+                let cut = Mutex::new(Vec::new());
+                let on_batch = |batch: &str, batch_path: PathBuf| {
+                    let mut batches = cut.lock().unwrap();
+                    batches.push(batch.to_string());
+                    save_batches(&batches)?;
+                    // The run is gone once it failed, discovery carries on regardless
+                    let _ = sender.send(batch_path);
+                    Ok(())
+                };
+                let discovered: Vec<Result<SourceDiscovery, String>> =
+                    std::thread::scope(|sources| {
+                        let handles: Vec<_> = shared
+                            .sources
+                            .iter()
+                            .map(|source| {
+                                sources.spawn(|| {
+                                    Executor::discover_source(
+                                        &shared.name,
+                                        &shared.path,
+                                        source.as_ref(),
+                                        &limits,
+                                        chain,
+                                        Some(&on_batch),
+                                    )
+                                })
+                            })
+                            .collect();
+                        handles
+                            .into_iter()
+                            .map(|handle| match handle.join() {
+                                Ok(discovered) => discovered,
+                                Err(_) => Err("Executor: Discovery thread panicked".to_string()),
+                            })
+                            .collect()
+                    });
+                // The workers run out of batches once every source is listed
+                drop(sender);
+                // Saved again in source order, so the plan comes out the same
+                // whichever source finished first
+                let mut batches = Vec::new();
+                for discovered in discovered {
+                    batches.extend(discovered?.0);
+                }
+                save_batches(&batches)?;
+                info!("Executor: Discovered {} batches", batches.len());
+                Ok(batches)
+            });
+            let processed =
+                Executor::process_queued(shared, receiver.into_iter(), chain, &checkpoint);
+            let discovered = match discovery.join() {
+                Ok(discovered) => discovered,
+                Err(_) => Err("Executor: Discovery thread panicked".to_string()),
+            };
+            (discovered, processed)
+        });
+        let batches = match discovered {
+            Ok(batches) => batches,
+            Err(err) => {
+                error!("Executor: Error discovering batches: {:?}", err);
+                return Err(err);
+            }
+        };
+        plan.batches = batches;
+
+        let mut combined_results = processed?;
+        combined_results.total_time = run_start_time.elapsed();
+        Executor::finish_run(plan, chain, &checkpoint, &combined_results)?;
+        Ok(combined_results)
+    }
+
+    /// Wraps up a run once every batch was processed: applies directory
+    /// metadata, finishes the middleware and removes the checkpoint
+    fn finish_run(
+        plan: &BackupPlan,
+        chain: &MiddlewareChain,
+        checkpoint: &Checkpoint,
+        combined_results: &ExecutorDiscoveryResults,
+    ) -> Result<(), String> {
+        let sinks: Vec<&dyn Sink> = plan.destinations.iter().map(|dest| dest.as_ref()).collect();
+        if let Err(err) = Executor::finish_directories(plan, &sinks) {
+            error!("Executor: Error finishing directories: {:?}", err);
+            return Err(err);
+        }
+        if let Err(err) = chain.finish() {
+            error!("Executor: Error finishing middleware: {:?}", err);
+            return Err(err);
+        }
+        // The run completed, the next one starts from the first batch again
+        if let Err(err) = checkpoint.remove() {
+            warn!("Executor: Error removing checkpoint: {:?}", err);
+        }
+        info!(
+            "Executor: Total time to process {} batches with {} files ({} skipped): {}ms",
+            combined_results.batches,
+            combined_results.files.to_formatted_string(&Locale::en),
+            combined_results.skipped.to_formatted_string(&Locale::en),
+            combined_results.total_time.as_millis()
+        );
+        if combined_results.bytes_saved != 0 {
+            info!(
+                "Executor: Middleware saved {} kb in total",
+                (combined_results.bytes_saved / 1024).to_formatted_string(&Locale::en)
+            );
+        }
+        for dest in &combined_results.destinations {
+            info!(
+                "Executor: Destination {:?}: {} written, {} failed, {} kept existing, {} conflicts",
+                dest.name,
+                dest.written.to_formatted_string(&Locale::en),
+                dest.failed.to_formatted_string(&Locale::en),
+                dest.skipped.to_formatted_string(&Locale::en),
+                dest.conflicts.len().to_formatted_string(&Locale::en)
+            );
+        }
+        Ok(())
+    }
+
+    /// Restores every backed up file of the plan's batches. Files that can not
+    /// be restored are listed with why in the results of `to`.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The plan the backup was made with
+    /// * `from` - Where the backup is stored, usually one of the plan's destinations
+    /// * `to` - Where restored files are written, using the same per-source layout as the backup
+    pub fn restore(
+        plan: &BackupPlan,
+        from: &dyn Source,
+        to: &dyn Sink,
+    ) -> Result<ExecutorDiscoveryResults, String> {
+        let restore_start_time = std::time::Instant::now();
+        info!(
+            "Executor: Restoring backup plan {} from {:?} to {:?}",
+            plan.name,
+            from.get_name(),
+            to.get_name()
+        );
+        let chain = MiddlewareChain::from_plan(plan)?;
+
+        let mut restored = 0;
+        let mut result = DestinationResults::new(to.get_name());
+        for batch_name in &plan.batches {
+            let batch_path = plan
+                .path
+                .join(".vbatches/")
+                .join(batch_name.to_string() + ".vbak_batch");
+            let mut batch = FileBatch::load_batch(batch_path)?;
+
+            for file in batch.get_files() {
+                if file.state != FileState::Stored && file.state != FileState::Skipped {
+                    debug!(
+                        "Executor: {:?} was never backed up, not restoring",
+                        file.path
+                    );
+                    continue;
+                }
+
+                if !file.kind.has_contents() {
+                    match Executor::write_entry_to(to, file) {
+                        Ok(decision) => result.record(file, decision),
+                        Err(err) => {
+                            error!("Executor: Error restoring {:?}: {:?}", file.path, err);
+                            result.record_error(file, &err);
+                            file.mark_error(err);
+                        }
+                    }
+                    continue;
+                }
+
+                // Files are stored under their source's subtree at the backup,
+                // or under the other path the backup kept them as
+                let mut stored = file.clone();
+                stored.path = file.get_stored_path(&from.get_id());
+                stored.source = String::new();
+                let reader = match from.open_file(&mut stored) {
+                    Ok(reader) => reader,
+                    Err(err) => {
+                        error!(
+                            "Executor: Error reading backup of {:?}: {:?}",
+                            file.path, err
+                        );
+                        result.record_error(file, &err);
+                        file.mark_error(err);
+                        continue;
+                    }
+                };
+                restored += 1;
+
+                // Undo the transforms applied before writing, in reverse order
+                let mut reader = match chain.on_restore(file, reader) {
+                    Ok(reader) => reader,
+                    Err(err) => {
+                        error!("Executor: Error restoring {:?}: {}", file.path, err);
+                        result.record_error(file, &err);
+                        file.mark_error(err);
+                        continue;
+                    }
+                };
+
+                let res = match Executor::open_writer(to, file) {
+                    Ok((decision, None)) => Ok(decision),
+                    Ok((decision, Some(mut writer))) => {
+                        match std::io::copy(&mut reader, &mut writer) {
+                            Ok(_) => writer.commit().map(|_| decision),
+                            // Dropping the writer discards the partially restored file
+                            Err(err) => Err(format!("read Error: {:?}", err)),
+                        }
+                    }
+                    Err(err) => Err(err),
+                };
+                match res {
+                    Ok(decision) => result.record(file, decision),
+                    Err(err) => {
+                        error!("Executor: Error restoring {:?}: {:?}", file.path, err);
+                        result.record_error(file, &err);
+                        file.mark_error(err);
+                    }
+                }
+            }
+        }
+        Executor::finish_directories(plan, &[to])?;
+
+        info!(
+            "Executor: Restored {} files ({} failed) in {:.4}s",
+            result.written.to_formatted_string(&Locale::en),
+            result.failed.to_formatted_string(&Locale::en),
+            restore_start_time.elapsed().as_secs_f64()
+        );
+        let mut results = ExecutorDiscoveryResults::new(
+            restored,
+            plan.batches.len(),
+            restore_start_time,
+            std::time::Instant::now(),
+            std::time::Instant::now(),
+        );
+        results.destinations.push(result);
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod executor_tests {
+    use crate::{
+        batch::{BatchLimits, FileBatch},
+        destination::{
+            filesystem_dest::FileSystemDestination, registry::BackendConfig, Backend,
+            ConflictPolicy, Source, WriteDecision,
+        },
+        executor::Executor,
+        file::{FileState, VictoryFile},
+        metadata::FileMetadata,
+        middleware::{
+            checkpoint::{Checkpoint, CheckpointState},
+            encrypt::{EncryptionConfig, ENCRYPT_MAGIC},
+            filter_glob::GlobFilterConfig,
+            filter_hash::HashIndex,
+            zip::{CompressionAlgorithm, CompressionConfig, COMPRESS_MAGIC},
+            Middleware, MiddlewareAction, MiddlewareChain, MiddlewareConfig,
+        },
+        utils::file_utils::{file_generates_folder, file_remove_all, file_test_dir},
+        workers::WorkerConfig,
+    };
+    use std::{
+        io::Read,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn test_discover() {
+        let n_files = 200;
+        let batch_size = 10;
+        let test_dir: std::path::PathBuf = file_test_dir("test_discover".to_string());
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_discover".to_string());
+        let mut source_path = test_dir.clone();
+        source_path.push("source");
+
+        let mut dest_path = test_dir.clone();
+        dest_path.push("dest");
+
+        let _ = file_generates_folder(&source_path, 100, n_files);
+
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        {
+            let res = Executor::discover(&mut plan, batch_size);
+
+            match res {
+                Ok(res) => {
+                    assert_eq!(res.files, n_files);
+                    assert_eq!(res.batches, n_files / batch_size as usize);
+                }
+                Err(err) => {
+                    panic!("Error: {:?}", err);
+                }
+            }
+        }
+
+        assert_eq!(plan.batches.len(), n_files / batch_size as usize);
+
+        file_remove_all(&test_dir.clone()).expect("Could not remove dest dir");
+    }
+
+    #[test]
+    fn test_discover_is_deterministic() {
+        let test_dir = file_test_dir("test_discover_is_deterministic".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        for folder in ["photos", "photos/2023", "photos/2024", "docs", "docs/old"] {
+            file_generates_folder(&test_dir.join("sources").join(folder), 10, 9).unwrap();
+        }
+
+        let discover = |walk_threads: usize| {
+            let mut plan = crate::plan::BackupPlan::new("plan__test_deterministic".to_string());
+            for source in ["photos", "docs"] {
+                let source_path = test_dir.join("sources").join(source);
+                plan.add_source(Box::new(
+                    FileSystemDestination::new(source_path.to_str().unwrap().to_string())
+                        .with_walk_threads(walk_threads),
+                ));
+            }
+            let plan_path = test_dir.join(format!("plan_{}", walk_threads));
+            plan.save_plan(&plan_path).expect("Could not save plan");
+            let res = Executor::discover(&mut plan, 7).expect("Discovery failed");
+            assert_eq!(res.files, 5 * 9 + 3);
+            plan.batches
+                .iter()
+                .map(|name| {
+                    let batch_path = plan_path
+                        .join(".vbatches/")
+                        .join(name.clone() + ".vbak_batch");
+                    let batch = FileBatch::load_batch(batch_path).unwrap();
+                    // Listing a folder may touch its access time, so no metadata
+                    let files: Vec<_> = batch
+                        .files
+                        .iter()
+                        .map(|file| (file.path.clone(), file.kind.clone()))
+                        .collect();
+                    (name.clone(), batch.get_filtered(), files)
+                })
+                .collect::<Vec<_>>()
+        };
+        let sequential = discover(1);
+        assert_eq!(sequential.len(), 5 + 3);
+        assert_eq!(discover(4), sequential);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_process_batch() {
+        let n_files = 200;
+        let batch_size = 10;
+        let test_dir: std::path::PathBuf = file_test_dir("test_process_batch".to_string());
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_process_batch".to_string());
+        let mut source_path = test_dir.clone();
+        source_path.push("source");
+
+        let mut dest_path = test_dir.clone();
+        dest_path.push("dest");
+
+        let _ = file_generates_folder(&source_path, 100, n_files);
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        {
+            let res = Executor::discover(&mut plan, batch_size);
+
+            match res {
+                Ok(res) => {
+                    assert_eq!(res.files, n_files);
+                    assert_eq!(res.batches, n_files / batch_size as usize);
+                }
+                Err(err) => {
+                    panic!("Error: {:?}", err);
+                }
+            }
+        }
+        let _ = plan.save_plan(&test_dir);
+
+        let batch_path = plan
+            .path
+            .join(".vbatches/")
+            .join(plan.batches[1].clone() + ".vbak_batch");
+
+        // Test plan_res is Ok
+        let chain = MiddlewareChain::from_plan(&plan).expect("Could not build middleware");
+        let checkpoint = Checkpoint::load(plan.get_checkpoint_path(), &plan.name, &plan.batches)
+            .expect("Could not load checkpoint");
+        let plan_res = Executor::process_batch(&plan, &batch_path, &chain, &checkpoint);
+        assert!(plan_res.is_ok());
+
+        // file_remove_all(&test_dir.clone()).expect("Could not remove dest dir");
+    }
+
+    #[test]
+    fn test_run_fans_out_to_every_destination() {
+        let n_files = 20;
+        let batch_size = 10;
+        let test_dir = file_test_dir("test_run_fans_out".to_string());
+        let source_path = test_dir.join("source");
+        let dest_a = test_dir.join("dest_a");
+        let dest_b = test_dir.join("dest_b");
+        // Nested under a regular file, so every write to it fails
+        let dest_broken = source_path.join("file_0").join("broken");
+
+        file_generates_folder(&source_path, 100, n_files).unwrap();
+        std::fs::create_dir_all(&dest_a).unwrap();
+        std::fs::create_dir_all(&dest_b).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_run_fans_out".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        for dest in [&dest_a, &dest_broken, &dest_b] {
+            plan.add_destination(Box::new(FileSystemDestination::new(
+                dest.to_str().unwrap().to_string(),
+            )));
+        }
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, batch_size).expect("Discovery failed");
+
+        let res = Executor::run(&plan).expect("Run failed");
+        assert_eq!(res.files, n_files);
+        assert_eq!(res.destinations.len(), 3);
+        assert_eq!(res.destinations[0].written, n_files);
+        assert_eq!(res.destinations[0].failed, 0);
+        assert_eq!(res.destinations[1].written, 0);
+        assert_eq!(res.destinations[1].failed, n_files);
+        assert_eq!(res.destinations[2].written, n_files);
+        assert_eq!(res.destinations[2].name, dest_b.to_str().unwrap());
+
+        let source_id = plan.sources[0].get_id();
+        for i in 0..n_files {
+            let name = format!("file_{}", i);
+            assert_eq!(
+                std::fs::read(dest_a.join(&source_id).join(&name)).unwrap(),
+                std::fs::read(source_path.join(&name)).unwrap()
+            );
+            assert!(dest_b.join(&source_id).join(&name).exists());
+        }
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_run_reads_from_matching_source() {
+        let test_dir = file_test_dir("test_run_matching_source".to_string());
+        let source_a = test_dir.join("source_a");
+        let source_b = test_dir.join("source_b");
+        let dest_path = test_dir.join("dest");
+
+        // Same relative names in both sources, told apart by size
+        file_generates_folder(&source_a, 100, 15).unwrap();
+        file_generates_folder(&source_b, 250, 5).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_matching_source".to_string());
+        for source in [&source_a, &source_b] {
+            plan.add_source(Box::new(FileSystemDestination::new(
+                source.to_str().unwrap().to_string(),
+            )));
+        }
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+
+        let id_a = plan.sources[0].get_id();
+        let id_b = plan.sources[1].get_id();
+        assert_eq!(plan.batches.len(), 3);
+        assert!(plan.batches[0].contains(&id_a));
+        assert!(plan.batches[2].contains(&id_b));
+
+        let batch_path = plan
+            .path
+            .join(".vbatches/")
+            .join(plan.batches[2].clone() + ".vbak_batch");
+        let batch = FileBatch::load_batch(batch_path).unwrap();
+        assert_eq!(batch.get_source(), id_b);
+        assert!(batch.files.iter().all(|file| file.source == id_b));
+
+        let res = Executor::run(&plan).expect("Run failed");
+        assert_eq!(res.files, 20);
+        assert_eq!(res.destinations[0].written, 20);
+        assert_eq!(res.destinations[0].failed, 0);
+
+        let file_a = dest_path.join(&id_a).join("file_0");
+        let file_b = dest_path.join(&id_b).join("file_0");
+        assert_eq!(file_a.metadata().unwrap().len(), 100);
+        assert_eq!(file_b.metadata().unwrap().len(), 250);
+        assert!(!dest_path.join(&id_b).join("file_10").exists());
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_discover_rejects_duplicate_source_ids() {
+        let mut plan = crate::plan::BackupPlan::new("plan__test_duplicate_ids".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            "/data/a_b".to_string(),
+        )));
+        plan.add_source(Box::new(FileSystemDestination::new(
+            "/data/a/b".to_string(),
+        )));
+
+        let err = Executor::discover(&mut plan, 10).err().unwrap();
+        assert!(err.contains("same id"));
+
+        // An explicit id tells them apart
+        plan.sources[1] = Box::new(
+            FileSystemDestination::new("/data/a/b".to_string()).with_id(Some("nested".to_string())),
+        );
+        assert_eq!(plan.sources[1].get_id(), "nested");
+        let filters = Executor::source_filters(&plan, &MiddlewareChain::new()).unwrap();
+        assert_eq!(filters.len(), 2);
+    }
+
+    #[test]
+    fn test_discover_excludes_nested_outputs() {
+        let test_dir = file_test_dir("test_discover_excludes_nested_outputs".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("data");
+        file_generates_folder(&source_path.join("docs"), 100, 2).unwrap();
+        std::fs::create_dir_all(source_path.join("_dest")).unwrap();
+        // Resolved through the link, still inside the source
+        let real_path = source_path.canonicalize().unwrap();
+        std::os::unix::fs::symlink(real_path, test_dir.join("linked")).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_nested".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            test_dir
+                .join("linked")
+                .join("_dest")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        )));
+        plan.save_plan(&source_path.join("__vk"))
+            .expect("Could not save plan");
+        Executor::discover(&mut plan, 50).expect("Discovery failed");
+        Executor::run(&plan).expect("Run failed");
+        // Discovering again does not pick up the first run's output
+        let mut plan = crate::plan::BackupPlan::from_saved(plan.get_saved()).unwrap();
+        let res = Executor::discover(&mut plan, 50).expect("Discovery failed");
+        assert_eq!(res.files, 3);
+
+        let written = source_path.join("_dest").join(plan.sources[0].get_id());
+        assert!(written.join("docs").join("file_1").is_file());
+        assert!(!written.join("_dest").exists());
+        assert!(!written.join("__vk").exists());
+
+        // A destination holding the source can not be left out
+        let mut plan = crate::plan::BackupPlan::new("plan__test_nested".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.join("docs").to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir.join("__vk"))
+            .expect("Could not save plan");
+        match Executor::discover(&mut plan, 50) {
+            Ok(_) => panic!("Discovered a source inside its destination"),
+            Err(err) => assert!(err.contains("is inside destination"), "{}", err),
+        }
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_discover_with_byte_limit() {
+        let test_dir = file_test_dir("test_discover_with_byte_limit".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("source");
+        file_generates_folder(&source_path.join("small"), 100, 30).unwrap();
+        file_generates_folder(&source_path.join("videos"), 5000, 3).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_byte_limit".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir.join("plan"))
+            .expect("Could not save plan");
+        let limits = BatchLimits::new(20).with_bytes(1000);
+        let res = Executor::discover_with_limits(&mut plan, limits).expect("Discovery failed");
+        // Both folders are listed too
+        assert_eq!(res.files, 35);
+
+        let batches: Vec<FileBatch> = plan
+            .batches
+            .iter()
+            .map(|name| {
+                FileBatch::load_batch(
+                    plan.path
+                        .join(".vbatches/")
+                        .join(name.clone() + ".vbak_batch"),
+                )
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| batch.get_length())
+                .sum::<usize>(),
+            35
+        );
+        for batch in &batches {
+            assert!(batch.get_length() <= 20);
+            assert!(batch.get_size() <= 1000 || batch.get_length() == 1);
+        }
+        let videos: Vec<&FileBatch> = batches
+            .iter()
+            .filter(|batch| batch.files.iter().any(|file| file.size == 5000))
+            .collect();
+        assert_eq!(videos.len(), 3);
+        assert!(videos.iter().all(|batch| batch.get_length() == 1));
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_discover_filtered() {
+        let test_dir = file_test_dir("test_discover_filtered".to_string());
+        let source_path = test_dir.join("source");
+        file_generates_folder(&source_path, 10, 10).unwrap();
+        file_generates_folder(&source_path.join("skip"), 10, 5).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_discover_filtered".to_string());
+        plan.add_middleware(MiddlewareConfig::Glob(GlobFilterConfig::new(
+            Vec::new(),
+            vec!["skip/".to_string()],
+        )));
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+
+        let res = Executor::discover(&mut plan, 4).expect("Discovery failed");
+        assert_eq!(res.files, 10);
+        assert_eq!(res.filtered, 1);
+
+        let mut batch_filtered = 0;
+        for batch_name in &plan.batches {
+            let batch_path = plan
+                .path
+                .join(".vbatches/")
+                .join(batch_name.clone() + ".vbak_batch");
+            let batch = FileBatch::load_batch(batch_path).unwrap();
+            assert!(batch
+                .files
+                .iter()
+                .all(|file| !file.path.starts_with("skip")));
+            batch_filtered += batch.get_filtered();
+        }
+        assert_eq!(batch_filtered, 1);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_run_skips_unchanged() {
+        let n_files = 12;
+        let test_dir = file_test_dir("test_run_skips_unchanged".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        file_generates_folder(&source_path, 100, n_files).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_run_skips_unchanged".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 5).expect("Discovery failed");
+
+        let first = Executor::run(&plan).expect("First run failed");
+        assert_eq!(first.skipped, 0);
+        assert_eq!(first.destinations[0].written, n_files);
+
+        // Hashes and states are stored in the processed batch
+        let batch_path = plan
+            .path
+            .join(".vbatches/")
+            .join(plan.batches[0].clone() + ".vbak_batch");
+        let batch = FileBatch::load_batch(batch_path.clone()).unwrap();
+        assert!(batch.files.iter().all(|file| file.hash.len() == 64));
+        assert!(batch
+            .files
+            .iter()
+            .all(|file| file.state == FileState::Stored));
+
+        // Change a single file, the rest should be skipped
+        std::fs::write(source_path.join("file_3"), b"changed").unwrap();
+        let second = Executor::run(&plan).expect("Second run failed");
+        assert_eq!(second.skipped, n_files - 1);
+        assert_eq!(second.destinations[0].written, 1);
+        assert_eq!(
+            std::fs::read(dest_path.join(plan.sources[0].get_id()).join("file_3")).unwrap(),
+            b"changed"
+        );
+        let batch = FileBatch::load_batch(batch_path).unwrap();
+        assert_eq!(batch.files[0].state, FileState::Skipped);
+        assert_eq!(
+            batch.files[0].reason,
+            Some("hash: unchanged since the previous run".to_string())
+        );
+
+        // Disabling change detection writes everything again
+        plan.middleware = vec![MiddlewareConfig::Hash {
+            skip_unchanged: false,
+        }];
+        let third = Executor::run(&plan).expect("Third run failed");
+        assert_eq!(third.skipped, 0);
+        assert_eq!(third.destinations[0].written, n_files);
+
+        // Files stored uncompressed are written again once compression is enabled
+        plan.middleware = MiddlewareConfig::defaults();
+        plan.add_middleware(MiddlewareConfig::Compress(CompressionConfig::new(
+            CompressionAlgorithm::Zstd,
+        )));
+        let compressed = Executor::run(&plan).expect("Compressed run failed");
+        assert_eq!(compressed.skipped, 0);
+        assert_eq!(compressed.destinations[0].written, n_files);
+        let stored =
+            std::fs::read(dest_path.join(plan.sources[0].get_id()).join("file_0")).unwrap();
+        assert_eq!(&stored[..4], COMPRESS_MAGIC);
+        let again = Executor::run(&plan).expect("Last run failed");
+        assert_eq!(again.skipped, n_files);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_run_skips_unchanged_per_destination() {
+        let n_files = 6;
+        let test_dir = file_test_dir("test_run_skips_unchanged_per_destination".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("source");
+        let usb_path = test_dir.join("usb");
+        let nas_path = test_dir.join("nas");
+        file_generates_folder(&source_path, 100, n_files).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_per_destination".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            usb_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+        Executor::run(&plan).expect("First run failed");
+
+        // A destination added since gets every file, the first one none
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            nas_path.to_str().unwrap().to_string(),
+        )));
+        let res = Executor::run(&plan).expect("Second run failed");
+        assert_eq!(res.skipped, 0);
+        assert_eq!(res.destinations[0].written, 0);
+        assert_eq!(res.destinations[1].written, n_files);
+        let source_id = plan.sources[0].get_id();
+        assert!(nas_path.join(&source_id).join("file_0").is_file());
+
+        // A swapped drive no longer holds the files, they are written again
+        file_remove_all(&usb_path).unwrap();
+        let res = Executor::run(&plan).expect("Third run failed");
+        assert_eq!(res.skipped, 0);
+        assert_eq!(res.destinations[0].written, n_files);
+        assert_eq!(res.destinations[1].written, 0);
+        assert!(usb_path.join(&source_id).join("file_0").is_file());
+
+        let res = Executor::run(&plan).expect("Fourth run failed");
+        assert_eq!(res.skipped, n_files);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_restore() {
+        let test_dir = file_test_dir("test_restore".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        file_generates_folder(&source_path, 300, 6).unwrap();
+        // Scripts stay executable
+        std::fs::set_permissions(
+            source_path.join("file_0"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_restore".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 4).expect("Discovery failed");
+        Executor::run(&plan).expect("Run failed");
+
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        let res = Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        assert_eq!(res.destinations[0].written, 6);
+        assert_eq!(res.destinations[0].failed, 0);
+
+        let source_id = plan.sources[0].get_id();
+        for i in 0..6 {
+            let name = format!("file_{}", i);
+            assert_eq!(
+                std::fs::read(restore_path.join(&source_id).join(&name)).unwrap(),
+                std::fs::read(source_path.join(&name)).unwrap()
+            );
+            let restored =
+                FileMetadata::from_path(&restore_path.join(&source_id).join(&name)).unwrap();
+            let original = FileMetadata::from_path(&source_path.join(&name)).unwrap();
+            assert_eq!(restored.mode, original.mode);
+            assert_eq!(restored.mtime, original.mtime);
+            assert_eq!(restored.mtime_nsec, original.mtime_nsec);
+        }
+        let restored = FileMetadata::from_path(&restore_path.join(&source_id).join("file_0"));
+        assert_eq!(restored.unwrap().mode, 0o755);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_backup_and_restore_links() {
+        let test_dir = file_test_dir("test_backup_and_restore_links".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        file_generates_folder(&source_path.join("data"), 300, 2).unwrap();
+        std::os::unix::fs::symlink("data/file_0", source_path.join("latest")).unwrap();
+        std::fs::hard_link(
+            source_path.join("data").join("file_1"),
+            source_path.join("file_1_again"),
+        )
+        .unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_links".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+        let res = Executor::run(&plan).expect("Run failed");
+        // Two files, two links and the data directory
+        assert_eq!(res.destinations[0].written, 5);
+        assert_eq!(res.destinations[0].failed, 0);
+
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        let res = Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        assert_eq!(res.destinations[0].written, 5);
+
+        let restored = restore_path.join(plan.sources[0].get_id());
+        assert_eq!(
+            std::fs::read_link(restored.join("latest")).unwrap(),
+            std::path::PathBuf::from("data/file_0")
+        );
+        assert_eq!(
+            std::fs::read(restored.join("latest")).unwrap(),
+            std::fs::read(source_path.join("data").join("file_0")).unwrap()
+        );
+        let inode = |path: std::path::PathBuf| {
+            std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(path).unwrap())
+        };
+        assert_eq!(
+            inode(restored.join("file_1_again")),
+            inode(restored.join("data").join("file_1"))
+        );
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_run_worker_pool() {
+        let test_dir = file_test_dir("test_run_worker_pool".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("source");
+        file_generates_folder(&source_path.join("data"), 2000, 40).unwrap();
+        for idx in 0..10 {
+            std::fs::hard_link(
+                source_path.join("data").join(format!("file_{}", idx)),
+                source_path.join(format!("link_{}", idx)),
+            )
+            .unwrap();
+        }
+
+        let run = |name: &str, workers: WorkerConfig| {
+            let mut plan = crate::plan::BackupPlan::new(format!("plan__test_{}", name));
+            plan.workers = workers;
+            plan.add_source(Box::new(FileSystemDestination::new(
+                source_path.to_str().unwrap().to_string(),
+            )));
+            plan.add_destination(Box::new(FileSystemDestination::new(
+                test_dir.join(name).to_str().unwrap().to_string(),
+            )));
+            plan.save_plan(&test_dir.join(format!("{}_plan", name)))
+                .expect("Could not save plan");
+            Executor::discover(&mut plan, 7).expect("Discovery failed");
+            let res = Executor::run(&plan).expect("Run failed");
+            (res, test_dir.join(name).join(plan.sources[0].get_id()))
+        };
+        let (sequential, _) = run("sequential", WorkerConfig::new().with_threads(1));
+        // Less budget than a single file, so files wait on each other
+        let (pooled, pooled_path) = run(
+            "pooled",
+            WorkerConfig::new()
+                .with_threads(8)
+                .with_max_in_flight_bytes(1000)
+                .with_max_open_files(2),
+        );
+
+        assert_eq!(pooled.files, 51);
+        assert_eq!(pooled.files, sequential.files);
+        assert_eq!(pooled.batches, sequential.batches);
+        assert_eq!(pooled.skipped, sequential.skipped);
+        assert_eq!(pooled.destinations[0].written, 51);
+        assert_eq!(pooled.destinations[0].failed, 0);
+        let inode = |path: std::path::PathBuf| {
+            std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(path).unwrap())
+        };
+        for idx in 0..10 {
+            assert_eq!(
+                inode(pooled_path.join(format!("link_{}", idx))),
+                inode(pooled_path.join("data").join(format!("file_{}", idx)))
+            );
+        }
+        for idx in 0..40 {
+            let name = format!("file_{}", idx);
+            assert_eq!(
+                std::fs::read(pooled_path.join("data").join(&name)).unwrap(),
+                std::fs::read(source_path.join("data").join(&name)).unwrap()
+            );
+        }
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_discover_and_run() {
+        let test_dir = file_test_dir("test_discover_and_run".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_a = test_dir.join("source_a");
+        let source_b = test_dir.join("source_b");
+        let dest_path = test_dir.join("dest");
+        file_generates_folder(&source_a.join("data"), 500, 30).unwrap();
+        file_generates_folder(&source_b, 500, 12).unwrap();
+        std::fs::hard_link(
+            source_a.join("data").join("file_3"),
+            source_a.join("file_3_again"),
+        )
+        .unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_discover_and_run".to_string());
+        plan.workers = WorkerConfig::new().with_threads(4);
+        for source in [&source_a, &source_b] {
+            plan.add_source(Box::new(FileSystemDestination::new(
+                source.to_str().unwrap().to_string(),
+            )));
+        }
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir.join("plan"))
+            .expect("Could not save plan");
+        let res =
+            Executor::discover_and_run(&mut plan, BatchLimits::new(5)).expect("Pipeline failed");
+        // 30 files, a link and the data directory, then 12 files
+        assert_eq!(res.files, 44);
+        assert_eq!(res.batches, 10);
+        assert_eq!(res.destinations[0].written, 44);
+        assert_eq!(res.destinations[0].failed, 0);
+        let dest_a = dest_path.join(plan.sources[0].get_id());
+        assert_eq!(
+            std::fs::read(dest_a.join("file_3_again")).unwrap(),
+            std::fs::read(source_a.join("data").join("file_3")).unwrap()
+        );
+        assert!(dest_path
+            .join(plan.sources[1].get_id())
+            .join("file_11")
+            .exists());
+
+        // Saved with its batches, in source order, for a later run to resume
+        assert_eq!(plan.batches.len(), 10);
+        assert!(plan.batches[0].contains(&plan.sources[0].get_id()));
+        assert!(plan.batches[9].contains(&plan.sources[1].get_id()));
+        assert!(!plan.get_checkpoint_path().exists());
+        let saved =
+            crate::plan::BackupPlan::load_saved(plan.path.join("plan__test_discover_and_run.yaml"))
+                .unwrap();
+        assert_eq!(saved.batches, plan.batches);
+        for batch in &plan.batches {
+            let batch_path = plan
+                .path
+                .join(".vbatches/")
+                .join(batch.clone() + ".vbak_batch");
+            let batch = FileBatch::load_batch(batch_path).unwrap();
+            assert!(batch
+                .files
+                .iter()
+                .all(|file| file.state == FileState::Stored));
+        }
+
+        // Nothing changed since
+        let res = Executor::run(&plan).expect("Run failed");
+        assert_eq!(res.skipped, 42);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    /// Source recording, each time it is listed, how many batches the saved
+    /// plan and its checkpoint hold
+    struct RecordingSource {
+        inner: FileSystemDestination,
+        plan_path: PathBuf,
+        checkpoint_path: PathBuf,
+        saved: Arc<Mutex<Vec<(usize, usize)>>>,
+    }
+
+    impl Backend for RecordingSource {
+        fn get_name(&self) -> String {
+            self.inner.get_name()
+        }
+
+        fn get_config(&self) -> BackendConfig {
+            self.inner.get_config()
+        }
+    }
+
+    impl Source for RecordingSource {
+        fn list_files_next(&self, count: u64) -> Result<Vec<VictoryFile>, String> {
+            let plan = crate::plan::BackupPlan::load_saved(self.plan_path.clone())?;
+            let checkpoint = CheckpointState::load_state(&self.checkpoint_path)
+                .map(|state| state.batches.len())
+                .unwrap_or(0);
+            self.saved
+                .lock()
+                .unwrap()
+                .push((plan.batches.len(), checkpoint));
+            self.inner.list_files_next(count)
+        }
+
+        fn read_file(&self, file: &mut VictoryFile) -> Result<(), String> {
+            self.inner.read_file(file)
+        }
+
+        fn open_file(&self, file: &mut VictoryFile) -> Result<Box<dyn Read + '_>, String> {
+            self.inner.open_file(file)
+        }
+    }
+
+    #[test]
+    fn test_discover_and_run_saves_batches_as_cut() {
+        let test_dir = file_test_dir("test_discover_and_run_saves_batches".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("source");
+        file_generates_folder(&source_path, 100, 12).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_saves_batches".to_string());
+        plan.workers = WorkerConfig::new().with_threads(2);
+        let plan_dir = test_dir.join("plan");
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        plan.add_source(Box::new(RecordingSource {
+            inner: FileSystemDestination::new(source_path),
+            plan_path: plan_dir.join("plan__test_saves_batches.yaml"),
+            checkpoint_path: plan_dir.join("plan__test_saves_batches.vcheckpoint"),
+            saved: saved.clone(),
+        }));
+        plan.add_destination(Box::new(FileSystemDestination::new(test_dir.join("dest"))));
+        plan.save_plan(&plan_dir).expect("Could not save plan");
+
+        let res =
+            Executor::discover_and_run(&mut plan, BatchLimits::new(5)).expect("Pipeline failed");
+        assert_eq!(res.batches, 3);
+        assert_eq!(res.destinations[0].written, 12);
+        // Every batch was in both the plan and the checkpoint before the next
+        // one was listed
+        assert_eq!(*saved.lock().unwrap(), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_backup_and_restore_directories() {
+        let test_dir = file_test_dir("test_backup_and_restore_directories".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        file_generates_folder(&source_path.join("docs"), 100, 3).unwrap();
+        std::fs::create_dir_all(source_path.join("empty").join("nested")).unwrap();
+        let mut old = FileMetadata::from_path(&source_path.join("docs")).unwrap();
+        old.mtime = 1_000_000_000;
+        old.apply(&std::fs::File::open(source_path.join("docs")).unwrap())
+            .unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_directories".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        // Small batches, so children are written in later batches than their directory
+        Executor::discover(&mut plan, 2).expect("Discovery failed");
+        Executor::run(&plan).expect("Run failed");
+
+        let source_id = plan.sources[0].get_id();
+        let backed_up = dest_path.join(&source_id);
+        assert!(backed_up.join("empty").join("nested").is_dir());
+        let docs = FileMetadata::from_path(&backed_up.join("docs")).unwrap();
+        assert_eq!(docs.mtime, 1_000_000_000);
+
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        let restored = restore_path.join(&source_id);
+        assert!(restored.join("empty").join("nested").is_dir());
+        let docs = FileMetadata::from_path(&restored.join("docs")).unwrap();
+        assert_eq!(docs.mtime, 1_000_000_000);
+        assert_eq!(docs.mode, old.mode);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_backup_and_restore_non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let test_dir = file_test_dir("test_backup_and_restore_non_utf8_names".to_string());
+        file_remove_all(&test_dir).expect("Could not clear test dir");
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        let latin1 = OsStr::from_bytes(b"caf\xe9.txt");
+        std::fs::create_dir_all(&source_path).unwrap();
+        std::fs::write(source_path.join(latin1), b"menu").unwrap();
+        std::os::unix::fs::symlink(latin1, source_path.join(OsStr::from_bytes(b"l\xe9nk")))
+            .unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_non_utf8".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 4).expect("Discovery failed");
+        let batch_path = plan
+            .path
+            .join(".vbatches/")
+            .join(plan.batches[0].clone() + ".vbak_batch");
+        let batch = FileBatch::load_batch(batch_path).unwrap();
+        assert_eq!(batch.files[0].name, "caf\\xE9.txt");
+        Executor::run(&plan).expect("Run failed");
+
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        let res = Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        assert_eq!(res.destinations[0].written, 2);
+        let restored = restore_path.join(plan.sources[0].get_id());
+        assert_eq!(std::fs::read(restored.join(latin1)).unwrap(), b"menu");
+        assert_eq!(
+            std::fs::read_link(restored.join(OsStr::from_bytes(b"l\xe9nk"))).unwrap(),
+            latin1
+        );
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_run_conflict_policies() {
+        for (policy, written, skipped) in [
+            (ConflictPolicy::Overwrite, 2, 0),
+            (ConflictPolicy::Skip, 1, 1),
+            (ConflictPolicy::KeepBoth, 2, 0),
+            (ConflictPolicy::Fail, 1, 0),
+        ] {
+            let test_dir = file_test_dir(format!("test_run_conflict_policies_{:?}", policy));
+            let source_path = test_dir.join("source");
+            let dest_path = test_dir.join("dest");
+            file_generates_folder(&source_path, 100, 2).unwrap();
+
+            let mut plan = crate::plan::BackupPlan::new("plan__test_conflicts".to_string());
+            plan.add_source(Box::new(FileSystemDestination::new(
+                source_path.to_str().unwrap().to_string(),
+            )));
+            plan.add_destination(Box::new(
+                FileSystemDestination::new(dest_path.to_str().unwrap().to_string())
+                    .with_conflict_policy(policy),
+            ));
+            plan.save_plan(&test_dir).expect("Could not save plan");
+            // Left on the drive by an older backup
+            let existing = dest_path.join(plan.sources[0].get_id()).join("file_0");
+            std::fs::create_dir_all(existing.parent().unwrap()).unwrap();
+            std::fs::write(&existing, b"older backup").unwrap();
+
+            Executor::discover(&mut plan, 10).expect("Discovery failed");
+            let res = Executor::run(&plan).expect("Run failed");
+            let dest = &res.destinations[0];
+            assert_eq!(dest.written, written, "{:?}", policy);
+            assert_eq!(dest.skipped, skipped, "{:?}", policy);
+            assert_eq!(dest.failed, 2 - written - skipped, "{:?}", policy);
+
+            let contents = std::fs::read(&existing).unwrap();
+            match policy {
+                ConflictPolicy::Overwrite => {
+                    assert_eq!(dest.conflicts[0].1, WriteDecision::Overwrite);
+                    assert_eq!(contents, std::fs::read(source_path.join("file_0")).unwrap());
+                }
+                ConflictPolicy::KeepBoth => {
+                    assert_eq!(contents, b"older backup");
+                    let kept = existing.with_file_name("file_0 (1)");
+                    assert_eq!(
+                        dest.conflicts[0].1,
+                        WriteDecision::KeepBoth(std::path::PathBuf::from("file_0 (1)"))
+                    );
+                    assert_eq!(
+                        std::fs::read(kept).unwrap(),
+                        std::fs::read(source_path.join("file_0")).unwrap()
+                    );
+
+                    // Restored from the copy it was kept as, not the older backup
+                    let restore_path = test_dir.join("restore");
+                    let backup =
+                        FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+                    let target =
+                        FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+                    Executor::restore(&plan, &backup, &target).expect("Restore failed");
+                    let restored = restore_path.join(plan.sources[0].get_id());
+                    assert_eq!(
+                        std::fs::read(restored.join("file_0")).unwrap(),
+                        std::fs::read(source_path.join("file_0")).unwrap()
+                    );
+                }
+                ConflictPolicy::Fail => {
+                    assert!(matches!(dest.conflicts[0].1, WriteDecision::Fail(_)));
+                    assert_eq!(contents, b"older backup");
+                }
+                _ => assert_eq!(contents, b"older backup"),
+            }
+
+            file_remove_all(&test_dir).expect("Could not remove test dir");
+        }
+    }
+
+    #[test]
+    fn test_encrypted_backup_and_restore() {
+        let test_dir = file_test_dir("test_encrypted_backup".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        file_generates_folder(&source_path, 1000, 5).unwrap();
+
+        std::env::set_var(
+            "VICTORY_TEST_ENCRYPTED_BACKUP",
+            "correct horse battery staple",
+        );
+        let mut plan = crate::plan::BackupPlan::new("plan__test_encrypted_backup".to_string());
+        plan.add_middleware(MiddlewareConfig::Encrypt(EncryptionConfig {
+            passphrase_env: Some("VICTORY_TEST_ENCRYPTED_BACKUP".to_string()),
+            key_file: None,
+        }));
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+        let res = Executor::run(&plan).expect("Run failed");
+        assert_eq!(res.destinations[0].written, 5);
+
+        // Nothing readable ends up at the destination
+        let source_id = plan.sources[0].get_id();
+        let stored = std::fs::read(dest_path.join(&source_id).join("file_0")).unwrap();
+        assert_eq!(&stored[..4], ENCRYPT_MAGIC);
+        assert_ne!(stored, std::fs::read(source_path.join("file_0")).unwrap());
+
+        // Tamper with one backed up file
+        let mut tampered = std::fs::read(dest_path.join(&source_id).join("file_2")).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        std::fs::write(dest_path.join(&source_id).join("file_2"), tampered).unwrap();
+        // and put another one in place of a second
+        std::fs::copy(
+            dest_path.join(&source_id).join("file_3"),
+            dest_path.join(&source_id).join("file_4"),
+        )
+        .unwrap();
+
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        let res = Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        assert_eq!(res.destinations[0].written, 3);
+        assert_eq!(res.destinations[0].failed, 2);
+        // Which files failed and why is reported
+        let errors = &res.destinations[0].errors;
+        assert_eq!(
+            errors.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            vec![
+                &PathBuf::from(&source_id).join("file_2"),
+                &PathBuf::from(&source_id).join("file_4")
+            ]
+        );
+        assert!(errors
+            .iter()
+            .all(|(_, err)| err.contains("Decryption Error")));
+
+        assert!(!restore_path.join(&source_id).join("file_2").exists());
+        assert!(!restore_path.join(&source_id).join("file_4").exists());
+        assert_eq!(
+            std::fs::read(restore_path.join(&source_id).join("file_0")).unwrap(),
+            std::fs::read(source_path.join("file_0")).unwrap()
+        );
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_compressed_backup_and_restore() {
+        let test_dir = file_test_dir("test_compressed_backup".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        std::fs::create_dir_all(&source_path).unwrap();
+        let text = "all work and no play makes jack a dull boy\n".repeat(500);
+        for i in 0..3 {
+            std::fs::write(source_path.join(format!("notes_{}.txt", i)), &text).unwrap();
+        }
+
+        std::env::set_var(
+            "VICTORY_TEST_COMPRESSED_BACKUP",
+            "correct horse battery staple",
+        );
+        let mut plan = crate::plan::BackupPlan::new("plan__test_compressed_backup".to_string());
+        plan.add_middleware(MiddlewareConfig::Compress(CompressionConfig::new(
+            CompressionAlgorithm::Zstd,
+        )));
+        plan.add_middleware(MiddlewareConfig::Encrypt(EncryptionConfig {
+            passphrase_env: Some("VICTORY_TEST_COMPRESSED_BACKUP".to_string()),
+            key_file: None,
+        }));
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+        let res = Executor::run(&plan).expect("Run failed");
+        assert_eq!(res.destinations[0].written, 3);
+        assert!(res.bytes_saved > 2 * text.len() as i64);
+
+        // Compressed before encryption, so the stored file is still small
+        let source_id = plan.sources[0].get_id();
+        let stored = std::fs::read(dest_path.join(&source_id).join("notes_0.txt")).unwrap();
+        assert_eq!(&stored[..4], ENCRYPT_MAGIC);
+        assert!(stored.len() < text.len() / 4);
+
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        let res = Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        assert_eq!(res.destinations[0].written, 3);
+        assert_eq!(res.destinations[0].failed, 0);
+        assert_eq!(
+            std::fs::read_to_string(restore_path.join(&source_id).join("notes_1.txt")).unwrap(),
+            text
+        );
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_restore_after_enabling_compression() {
+        let test_dir = file_test_dir("test_restore_after_compression".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        let restore_path = test_dir.join("restore");
+        std::fs::create_dir_all(&source_path).unwrap();
+        let text = "all work and no play makes jack a dull boy\n".repeat(500);
+        std::fs::write(source_path.join("notes.txt"), &text).unwrap();
+        std::fs::write(source_path.join("short.txt"), "VZ").unwrap();
+
+        let mut plan =
+            crate::plan::BackupPlan::new("plan__test_restore_after_compression".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+        let res = Executor::run(&plan).expect("Run failed");
+        assert_eq!(res.destinations[0].written, 2);
+
+        // Stored without compression, restored after it was enabled
+        plan.add_middleware(MiddlewareConfig::Compress(CompressionConfig::new(
+            CompressionAlgorithm::Zstd,
+        )));
+        let source_id = plan.sources[0].get_id();
+        let backup = FileSystemDestination::new(dest_path.to_str().unwrap().to_string());
+        let target = FileSystemDestination::new(restore_path.to_str().unwrap().to_string());
+        let res = Executor::restore(&plan, &backup, &target).expect("Restore failed");
+        assert_eq!(res.destinations[0].written, 2);
+        assert_eq!(res.destinations[0].failed, 0);
+        assert_eq!(
+            std::fs::read_to_string(restore_path.join(&source_id).join("notes.txt")).unwrap(),
+            text
+        );
+        assert_eq!(
+            std::fs::read_to_string(restore_path.join(&source_id).join("short.txt")).unwrap(),
+            "VZ"
+        );
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_run_resumes_from_checkpoint() {
+        let n_files = 20;
+        let test_dir = file_test_dir("test_run_resumes".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        file_generates_folder(&source_path, 100, n_files).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_run_resumes".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 5).expect("Discovery failed");
+        assert_eq!(plan.batches.len(), 4);
+
+        // Interrupt the run at the third batch
+        let batch_path = |idx: usize| {
+            plan.path
+                .join(".vbatches/")
+                .join(plan.batches[idx].clone() + ".vbak_batch")
+        };
+        let moved_path = test_dir.join("moved_batch");
+        std::fs::rename(batch_path(2), &moved_path).unwrap();
+        assert!(Executor::run(&plan).is_err());
+        assert!(plan.get_checkpoint_path().exists());
+        // Hashes recorded so far are in the hash log, not in the checkpoint
+        let state =
+            crate::middleware::checkpoint::CheckpointState::load_state(&plan.get_checkpoint_path())
+                .unwrap();
+        assert_eq!(state.middleware["hash"].as_u64(), Some(10));
+
+        // Files written by the interrupted run are not written again
+        let source_id = plan.sources[0].get_id();
+        let done = FileBatch::load_batch(batch_path(0)).unwrap();
+        let done_path = dest_path.join(&source_id).join(&done.files[0].path);
+        std::fs::write(&done_path, b"left alone").unwrap();
+
+        std::fs::rename(&moved_path, batch_path(2)).unwrap();
+        let res = Executor::run(&plan).expect("Resumed run failed");
+        assert_eq!(res.batches, 2);
+        assert_eq!(res.destinations[0].written, 10);
+        assert_eq!(std::fs::read(&done_path).unwrap(), b"left alone");
+        assert!(!plan.get_checkpoint_path().exists());
+
+        // Hashes recorded before the interruption are kept for the next run
+        let index = HashIndex::load_index(plan.get_hash_index_path()).unwrap();
+        assert_eq!(index.get_length(), n_files);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    #[test]
+    fn test_process_batch_skips_checkpointed_files() {
+        let test_dir = file_test_dir("test_process_batch_checkpoint".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        file_generates_folder(&source_path, 100, 4).unwrap();
+
+        let mut plan =
+            crate::plan::BackupPlan::new("plan__test_process_batch_checkpoint".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+        let batch_path = plan
+            .path
+            .join(".vbatches/")
+            .join(plan.batches[0].clone() + ".vbak_batch");
+
+        // A run that was killed after finishing the first file of the batch
+        let chain = MiddlewareChain::from_plan(&plan).expect("Could not build middleware");
+        let checkpoint = Checkpoint::load(plan.get_checkpoint_path(), &plan.name, &plan.batches)
+            .expect("Could not load checkpoint");
+        let mut first = FileBatch::load_batch(batch_path.clone()).unwrap().files[0].clone();
+        first.state = FileState::Stored;
+        first.hash = "previous".to_string();
+        checkpoint
+            .record_file(&plan.batches[0], &first, &chain)
+            .unwrap();
+
+        let res = Executor::process_batch(&plan, &batch_path, &chain, &checkpoint)
+            .expect("Process batch failed");
+        assert_eq!(res.files, 3);
+        assert_eq!(res.destinations[0].written, 3);
+        assert!(!dest_path
+            .join(plan.sources[0].get_id())
+            .join(&first.path)
+            .exists());
+
+        let batch = FileBatch::load_batch(batch_path).unwrap();
+        assert_eq!(batch.files[0].hash, "previous");
+        assert!(batch
+            .files
+            .iter()
+            .all(|file| file.state == FileState::Stored));
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
+    }
+
+    /// Fails every file whose name ends with an odd digit
+    struct OddFilesFail;
+
+    impl Middleware for OddFilesFail {
+        fn get_name(&self) -> String {
+            "odd_files".to_string()
+        }
+
+        fn on_read(&self, file: &mut crate::file::VictoryFile) -> MiddlewareAction {
+            match file.name.ends_with(['1', '3', '5', '7', '9']) {
+                true => MiddlewareAction::Error("odd file".to_string()),
+                false => MiddlewareAction::Continue,
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_with_custom_middleware() {
+        let test_dir = file_test_dir("test_run_custom_middleware".to_string());
+        let source_path = test_dir.join("source");
+        let dest_path = test_dir.join("dest");
+        file_generates_folder(&source_path, 100, 6).unwrap();
+
+        let mut plan = crate::plan::BackupPlan::new("plan__test_run_custom_middleware".to_string());
+        plan.add_source(Box::new(FileSystemDestination::new(
+            source_path.to_str().unwrap().to_string(),
+        )));
+        plan.add_destination(Box::new(FileSystemDestination::new(
+            dest_path.to_str().unwrap().to_string(),
+        )));
+        plan.save_plan(&test_dir).expect("Could not save plan");
+        Executor::discover(&mut plan, 10).expect("Discovery failed");
+
+        let mut chain = MiddlewareChain::from_plan(&plan).expect("Could not build middleware");
+        chain.push(Box::new(OddFilesFail));
+        let res = Executor::run_with_chain(&plan, &chain).expect("Run failed");
+        assert_eq!(res.destinations[0].written, 3);
+        assert_eq!(res.destinations[0].failed, 3);
+
+        let batch_path = plan
+            .path
+            .join(".vbatches/")
+            .join(plan.batches[0].clone() + ".vbak_batch");
+        let batch = FileBatch::load_batch(batch_path).unwrap();
+        let failed = batch
+            .files
+            .iter()
+            .find(|file| file.name == "file_1")
+            .unwrap();
+        assert_eq!(failed.state, FileState::Error);
+        assert_eq!(failed.reason, Some("odd_files: odd file".to_string()));
+        assert!(!dest_path
+            .join(plan.sources[0].get_id())
+            .join("file_1")
+            .exists());
+
+        // Failed files are not recorded, so the next run tries them again
+        let index = HashIndex::load_index(plan.get_hash_index_path()).unwrap();
+        assert_eq!(index.get_length(), 3);
+
+        file_remove_all(&test_dir).expect("Could not remove test dir");
     }
 }
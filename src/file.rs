@@ -1,8 +1,10 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::debug;
 use serde::{Deserialize, Serialize};
 
+use crate::{metadata::FileMetadata, utils::os_path};
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum FileState {
     Discovered,
@@ -12,39 +14,129 @@ pub enum FileState {
     Error,
     Skipped,
 }
+/// What a discovered entry is, and so how it is stored at a destination
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum FileKind {
+    /// Regular file, its contents are read and written
+    #[default]
+    File,
+    /// Symbolic link, recreated pointing to the stored target
+    Symlink(#[serde(with = "os_path")] PathBuf),
+    /// Another name of a file listed earlier in the same source (same inode),
+    /// recreated as a hardlink to that file's path
+    Hardlink(#[serde(with = "os_path")] PathBuf),
+    /// Directory, created before its children. Its metadata is applied once
+    /// every child was written, so writing them does not change its times.
+    Directory,
+}
+
+impl FileKind {
+    /// True for entries stored as links, without any contents
+    pub fn is_link(&self) -> bool {
+        matches!(self, FileKind::Symlink(_) | FileKind::Hardlink(_))
+    }
+
+    /// True for entries whose contents are read and written
+    pub fn has_contents(&self) -> bool {
+        *self == FileKind::File
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct VictoryFile {
+    /// Readable file name for logs, see [`os_path::escaped`]
     pub name: String,
+    /// Path relative to its source, kept as the exact bytes the OS listed
+    #[serde(with = "os_path")]
     pub path: PathBuf,
     pub extension: String,
     pub state: FileState,
+    /// Whole contents, only loaded by `Source::read_file`. The executor streams
+    /// contents instead, see `Source::open_file`.
     pub contents: Option<Vec<u8>>,
     pub size: usize,
     pub hash: String,
+    /// Id of the source the file was discovered in, see `Backend::get_id`
+    #[serde(default)]
+    pub source: String,
+    /// Why the file ended up `Skipped` or in `Error`, set by middleware stages
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Permissions, ownership and times captured at discovery, applied when
+    /// the file is written to a destination or restored
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    #[serde(default)]
+    pub kind: FileKind,
+    /// Other paths destinations stored the file under, as they kept an
+    /// existing file at its own path (see `ConflictPolicy::KeepBoth`)
+    #[serde(default)]
+    pub kept_as: Vec<KeptCopy>,
+}
+
+/// Where a destination stored a file instead of its own path
+/// # Fields:
+/// - dest: Id of the destination, see `Backend::get_id`
+/// - path: Path the file was stored under, relative to its source's subtree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeptCopy {
+    pub dest: String,
+    #[serde(with = "os_path")]
+    pub path: PathBuf,
 }
 
 impl VictoryFile {
-    pub fn new(path: &PathBuf) -> VictoryFile {
-        let name = path
-            .file_name()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap()
-            .to_string();
-        let extension = path
-            .extension()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap()
-            .to_string();
+    pub fn new(path: &Path) -> VictoryFile {
+        let name = os_path::escaped(Path::new(path.file_name().unwrap_or_default()));
+        let extension = os_path::escaped(Path::new(path.extension().unwrap_or_default()));
         VictoryFile {
-            name: name,
-            path: path.clone(),
-            extension: extension,
+            name,
+            path: path.to_path_buf(),
+            extension,
             state: FileState::Discovered,
             contents: None,
             size: 0,
             hash: "".to_string(),
+            source: "".to_string(),
+            reason: None,
+            metadata: None,
+            kind: FileKind::File,
+            kept_as: Vec::new(),
+        }
+    }
+
+    /// Copy of the file to be stored under another path of its source
+    pub fn with_path(&self, path: &Path) -> VictoryFile {
+        let mut moved = self.clone();
+        moved.path = path.to_path_buf();
+        moved.name = VictoryFile::new(path).name;
+        moved
+    }
+
+    /// Path the file is stored at relative to a destination root,
+    /// keeping every source in its own subtree
+    pub fn get_dest_path(&self) -> PathBuf {
+        Path::new(&self.source).join(&self.path)
+    }
+
+    /// Path the file is stored at relative to a destination's root, following
+    /// the other path it was kept as there, if any
+    pub fn get_stored_path(&self, dest: &str) -> PathBuf {
+        match self.kept_as.iter().find(|kept| kept.dest == dest) {
+            Some(kept) => Path::new(&self.source).join(&kept.path),
+            None => self.get_dest_path(),
+        }
+    }
+
+    /// Remembers the other path a destination stored the file under, or
+    /// forgets it once the destination stores it under its own path again
+    pub fn set_kept_as(&mut self, dest: &str, path: Option<&Path>) {
+        self.kept_as.retain(|kept| kept.dest != dest);
+        if let Some(path) = path {
+            self.kept_as.push(KeptCopy {
+                dest: dest.to_string(),
+                path: path.to_path_buf(),
+            });
         }
     }
 
@@ -52,6 +144,7 @@ impl VictoryFile {
         self.size = contents.len();
         self.contents = Some(contents);
         self.state = FileState::Read;
+        self.reason = None;
         debug!(
             "Loaded contents for file: {:?} with size {:.3}MB",
             self.path,
@@ -71,4 +164,16 @@ impl VictoryFile {
         self.contents = None;
         self.state = FileState::Stored;
     }
+
+    pub fn mark_skipped(&mut self, reason: String) {
+        self.contents = None;
+        self.state = FileState::Skipped;
+        self.reason = Some(reason);
+    }
+
+    pub fn mark_error(&mut self, reason: String) {
+        self.contents = None;
+        self.state = FileState::Error;
+        self.reason = Some(reason);
+    }
 }
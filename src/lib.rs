@@ -1,6 +1,10 @@
 pub mod destination;
 pub mod plan;
 pub mod file;
+#[cfg(unix)]
+pub mod metadata;
 pub mod batch;
 pub mod executor;
-pub mod utils;
\ No newline at end of file
+pub mod middleware;
+pub mod utils;
+pub mod workers;
\ No newline at end of file
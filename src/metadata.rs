@@ -0,0 +1,388 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{self, FileTimes},
+    io::Error,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{fchown, lchown, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// POSIX metadata of a file, captured at discovery and stored in its batch
+/// # Fields:
+/// - mode: Permission bits, including setuid, setgid and sticky
+/// - uid / gid: Numeric owner and group
+/// - mtime / atime: Modification and access times, in seconds and nanoseconds since the epoch
+/// - xattrs: Extended attributes by name, e.g. `security.capability`, `security.selinux`
+///   or POSIX ACLs (`system.posix_acl_access`). Only captured by sources that ask for them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub atime: i64,
+    pub atime_nsec: u32,
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl FileMetadata {
+    pub fn from_fs(metadata: &fs::Metadata) -> FileMetadata {
+        FileMetadata {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            atime: metadata.atime(),
+            atime_nsec: metadata.atime_nsec() as u32,
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    /// Reads the metadata of a file on disk, without following symlinks
+    pub fn from_path(path: &Path) -> Result<FileMetadata, String> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(FileMetadata::from_fs(&metadata)),
+            Err(err) => Err(format!("metadata Error: {:?}", err)),
+        }
+    }
+
+    pub fn get_mtime(&self) -> SystemTime {
+        Self::to_system_time(self.mtime, self.mtime_nsec)
+    }
+
+    pub fn get_atime(&self) -> SystemTime {
+        Self::to_system_time(self.atime, self.atime_nsec)
+    }
+
+    fn to_system_time(secs: i64, nsec: u32) -> SystemTime {
+        let since_epoch = Duration::new(secs.unsigned_abs(), 0);
+        let time = match secs >= 0 {
+            true => UNIX_EPOCH + since_epoch,
+            false => UNIX_EPOCH - since_epoch,
+        };
+        time + Duration::from_nanos(nsec as u64)
+    }
+
+    /// Applies the metadata to a freshly written file
+    ///
+    /// Ownership can only be given away by root, so failing to change it is
+    /// logged and the file keeps the writing user as owner. Times are set
+    /// last, once nothing writes to the file anymore.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Extended attributes the file could not hold, e.g. because
+    ///   its filesystem does not support them or they need privileges
+    pub fn apply(&self, file: &fs::File) -> Result<Vec<String>, String> {
+        // Ownership first, changing it clears the setuid and setgid bits
+        let owned = match file.metadata() {
+            Ok(current) => current.uid() == self.uid && current.gid() == self.gid,
+            Err(_) => false,
+        };
+        if !owned {
+            match fchown(file, Some(self.uid), Some(self.gid)) {
+                Ok(_) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                    debug!("Metadata: Not allowed to chown to {}:{}", self.uid, self.gid)
+                }
+                Err(err) => warn!("Metadata: chown Error: {:?}", err),
+            }
+        }
+
+        if let Err(err) = file.set_permissions(fs::Permissions::from_mode(self.mode)) {
+            return Err(format!("chmod Error: {:?}", err));
+        }
+
+        // After the mode, so ACLs keep their mask, and after ownership,
+        // which clears file capabilities
+        let mut dropped = Vec::new();
+        for (name, value) in &self.xattrs {
+            if let Err(err) = set_xattr(file, name, value) {
+                debug!("Metadata: setxattr {:?} Error: {:?}", name, err);
+                dropped.push(name.clone());
+            }
+        }
+
+        let times = FileTimes::new()
+            .set_accessed(self.get_atime())
+            .set_modified(self.get_mtime());
+        match file.set_times(times) {
+            Ok(_) => Ok(dropped),
+            Err(err) => Err(format!("set_times Error: {:?}", err)),
+        }
+    }
+
+    /// Applies ownership and times to a symlink itself, not its target.
+    /// Links have no permissions of their own.
+    pub fn apply_to_link(&self, path: &Path) -> Result<(), String> {
+        match lchown(path, Some(self.uid), Some(self.gid)) {
+            Ok(_) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                debug!("Metadata: Not allowed to chown to {}:{}", self.uid, self.gid)
+            }
+            Err(err) => warn!("Metadata: lchown Error: {:?}", err),
+        }
+
+        let c_path = to_c_path(path)?;
+        let times = [
+            libc::timespec {
+                tv_sec: self.atime as libc::time_t,
+                tv_nsec: self.atime_nsec as libc::c_long,
+            },
+            libc::timespec {
+                tv_sec: self.mtime as libc::time_t,
+                tv_nsec: self.mtime_nsec as libc::c_long,
+            },
+        ];
+        let res = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        match res {
+            0 => Ok(()),
+            _ => Err(format!("utimensat Error: {:?}", Error::last_os_error())),
+        }
+    }
+}
+
+fn to_c_path(path: &Path) -> Result<CString, String> {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => Ok(path),
+        Err(err) => Err(format!("path Error: {:?}", err)),
+    }
+}
+
+/// Reads every extended attribute of a file, without following symlinks.
+/// Filesystems without extended attributes have none.
+pub fn read_xattrs(path: &Path) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let c_path = to_c_path(path)?;
+    let mut xattrs = BTreeMap::new();
+    let names = match list_xattrs(&c_path) {
+        Ok(names) => names,
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(xattrs),
+        Err(err) => return Err(format!("listxattr Error: {:?} with path {:?}", err, path)),
+    };
+
+    for name in names.split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name).unwrap();
+        let value = match get_xattr(&c_path, &c_name) {
+            Ok(Some(value)) => value,
+            // Removed since it was listed
+            Ok(None) => continue,
+            Err(err) => return Err(format!("getxattr Error: {:?} with path {:?}", err, path)),
+        };
+        match std::str::from_utf8(name) {
+            Ok(name) => {
+                xattrs.insert(name.to_string(), value);
+            }
+            Err(_) => warn!("Metadata: Skipping non UTF-8 xattr {:?} of {:?}", name, path),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Calls a `listxattr`/`getxattr` style function, first asking for the size
+/// it needs and retrying if the attribute grew in between
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_xattr_buf(
+    call: impl Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+) -> Result<Vec<u8>, Error> {
+    loop {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let read = call(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let err = Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+/// Names of the extended attributes of a file, each followed by a nul byte
+#[cfg(target_os = "linux")]
+fn list_xattrs(path: &CString) -> Result<Vec<u8>, Error> {
+    read_xattr_buf(|buf, len| unsafe {
+        libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, len)
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn list_xattrs(path: &CString) -> Result<Vec<u8>, Error> {
+    read_xattr_buf(|buf, len| unsafe {
+        libc::listxattr(
+            path.as_ptr(),
+            buf as *mut libc::c_char,
+            len,
+            libc::XATTR_NOFOLLOW,
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn list_xattrs(_path: &CString) -> Result<Vec<u8>, Error> {
+    Err(Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// Value of an extended attribute, `None` if the file does not have it
+#[cfg(target_os = "linux")]
+fn get_xattr(path: &CString, name: &CString) -> Result<Option<Vec<u8>>, Error> {
+    match read_xattr_buf(|buf, len| unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, len)
+    }) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn get_xattr(path: &CString, name: &CString) -> Result<Option<Vec<u8>>, Error> {
+    match read_xattr_buf(|buf, len| unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf,
+            len,
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    }) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.raw_os_error() == Some(libc::ENOATTR) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn get_xattr(_path: &CString, _name: &CString) -> Result<Option<Vec<u8>>, Error> {
+    Err(Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+fn set_xattr(file: &fs::File, name: &str, value: &[u8]) -> Result<(), Error> {
+    let c_name = match CString::new(name) {
+        Ok(name) => name,
+        Err(err) => return Err(Error::new(std::io::ErrorKind::InvalidInput, err)),
+    };
+    fset_xattr(file, &c_name, value)
+}
+
+#[cfg(target_os = "linux")]
+fn fset_xattr(file: &fs::File, name: &CString, value: &[u8]) -> Result<(), Error> {
+    let res = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn fset_xattr(file: &fs::File, name: &CString, value: &[u8]) -> Result<(), Error> {
+    // Position is only used by resource forks
+    let res = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+            0,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn fset_xattr(_file: &fs::File, _name: &CString, _value: &[u8]) -> Result<(), Error> {
+    Err(Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+#[cfg(test)]
+mod metadata_tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("script.sh");
+        fs::write(&path, b"#!/bin/sh\n").unwrap();
+
+        let mut metadata = FileMetadata::from_path(&path).unwrap();
+        metadata.mode = 0o750;
+        metadata.mtime = 1_000_000_000;
+        metadata.mtime_nsec = 500;
+        metadata.atime = -10;
+        metadata.atime_nsec = 0;
+        metadata
+            .apply(&fs::File::options().write(true).open(&path).unwrap())
+            .unwrap();
+
+        let applied = FileMetadata::from_path(&path).unwrap();
+        assert_eq!(applied, metadata);
+        assert!(read_xattrs(&path).unwrap().is_empty());
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o750
+        );
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("labelled");
+        fs::write(&path, b"data").unwrap();
+
+        let mut metadata = FileMetadata::from_path(&path).unwrap();
+        metadata
+            .xattrs
+            .insert("user.victory.test".to_string(), b"\x00label".to_vec());
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        let dropped = metadata.apply(&file).unwrap();
+        if dropped.is_empty() {
+            let xattrs = read_xattrs(&path).unwrap();
+            assert_eq!(xattrs.get("user.victory.test").unwrap(), b"\x00label");
+        } else {
+            // The filesystem holding the temp dir has no user xattrs
+            assert_eq!(dropped, vec!["user.victory.test"]);
+        }
+
+        // Attributes no filesystem accepts are reported, not fatal
+        metadata.xattrs.clear();
+        metadata
+            .xattrs
+            .insert("bogus.victory.test".to_string(), b"value".to_vec());
+        assert_eq!(metadata.apply(&file).unwrap(), vec!["bogus.victory.test"]);
+    }
+}
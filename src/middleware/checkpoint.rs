@@ -0,0 +1,364 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::{FileState, VictoryFile},
+    utils::{file_utils::file_write_atomic, os_path},
+};
+
+use super::MiddlewareChain;
+
+/// Files finished between two saves of the checkpoint while a batch is processed
+pub const CHECKPOINT_INTERVAL: usize = 100;
+
+/// Savable outcome of a file finished in a batch being processed
+/// # Fields:
+/// - state: State the file ended up in (`Stored`, `Skipped` or `Error`)
+/// - hash: Hash of the contents that were read
+/// - reason: Why the file was skipped or failed, if it was
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckpointFile {
+    pub state: FileState,
+    pub hash: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Savable progress of a run
+/// # Fields:
+/// - plan: Name of the plan being run
+/// - batches: Batches of the plan when the run started, a checkpoint is only used for the same batches
+/// - completed_batches: Batches fully processed
+/// - in_progress: Finished files of the batches that were being processed when the
+///   checkpoint was saved, by batch then by path at the destination
+/// - middleware: State of the middleware stages, e.g. how many hashes were recorded so far
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CheckpointState {
+    pub plan: String,
+    pub batches: Vec<String>,
+    pub completed_batches: Vec<String>,
+    #[serde(default)]
+    pub in_progress: BTreeMap<String, BTreeMap<String, CheckpointFile>>,
+    #[serde(default)]
+    pub middleware: BTreeMap<String, serde_yaml::Value>,
+}
+
+impl CheckpointState {
+    pub fn new(plan: &str, batches: &[String]) -> CheckpointState {
+        CheckpointState {
+            plan: plan.to_string(),
+            batches: batches.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<usize, String> {
+        let yaml = match serde_yaml::to_string(&self) {
+            Ok(yaml) => yaml,
+            Err(err) => return Err(format!("save_checkpoint Error: {:?}", err)),
+        };
+        // A run killed while saving does not leave a truncated checkpoint behind
+        file_write_atomic(path, yaml.as_bytes())?;
+        Ok(yaml.len())
+    }
+
+    pub fn load_state(path: &Path) -> Result<CheckpointState, String> {
+        let mut file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("load_checkpoint Error: {:?}", err)),
+        };
+        let mut yaml = String::new();
+        if let Err(err) = file.read_to_string(&mut yaml) {
+            return Err(format!("load_checkpoint Error: {:?}", err));
+        }
+        match serde_yaml::from_str(&yaml) {
+            Ok(state) => Ok(state),
+            Err(err) => Err(format!("load_checkpoint Error: {:?}", err)),
+        }
+    }
+}
+
+/// Tracks which batches and files a run already finished, so a run that was
+/// killed picks up where it stopped instead of starting again from batch 0
+///
+/// The checkpoint is saved after every batch and every [`CHECKPOINT_INTERVAL`]
+/// files, and removed once the run completes. Workers processing different
+/// batches at once share it.
+pub struct Checkpoint {
+    path: PathBuf,
+    state: Mutex<CheckpointState>,
+    unsaved: Mutex<usize>,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf, state: CheckpointState) -> Checkpoint {
+        Checkpoint {
+            path,
+            state: Mutex::new(state),
+            unsaved: Mutex::new(0),
+        }
+    }
+
+    /// Loads the checkpoint left by an interrupted run of the same batches,
+    /// starting fresh if there is none or it does not match
+    pub fn load(path: PathBuf, plan: &str, batches: &[String]) -> Result<Checkpoint, String> {
+        if !path.exists() {
+            debug!("No checkpoint at {:?}, starting from the first batch", path);
+            return Ok(Checkpoint::new(path, CheckpointState::new(plan, batches)));
+        }
+        let state = CheckpointState::load_state(&path)?;
+        if state.plan != plan || state.batches != batches {
+            warn!(
+                "Checkpoint: {:?} was saved for different batches, starting from the first batch",
+                path
+            );
+            return Ok(Checkpoint::new(path, CheckpointState::new(plan, batches)));
+        }
+        info!(
+            "Checkpoint: Resuming after {} of {} batches ({} files into {} more batches)",
+            state.completed_batches.len(),
+            state.batches.len(),
+            state
+                .in_progress
+                .values()
+                .map(|files| files.len())
+                .sum::<usize>(),
+            state.in_progress.len()
+        );
+        Ok(Checkpoint::new(path, state))
+    }
+
+    pub fn get_path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Sets the batches the checkpoint is for, as a run that processes
+    /// batches while they are discovered cuts them
+    pub fn set_batches(&self, batches: &[String]) {
+        self.state.lock().unwrap().batches = batches.to_vec();
+    }
+
+    /// Hands the state saved before the interruption back to the middleware stages
+    pub fn resume(&self, chain: &MiddlewareChain) -> Result<(), String> {
+        chain.resume(&self.state.lock().unwrap().middleware)
+    }
+
+    pub fn is_batch_done(&self, batch: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .completed_batches
+            .iter()
+            .any(|done| done == batch)
+    }
+
+    /// Outcome of a file finished before the interruption, if any
+    pub fn get_finished(&self, batch: &str, file: &VictoryFile) -> Option<CheckpointFile> {
+        let state = self.state.lock().unwrap();
+        state
+            .in_progress
+            .get(batch)?
+            .get(&os_path::escaped(&file.get_dest_path()))
+            .cloned()
+    }
+
+    /// Records a finished file, saving the checkpoint every [`CHECKPOINT_INTERVAL`] files
+    pub fn record_file(
+        &self,
+        batch: &str,
+        file: &VictoryFile,
+        chain: &MiddlewareChain,
+    ) -> Result<(), String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state
+                .in_progress
+                .entry(batch.to_string())
+                .or_default()
+                .insert(
+                    os_path::escaped(&file.get_dest_path()),
+                    CheckpointFile {
+                        state: file.state.clone(),
+                        hash: file.hash.clone(),
+                        reason: file.reason.clone(),
+                    },
+                );
+        }
+        let mut unsaved = self.unsaved.lock().unwrap();
+        *unsaved += 1;
+        if *unsaved < CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+        *unsaved = 0;
+        self.save(chain)
+    }
+
+    /// Marks a batch as fully processed and saves the checkpoint
+    pub fn complete_batch(&self, batch: &str, chain: &MiddlewareChain) -> Result<(), String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.completed_batches.push(batch.to_string());
+            state.in_progress.remove(batch);
+        }
+        *self.unsaved.lock().unwrap() = 0;
+        self.save(chain)
+    }
+
+    pub fn save(&self, chain: &MiddlewareChain) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.middleware = chain.get_checkpoint();
+        state.save_state(&self.path)?;
+        debug!(
+            "Checkpoint: Saved after {} batches to {:?}",
+            state.completed_batches.len(),
+            self.path
+        );
+        Ok(())
+    }
+
+    /// Removes the checkpoint of a completed run
+    pub fn remove(&self) -> Result<(), String> {
+        Checkpoint::remove_path(&self.path)
+    }
+
+    /// Removes a checkpoint, a missing checkpoint is not an error
+    pub fn remove_path(path: &PathBuf) -> Result<(), String> {
+        if !path.exists() {
+            return Ok(());
+        }
+        match std::fs::remove_file(path) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("remove_checkpoint Error: {:?}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+    use super::*;
+    use crate::{
+        middleware::filter_hash::{HashFilter, HashIndex},
+        utils::file_utils::{file_remove_all, file_test_dir},
+    };
+
+    fn hash_chain() -> MiddlewareChain {
+        let mut chain = MiddlewareChain::new();
+        chain.push(Box::new(HashFilter::new(HashIndex::new(), true)));
+        chain
+    }
+
+    fn stored_file(name: &str) -> VictoryFile {
+        let mut file = VictoryFile::new(&PathBuf::from(name));
+        file.source = "src".to_string();
+        file.load_contents(name.as_bytes().to_vec()).unwrap();
+        file.clear_contents();
+        file.hash = format!("hash_{}", name);
+        file
+    }
+
+    #[test]
+    fn test_resume_after_interruption() {
+        let test_dir = file_test_dir("test_checkpoint_resume".to_string());
+        let path = test_dir.join("plan.vcheckpoint");
+        let batches = vec!["b0".to_string(), "b1".to_string()];
+        let chain = hash_chain();
+
+        let checkpoint = Checkpoint::load(path.clone(), "plan", &batches).unwrap();
+        assert!(!checkpoint.is_batch_done("b0"));
+        let file = stored_file("a.txt");
+        chain.after_write(&file, &["dest".to_string()]);
+        checkpoint.record_file("b0", &file, &chain).unwrap();
+        checkpoint.complete_batch("b0", &chain).unwrap();
+        let file = stored_file("b.txt");
+        chain.after_write(&file, &["dest".to_string()]);
+        checkpoint.record_file("b1", &file, &chain).unwrap();
+        checkpoint.save(&chain).unwrap();
+
+        // Next run
+        let resumed = Checkpoint::load(path.clone(), "plan", &batches).unwrap();
+        assert!(resumed.is_batch_done("b0"));
+        assert!(!resumed.is_batch_done("b1"));
+        let finished = resumed.get_finished("b1", &file).unwrap();
+        assert_eq!(finished.state, FileState::Stored);
+        assert_eq!(finished.hash, "hash_b.txt");
+        assert!(resumed.get_finished("b1", &stored_file("c.txt")).is_none());
+        assert!(resumed.get_finished("b0", &file).is_none());
+
+        let resumed_chain = hash_chain();
+        resumed.resume(&resumed_chain).unwrap();
+        assert_eq!(resumed_chain.get_checkpoint(), chain.get_checkpoint());
+
+        resumed.remove().unwrap();
+        assert!(!path.exists());
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_ignores_other_batches() {
+        let test_dir = file_test_dir("test_checkpoint_other_batches".to_string());
+        let path = test_dir.join("plan.vcheckpoint");
+        let chain = hash_chain();
+
+        let checkpoint = Checkpoint::load(path.clone(), "plan", &["b0".to_string()]).unwrap();
+        checkpoint.complete_batch("b0", &chain).unwrap();
+
+        let rediscovered =
+            Checkpoint::load(path.clone(), "plan", &["b0".to_string(), "b1".to_string()]).unwrap();
+        assert!(!rediscovered.is_batch_done("b0"));
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_batches_in_progress_together() {
+        let test_dir = file_test_dir("test_checkpoint_in_progress".to_string());
+        let path = test_dir.join("plan.vcheckpoint");
+        let batches = vec!["b0".to_string(), "b1".to_string()];
+        let chain = hash_chain();
+
+        let checkpoint = Checkpoint::load(path.clone(), "plan", &batches).unwrap();
+        let (a, b) = (stored_file("a.txt"), stored_file("b.txt"));
+        checkpoint.record_file("b0", &a, &chain).unwrap();
+        checkpoint.record_file("b1", &b, &chain).unwrap();
+        checkpoint.save(&chain).unwrap();
+
+        let resumed = Checkpoint::load(path.clone(), "plan", &batches).unwrap();
+        assert!(resumed.get_finished("b0", &a).is_some());
+        assert!(resumed.get_finished("b1", &b).is_some());
+        resumed.complete_batch("b0", &chain).unwrap();
+        assert!(resumed.get_finished("b0", &a).is_none());
+        assert!(resumed.get_finished("b1", &b).is_some());
+
+        Checkpoint::remove_path(&path).unwrap();
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_saves_every_interval() {
+        let test_dir = file_test_dir("test_checkpoint_interval".to_string());
+        let path = test_dir.join("plan.vcheckpoint");
+        let chain = hash_chain();
+        let checkpoint = Checkpoint::load(path.clone(), "plan", &["b0".to_string()]).unwrap();
+
+        for idx in 0..CHECKPOINT_INTERVAL - 1 {
+            let file = stored_file(&format!("file_{}", idx));
+            checkpoint.record_file("b0", &file, &chain).unwrap();
+        }
+        assert!(!path.exists());
+        checkpoint
+            .record_file("b0", &stored_file("last"), &chain)
+            .unwrap();
+        let saved = CheckpointState::load_state(&path).unwrap();
+        assert_eq!(saved.in_progress["b0"].len(), CHECKPOINT_INTERVAL);
+
+        Checkpoint::remove_path(&path).unwrap();
+        file_remove_all(&test_dir).unwrap();
+    }
+}
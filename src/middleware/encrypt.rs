@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, ErrorKind, Read},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::Mutex,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit, OsRng, Payload,
+    },
+    Key, XChaCha20Poly1305,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::file::VictoryFile;
+
+use super::Middleware;
+
+/// Magic bytes every encrypted file starts with
+pub const ENCRYPT_MAGIC: &[u8; 4] = b"VENC";
+pub const ENCRYPT_VERSION: u8 = 1;
+/// Plaintext bytes per authenticated chunk
+pub const ENCRYPT_CHUNK_SIZE: usize = 64 * 1024;
+
+const SALT_LEN: usize = 16;
+/// XChaCha20 nonce minus the 5 bytes the STREAM construction uses as chunk counter
+const NONCE_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = ENCRYPT_MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+/// Savable encryption settings. The secret itself is never stored in the plan.
+/// # Fields:
+/// - passphrase_env: Name of the environment variable holding the passphrase
+/// - key_file: Path to a file whose contents are used as the secret
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct EncryptionConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+}
+
+impl EncryptionConfig {
+    /// Reads the secret the encryption key is derived from
+    pub fn load_secret(&self) -> Result<Vec<u8>, String> {
+        match (&self.passphrase_env, &self.key_file) {
+            (Some(var), None) => match std::env::var(var) {
+                Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase.into_bytes()),
+                Ok(_) => Err(format!("Encryption Error: ${} is empty", var)),
+                Err(err) => Err(format!("Encryption Error: ${}: {}", var, err)),
+            },
+            (None, Some(path)) => match std::fs::read(path) {
+                Ok(secret) if !secret.is_empty() => Ok(secret),
+                Ok(_) => Err(format!("Encryption Error: key file {:?} is empty", path)),
+                Err(err) => Err(format!("Encryption Error: key file {:?}: {:?}", path, err)),
+            },
+            _ => Err("Encryption Error: set exactly one of passphrase_env or key_file".to_string()),
+        }
+    }
+}
+
+/// Authenticated encryption of file contents (XChaCha20-Poly1305, STREAM construction)
+///
+/// The key is derived from the secret with Argon2id using a random salt per
+/// run. Each encrypted file stores the salt and a random nonce in its header,
+/// followed by the contents split into independently authenticated chunks, so
+/// any modification, reordering or truncation fails to decrypt. Every chunk
+/// also authenticates the header and the file's path at the destination, so
+/// a file moved to another path, or restored in place of another, fails too.
+pub struct Encryption {
+    secret: Vec<u8>,
+    salt: [u8; SALT_LEN],
+    key: Key,
+    /// Keys for salts written by earlier runs, only needed to decrypt
+    keys: Mutex<HashMap<[u8; SALT_LEN], Key>>,
+}
+
+impl Encryption {
+    pub fn new(secret: &[u8]) -> Result<Encryption, String> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = Self::derive_key(secret, &salt)?;
+        Ok(Encryption {
+            secret: secret.to_vec(),
+            salt,
+            key,
+            keys: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn from_config(config: &EncryptionConfig) -> Result<Encryption, String> {
+        Encryption::new(&config.load_secret()?)
+    }
+
+    fn derive_key(secret: &[u8], salt: &[u8; SALT_LEN]) -> Result<Key, String> {
+        let mut key = Key::default();
+        match Argon2::default().hash_password_into(secret, salt, &mut key) {
+            Ok(_) => Ok(key),
+            Err(err) => Err(format!("Encryption Error: key derivation failed: {}", err)),
+        }
+    }
+
+    fn key_for_salt(&self, salt: &[u8; SALT_LEN]) -> Result<Key, String> {
+        if *salt == self.salt {
+            return Ok(self.key);
+        }
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(key) = keys.get(salt) {
+            return Ok(*key);
+        }
+        debug!("Encryption: Deriving key for salt of a previous run");
+        let key = Self::derive_key(&self.secret, salt)?;
+        keys.insert(*salt, key);
+        Ok(key)
+    }
+
+    /// Data every chunk of a file authenticates besides its contents
+    fn associated_data(header: &[u8], path: &Path) -> Vec<u8> {
+        let mut aad = header.to_vec();
+        aad.extend_from_slice(path.as_os_str().as_bytes());
+        aad
+    }
+
+    /// Wraps a reader so it yields the encrypted form of its contents, header
+    /// included, bound to the path the file is stored at
+    pub fn encrypt_reader<'a>(
+        &self,
+        reader: Box<dyn Read + 'a>,
+        path: &Path,
+    ) -> Box<dyn Read + 'a> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(ENCRYPT_MAGIC);
+        header.push(ENCRYPT_VERSION);
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&nonce);
+
+        let cipher = XChaCha20Poly1305::new(&self.key);
+        Box::new(EncryptReader {
+            inner: reader,
+            encryptor: Some(EncryptorBE32::from_aead(cipher, nonce.as_ref().into())),
+            aad: Self::associated_data(&header, path),
+            plaintext: Vec::with_capacity(ENCRYPT_CHUNK_SIZE + 1),
+            output: Cursor::new(header),
+        })
+    }
+
+    /// Wraps a reader over encrypted contents so it yields the decrypted
+    /// contents. Reading fails if any chunk does not authenticate, or the
+    /// file was encrypted for another path.
+    pub fn decrypt_reader<'a>(
+        &self,
+        reader: Box<dyn Read + 'a>,
+        path: &Path,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let mut reader = reader;
+        let mut header = [0u8; HEADER_LEN];
+        if reader.read_exact(&mut header).is_err() || &header[..ENCRYPT_MAGIC.len()] != ENCRYPT_MAGIC
+        {
+            return Err("Decryption Error: not an encrypted file".to_string());
+        }
+        let version = header[ENCRYPT_MAGIC.len()];
+        if version != ENCRYPT_VERSION {
+            return Err(format!(
+                "Decryption Error: unsupported format version {}",
+                version
+            ));
+        }
+        let salt_start = ENCRYPT_MAGIC.len() + 1;
+        let nonce_start = salt_start + SALT_LEN;
+        let salt: [u8; SALT_LEN] = header[salt_start..nonce_start].try_into().unwrap();
+        let nonce = &header[nonce_start..HEADER_LEN];
+
+        let cipher = XChaCha20Poly1305::new(&self.key_for_salt(&salt)?);
+        Ok(Box::new(DecryptReader {
+            inner: reader,
+            decryptor: Some(DecryptorBE32::from_aead(cipher, nonce.into())),
+            aad: Self::associated_data(&header, path),
+            ciphertext: Vec::with_capacity(ENCRYPT_CHUNK_SIZE + TAG_LEN + 1),
+            output: Cursor::new(Vec::new()),
+        }))
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8], path: &Path) -> Result<Vec<u8>, String> {
+        let chunks = plaintext.len() / ENCRYPT_CHUNK_SIZE + 1;
+        let mut data = Vec::with_capacity(HEADER_LEN + plaintext.len() + chunks * TAG_LEN);
+        match self
+            .encrypt_reader(Box::new(plaintext), path)
+            .read_to_end(&mut data)
+        {
+            Ok(_) => Ok(data),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    pub fn decrypt(&self, data: &[u8], path: &Path) -> Result<Vec<u8>, String> {
+        let mut plaintext = Vec::with_capacity(data.len());
+        match self
+            .decrypt_reader(Box::new(data), path)?
+            .read_to_end(&mut plaintext)
+        {
+            Ok(_) => Ok(plaintext),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+/// Fills `buf` up to `len` bytes, stopping early only at the end of `reader`
+fn fill_chunk(reader: &mut dyn Read, buf: &mut Vec<u8>, len: usize) -> std::io::Result<()> {
+    let missing = len.saturating_sub(buf.len());
+    reader.take(missing as u64).read_to_end(buf)?;
+    Ok(())
+}
+
+/// Encrypts contents one chunk at a time as they are read
+///
+/// One byte past the chunk is read ahead to know whether the chunk is the
+/// last one, which the STREAM construction authenticates differently.
+struct EncryptReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    /// Taken once the last chunk was encrypted
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    /// Header and path, authenticated with every chunk
+    aad: Vec<u8>,
+    plaintext: Vec<u8>,
+    output: Cursor<Vec<u8>>,
+}
+
+impl EncryptReader<'_> {
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        fill_chunk(&mut self.inner, &mut self.plaintext, ENCRYPT_CHUNK_SIZE + 1)?;
+        let encrypted = if self.plaintext.len() > ENCRYPT_CHUNK_SIZE {
+            let encryptor = self.encryptor.as_mut().unwrap();
+            let encrypted = encryptor.encrypt_next(Payload {
+                msg: &self.plaintext[..ENCRYPT_CHUNK_SIZE],
+                aad: &self.aad,
+            });
+            self.plaintext.drain(..ENCRYPT_CHUNK_SIZE);
+            encrypted
+        } else {
+            let encryptor = self.encryptor.take().unwrap();
+            let encrypted = encryptor.encrypt_last(Payload {
+                msg: &self.plaintext[..],
+                aad: &self.aad,
+            });
+            self.plaintext.clear();
+            encrypted
+        };
+        match encrypted {
+            Ok(encrypted) => {
+                self.output = Cursor::new(encrypted);
+                Ok(())
+            }
+            Err(err) => Err(std::io::Error::other(format!("Encryption Error: {}", err))),
+        }
+    }
+}
+
+impl Read for EncryptReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.output.read(buf)?;
+            if read > 0 || buf.is_empty() || self.encryptor.is_none() {
+                return Ok(read);
+            }
+            self.next_chunk()?;
+        }
+    }
+}
+
+/// Decrypts contents one authenticated chunk at a time as they are read
+struct DecryptReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    /// Taken once the last chunk was decrypted
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    /// Header and path, authenticated with every chunk
+    aad: Vec<u8>,
+    ciphertext: Vec<u8>,
+    output: Cursor<Vec<u8>>,
+}
+
+impl DecryptReader<'_> {
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        const SEALED_CHUNK_SIZE: usize = ENCRYPT_CHUNK_SIZE + TAG_LEN;
+        fill_chunk(&mut self.inner, &mut self.ciphertext, SEALED_CHUNK_SIZE + 1)?;
+        let decrypted = if self.ciphertext.len() > SEALED_CHUNK_SIZE {
+            let decryptor = self.decryptor.as_mut().unwrap();
+            let decrypted = decryptor.decrypt_next(Payload {
+                msg: &self.ciphertext[..SEALED_CHUNK_SIZE],
+                aad: &self.aad,
+            });
+            self.ciphertext.drain(..SEALED_CHUNK_SIZE);
+            decrypted
+        } else {
+            let decryptor = self.decryptor.take().unwrap();
+            let decrypted = decryptor.decrypt_last(Payload {
+                msg: &self.ciphertext[..],
+                aad: &self.aad,
+            });
+            self.ciphertext.clear();
+            decrypted
+        };
+        match decrypted {
+            Ok(decrypted) => {
+                self.output = Cursor::new(decrypted);
+                Ok(())
+            }
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Decryption Error: file was tampered with",
+            )),
+        }
+    }
+}
+
+impl Read for DecryptReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.output.read(buf)?;
+            if read > 0 || buf.is_empty() || self.decryptor.is_none() {
+                return Ok(read);
+            }
+            self.next_chunk()?;
+        }
+    }
+}
+
+impl Middleware for Encryption {
+    fn get_name(&self) -> String {
+        "encrypt".to_string()
+    }
+
+    fn before_write<'a>(
+        &self,
+        file: &VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        Ok(self.encrypt_reader(reader, &file.get_dest_path()))
+    }
+
+    fn on_restore<'a>(
+        &self,
+        file: &VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        match self.decrypt_reader(reader, &file.get_dest_path()) {
+            Ok(reader) => Ok(reader),
+            Err(err) => {
+                warn!("Encryption: {:?}: {}", file.path, err);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod encrypt_tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let encryption = Encryption::new(b"correct horse").unwrap();
+        let path = Path::new("src/a.txt");
+        for len in [
+            0,
+            1,
+            ENCRYPT_CHUNK_SIZE - 1,
+            ENCRYPT_CHUNK_SIZE,
+            ENCRYPT_CHUNK_SIZE + 1,
+            3 * ENCRYPT_CHUNK_SIZE + 5,
+        ] {
+            let plaintext = data(len);
+            let encrypted = encryption.encrypt(&plaintext, path).unwrap();
+            assert_eq!(&encrypted[..4], ENCRYPT_MAGIC);
+            assert_ne!(&encrypted[HEADER_LEN..], &plaintext[..]);
+            assert_eq!(encryption.decrypt(&encrypted, path).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_with_other_salt() {
+        let first = Encryption::new(b"correct horse").unwrap();
+        let second = Encryption::new(b"correct horse").unwrap();
+        assert_ne!(first.salt, second.salt);
+
+        let path = Path::new("src/a.txt");
+        let encrypted = first.encrypt(b"hello", path).unwrap();
+        assert_eq!(second.decrypt(&encrypted, path).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_detects_tampering() {
+        let encryption = Encryption::new(b"correct horse").unwrap();
+        let path = Path::new("src/a.txt");
+        let encrypted = encryption
+            .encrypt(&data(2 * ENCRYPT_CHUNK_SIZE + 10), path)
+            .unwrap();
+
+        // Flipped bit in the contents, the salt and the nonce
+        for idx in [encrypted.len() - 1, HEADER_LEN + 10, 6, HEADER_LEN - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[idx] ^= 0x01;
+            assert!(
+                encryption.decrypt(&tampered, path).is_err(),
+                "index {}",
+                idx
+            );
+        }
+
+        // Dropped trailing chunk
+        let truncated = &encrypted[..HEADER_LEN + 2 * (ENCRYPT_CHUNK_SIZE + TAG_LEN)];
+        assert!(encryption.decrypt(truncated, path).is_err());
+
+        // Not encrypted at all
+        assert!(encryption.decrypt(b"plain text file", path).is_err());
+
+        let wrong = Encryption::new(b"wrong horse").unwrap();
+        assert!(wrong.decrypt(&encrypted, path).is_err());
+    }
+
+    #[test]
+    fn test_bound_to_path() {
+        let encryption = Encryption::new(b"correct horse").unwrap();
+        let notes = VictoryFile::new(&PathBuf::from("notes.txt"));
+        let mut salary = VictoryFile::new(&PathBuf::from("salary.txt"));
+        salary.source = "src".to_string();
+
+        let mut stored = Vec::new();
+        encryption
+            .before_write(&salary, Box::new(&b"secret"[..]))
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        let mut restored = Vec::new();
+        encryption
+            .on_restore(&salary, Box::new(&stored[..]))
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, b"secret");
+
+        // Moved to another path, or restored in place of another file
+        let mut reader = encryption
+            .on_restore(&notes, Box::new(&stored[..]))
+            .unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(encryption
+            .decrypt(&stored, Path::new("other/salary.txt"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_stream_in_small_reads() {
+        let encryption = Encryption::new(b"correct horse").unwrap();
+        let file = VictoryFile::new(&PathBuf::from("a.txt"));
+        let plaintext = data(2 * ENCRYPT_CHUNK_SIZE + 10);
+
+        let mut stored = Vec::new();
+        let mut reader = encryption
+            .before_write(&file, Box::new(&plaintext[..]))
+            .unwrap();
+        let mut buf = [0u8; 7];
+        loop {
+            let read = reader.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            stored.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(
+            encryption.decrypt(&stored, &file.get_dest_path()).unwrap(),
+            plaintext
+        );
+
+        let mut restored = Vec::new();
+        encryption
+            .on_restore(&file, Box::new(&stored[..]))
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, plaintext);
+
+        // Tampering only shows once the chunk is read
+        stored[HEADER_LEN] ^= 0x01;
+        let mut reader = encryption.on_restore(&file, Box::new(&stored[..])).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(encryption
+            .on_restore(&file, Box::new(&b"plain text file"[..]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_load_secret() {
+        let config = EncryptionConfig {
+            passphrase_env: Some("VICTORY_TEST_LOAD_SECRET".to_string()),
+            key_file: None,
+        };
+        assert!(config.load_secret().is_err());
+        std::env::set_var("VICTORY_TEST_LOAD_SECRET", "hunter2");
+        assert_eq!(config.load_secret().unwrap(), b"hunter2");
+
+        let key_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(key_file.path(), b"key file contents").unwrap();
+        let config = EncryptionConfig {
+            passphrase_env: None,
+            key_file: Some(key_file.path().to_str().unwrap().to_string()),
+        };
+        assert_eq!(config.load_secret().unwrap(), b"key file contents");
+
+        assert!(EncryptionConfig::default().load_secret().is_err());
+    }
+}
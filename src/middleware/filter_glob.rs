@@ -0,0 +1,239 @@
+use std::{os::unix::ffi::OsStrExt, path::Path};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::file::{FileKind, VictoryFile};
+
+use super::{Middleware, MiddlewareAction};
+
+/// Savable include/exclude patterns, using gitignore syntax
+/// # Fields:
+/// - include: When not empty, only files matching one of these are kept
+/// - exclude: Files and directories matching any of these are dropped, excluded
+///   directories are never walked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GlobFilterConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+}
+
+impl GlobFilterConfig {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> GlobFilterConfig {
+        GlobFilterConfig { include, exclude }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Combines two sets of patterns, e.g. a source's own patterns with the plan wide ones
+    pub fn merge(&self, other: &GlobFilterConfig) -> GlobFilterConfig {
+        let mut merged = self.clone();
+        merged.include.extend(other.include.iter().cloned());
+        merged.exclude.extend(other.exclude.iter().cloned());
+        merged
+    }
+
+    /// Patterns excluding a single folder of a source, and everything in it
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the folder relative to the source root. Bytes that
+    ///   are not UTF-8 can not be written in a pattern, they match any single byte.
+    pub fn exclude_dir(path: &Path) -> GlobFilterConfig {
+        // Anchored to the root, with glob characters matching themselves
+        let mut pattern = "/".to_string();
+        for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if matches!(c, '\\' | '*' | '?' | '[' | ']' | ' ') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+            }
+            for _ in chunk.invalid() {
+                pattern.push('?');
+            }
+        }
+        pattern.push('/');
+        GlobFilterConfig::new(Vec::new(), vec![pattern])
+    }
+}
+
+/// Compiled version of a [`GlobFilterConfig`], matched against paths relative to a source root
+#[derive(Debug, Clone)]
+pub struct GlobFilter {
+    config: GlobFilterConfig,
+    include: Option<Gitignore>,
+    exclude: Gitignore,
+}
+
+impl GlobFilter {
+    pub fn new(config: &GlobFilterConfig) -> Result<GlobFilter, String> {
+        let include = match config.include.is_empty() {
+            true => None,
+            false => Some(Self::build(&config.include)?),
+        };
+        let exclude = Self::build(&config.exclude)?;
+        Ok(GlobFilter {
+            config: config.clone(),
+            include,
+            exclude,
+        })
+    }
+
+    fn build(patterns: &[String]) -> Result<Gitignore, String> {
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in patterns {
+            if let Err(err) = builder.add_line(None, pattern) {
+                return Err(format!(
+                    "GlobFilter Error: invalid pattern {:?}: {}",
+                    pattern, err
+                ));
+            }
+        }
+        match builder.build() {
+            Ok(gitignore) => Ok(gitignore),
+            Err(err) => Err(format!("GlobFilter Error: {}", err)),
+        }
+    }
+
+    /// Checks if an entry should be dropped
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the entry relative to the source root
+    /// * `is_dir` - Whether the entry is a directory. Directories are only checked
+    ///   against exclude patterns, so included files deeper down are still found
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self
+            .exclude
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return true;
+        }
+        if is_dir {
+            return false;
+        }
+        match &self.include {
+            Some(include) => !include
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore(),
+            None => false,
+        }
+    }
+}
+
+impl Middleware for GlobFilter {
+    fn get_name(&self) -> String {
+        "glob".to_string()
+    }
+
+    fn get_discovery_filter(&self) -> Option<&GlobFilterConfig> {
+        Some(&self.config)
+    }
+
+    /// Sources supporting filters already dropped excluded files while walking,
+    /// this catches the files of sources that do not
+    fn on_discover(&self, file: &mut VictoryFile) -> MiddlewareAction {
+        match self.is_excluded(&file.path, file.kind == FileKind::Directory) {
+            true => MiddlewareAction::Skip("excluded by glob patterns".to_string()),
+            false => MiddlewareAction::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod filter_glob_tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> GlobFilter {
+        let config = GlobFilterConfig::new(
+            include.iter().map(|p| p.to_string()).collect(),
+            exclude.iter().map(|p| p.to_string()).collect(),
+        );
+        GlobFilter::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_empty_keeps_everything() {
+        let filter = filter(&[], &[]);
+        assert!(!filter.is_excluded(Path::new("a/b/c.txt"), false));
+        assert!(!filter.is_excluded(Path::new("a/b"), true));
+    }
+
+    #[test]
+    fn test_exclude_dirs() {
+        let filter = filter(&[], &["node_modules/", "target"]);
+        assert!(filter.is_excluded(Path::new("web/node_modules"), true));
+        assert!(filter.is_excluded(Path::new("target"), true));
+        assert!(filter.is_excluded(Path::new("target/debug/app"), false));
+        // Directory only pattern does not match a file of the same name
+        assert!(!filter.is_excluded(Path::new("docs/node_modules"), false));
+        assert!(!filter.is_excluded(Path::new("src/main.rs"), false));
+    }
+
+    #[test]
+    fn test_exclude_dir() {
+        let config = GlobFilterConfig::exclude_dir(Path::new("backups/[old] *"));
+        let filter = GlobFilter::new(&config).unwrap();
+        assert!(filter.is_excluded(Path::new("backups/[old] *"), true));
+        assert!(filter.is_excluded(Path::new("backups/[old] */batch"), false));
+        assert!(!filter.is_excluded(Path::new("backups/o"), true));
+        assert!(!filter.is_excluded(Path::new("backups/[old] x"), true));
+        assert!(!filter.is_excluded(Path::new("nested/backups/[old] *"), true));
+    }
+
+    #[test]
+    fn test_exclude_dir_non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = Path::new(OsStr::from_bytes(b"backups/caf\xe9"));
+        let config = GlobFilterConfig::exclude_dir(dir);
+        let filter = GlobFilter::new(&config).unwrap();
+        assert!(filter.is_excluded(dir, true));
+        assert!(filter.is_excluded(&dir.join("batch"), false));
+        assert!(!filter.is_excluded(Path::new("backups/caf"), true));
+        assert!(!filter.is_excluded(Path::new("backups/caf\u{e9}"), true));
+    }
+
+    #[test]
+    fn test_exclude_with_negation() {
+        let filter = filter(&[], &["*.log", "!keep.log"]);
+        assert!(filter.is_excluded(Path::new("logs/run.log"), false));
+        assert!(!filter.is_excluded(Path::new("logs/keep.log"), false));
+    }
+
+    #[test]
+    fn test_include_only_filters_files() {
+        let filter = filter(&["*.jpg", "docs/"], &["private/"]);
+        assert!(!filter.is_excluded(Path::new("photos/cat.jpg"), false));
+        assert!(!filter.is_excluded(Path::new("docs/notes.txt"), false));
+        assert!(filter.is_excluded(Path::new("photos/cat.txt"), false));
+        // Directories are still walked to find included files
+        assert!(!filter.is_excluded(Path::new("photos"), true));
+        assert!(filter.is_excluded(Path::new("private"), true));
+        assert!(filter.is_excluded(Path::new("private/cat.jpg"), false));
+    }
+
+    #[test]
+    fn test_merge() {
+        let source = GlobFilterConfig::new(vec!["*.rs".to_string()], vec![]);
+        let global = GlobFilterConfig::new(vec![], vec!["target/".to_string()]);
+        let merged = source.merge(&global);
+        assert_eq!(merged.include, vec!["*.rs"]);
+        assert_eq!(merged.exclude, vec!["target/"]);
+        assert!(!merged.is_empty());
+        assert!(GlobFilterConfig::default().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let config = GlobFilterConfig::new(vec![], vec!["src/[z-a].rs".to_string()]);
+        let err = GlobFilter::new(&config).err().unwrap();
+        assert!(err.contains("invalid pattern"));
+    }
+}
@@ -0,0 +1,663 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::VictoryFile,
+    utils::{file_utils::file_write_atomic, os_path},
+};
+
+use super::{Inspector, Middleware};
+
+/// Hex encoded BLAKE3 hash of a file's contents
+pub fn hash_contents(contents: &[u8]) -> String {
+    blake3::hash(contents).to_hex().to_string()
+}
+
+/// Hashes contents as they stream past, storing the hex hash on the file
+pub struct HashInspector {
+    hasher: blake3::Hasher,
+}
+
+impl HashInspector {
+    pub fn new() -> HashInspector {
+        HashInspector {
+            hasher: blake3::Hasher::new(),
+        }
+    }
+}
+
+impl Default for HashInspector {
+    fn default() -> Self {
+        HashInspector::new()
+    }
+}
+
+impl Inspector for HashInspector {
+    fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+    }
+
+    fn finish(self: Box<Self>, file: &mut VictoryFile) {
+        file.hash = self.hasher.finalize().to_hex().to_string();
+    }
+}
+
+/// Hash of a file stored at a destination, with the size and modification
+/// time the file had, to tell whether it is worth reading again
+/// # Fields:
+/// - transforms: Fingerprint of the stages that changed the contents on their
+///   way to the destination (empty if none did), see [`super::MiddlewareConfig::transforms_fingerprint`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct HashEntry {
+    pub hash: String,
+    pub size: usize,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    #[serde(default)]
+    pub transforms: String,
+}
+
+impl HashEntry {
+    pub fn new(file: &VictoryFile) -> HashEntry {
+        let (mtime, mtime_nsec) = match &file.metadata {
+            Some(metadata) => (metadata.mtime, metadata.mtime_nsec),
+            None => (0, 0),
+        };
+        HashEntry {
+            hash: file.hash.clone(),
+            size: file.size,
+            mtime,
+            mtime_nsec,
+            transforms: String::new(),
+        }
+    }
+
+    pub fn with_transforms(mut self, transforms: &str) -> HashEntry {
+        self.transforms = transforms.to_string();
+        self
+    }
+
+    /// Whether the file still has the size and modification time it had,
+    /// files without metadata only compare by size
+    pub fn same_stat(&self, file: &VictoryFile) -> bool {
+        let same_mtime = match &file.metadata {
+            Some(metadata) => {
+                (metadata.mtime, metadata.mtime_nsec) == (self.mtime, self.mtime_nsec)
+            }
+            None => true,
+        };
+        self.size == file.size && same_mtime
+    }
+}
+
+/// Savable content hashes of the files stored at every destination, by
+/// destination id and then by the file's path at the destination
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct HashIndex {
+    pub destinations: BTreeMap<String, BTreeMap<String, HashEntry>>,
+}
+
+impl HashIndex {
+    pub fn new() -> HashIndex {
+        HashIndex::default()
+    }
+
+    fn key(file: &VictoryFile) -> String {
+        os_path::escaped(&file.get_dest_path())
+    }
+
+    pub fn get(&self, dest: &str, file: &VictoryFile) -> Option<&HashEntry> {
+        self.destinations.get(dest)?.get(&Self::key(file))
+    }
+
+    pub fn insert(&mut self, dest: &str, file: &VictoryFile, entry: HashEntry) {
+        self.insert_key(dest, Self::key(file), entry);
+    }
+
+    /// Number of hashes over every destination
+    pub fn get_length(&self) -> usize {
+        self.destinations.values().map(|hashes| hashes.len()).sum()
+    }
+
+    fn insert_key(&mut self, dest: &str, key: String, entry: HashEntry) {
+        self.destinations
+            .entry(dest.to_string())
+            .or_default()
+            .insert(key, entry);
+    }
+
+    /// Adds every hash of another index, replacing the ones already known
+    pub fn merge(&mut self, other: &HashIndex) {
+        for (dest, hashes) in &other.destinations {
+            let known = self.destinations.entry(dest.clone()).or_default();
+            for (path, entry) in hashes {
+                known.insert(path.clone(), entry.clone());
+            }
+        }
+    }
+
+    /// Saves the index, replacing the previous one in a single step
+    pub fn save_index(&self, path: PathBuf) -> Result<usize, String> {
+        let yaml = match serde_yaml::to_string(&self) {
+            Ok(yaml) => yaml,
+            Err(err) => return Err(format!("save_index Error: {:?}", err)),
+        };
+        file_write_atomic(&path, yaml.as_bytes())?;
+        Ok(yaml.len())
+    }
+
+    /// Loads an index, a missing file is treated as an empty index (first run)
+    pub fn load_index(path: PathBuf) -> Result<HashIndex, String> {
+        if !path.exists() {
+            debug!("No hash index at {:?}, starting empty", path);
+            return Ok(HashIndex::new());
+        }
+        let mut file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("load_index Error: {:?}", err)),
+        };
+        let mut yaml = String::new();
+        if let Err(err) = file.read_to_string(&mut yaml) {
+            return Err(format!("load_index Error: {:?}", err));
+        }
+        match serde_yaml::from_str(&yaml) {
+            Ok(index) => Ok(index),
+            Err(err) => Err(format!("load_index Error: {:?}", err)),
+        }
+    }
+}
+
+/// A hash recorded by a run, as appended to its log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct LoggedHash {
+    dest: String,
+    path: String,
+    entry: HashEntry,
+}
+
+/// Leaves files out at destinations that hold the same contents since the previous run
+///
+/// Hashes are computed as files are read and compared against the index saved
+/// by the previous run for each destination, so a destination added since
+/// still gets every file. Only destinations a file reached are recorded in
+/// the new index, so a failed write is retried there on the next run. Files
+/// stored through other transforms (e.g. before compression was enabled)
+/// count as changed.
+///
+/// Recorded hashes are also appended to a log next to the index, so the
+/// checkpoint of an interrupted run does not have to hold all of them.
+/// Resuming replays the log.
+pub struct HashFilter {
+    previous: HashIndex,
+    current: Mutex<HashIndex>,
+    skip_unchanged: bool,
+    /// Fingerprint of the stages changing the contents in this run
+    transforms: String,
+    /// Where the index is saved once the run finishes
+    index_path: Option<PathBuf>,
+    /// Log of the hashes recorded in this run, opened with the first one
+    log: Mutex<Option<BufWriter<File>>>,
+}
+
+impl HashFilter {
+    pub fn new(previous: HashIndex, skip_unchanged: bool) -> HashFilter {
+        HashFilter {
+            previous,
+            current: Mutex::new(HashIndex::new()),
+            skip_unchanged,
+            transforms: String::new(),
+            index_path: None,
+            log: Mutex::new(None),
+        }
+    }
+
+    pub fn with_transforms(mut self, transforms: String) -> HashFilter {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Loads the previous run's index from disk, the new index is saved to
+    /// the same path when the run finishes
+    pub fn load(path: PathBuf, skip_unchanged: bool) -> Result<HashFilter, String> {
+        let previous = HashIndex::load_index(path.clone())?;
+        info!(
+            "HashFilter: Loaded {} hashes from previous run",
+            previous.get_length()
+        );
+        let mut filter = HashFilter::new(previous, skip_unchanged);
+        filter.index_path = Some(path);
+        Ok(filter)
+    }
+
+    /// Checks the hash of a freshly read file against what the previous run
+    /// stored at a destination
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the destination holds the same contents and the file should be left out there
+    pub fn is_unchanged(&self, file: &VictoryFile, dest: &str) -> bool {
+        match self.get_previous(file, dest) {
+            Some(previous) => previous.hash == file.hash,
+            None => false,
+        }
+    }
+
+    /// Checks the size and modification time of a file that was not read yet
+    /// against what the previous run stored at a destination
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the file looks unchanged, so it is worth hashing before it is written
+    pub fn may_be_unchanged(&self, file: &VictoryFile, dest: &str) -> bool {
+        match self.get_previous(file, dest) {
+            Some(previous) => previous.same_stat(file),
+            None => false,
+        }
+    }
+
+    /// What the previous run stored at a destination, if it is worth comparing against
+    fn get_previous(&self, file: &VictoryFile, dest: &str) -> Option<&HashEntry> {
+        if !self.skip_unchanged {
+            return None;
+        }
+        self.previous
+            .get(dest, file)
+            .filter(|previous| previous.transforms == self.transforms)
+    }
+
+    /// Records a file as backed up at a destination, to compare against on the next run
+    pub fn record(&self, dest: &str, file: &VictoryFile) {
+        let entry = HashEntry::new(file).with_transforms(&self.transforms);
+        if let Err(err) = self.append_log(dest, file, &entry) {
+            warn!(
+                "HashFilter: Error logging hash of {:?}: {:?}",
+                file.path, err
+            );
+        }
+        self.current.lock().unwrap().insert(dest, file, entry);
+    }
+
+    /// Log of the recorded hashes next to the index, none for filters without an index
+    pub fn get_log_path(&self) -> Option<PathBuf> {
+        let path = self.index_path.as_ref()?;
+        Some(path.with_extension("vhashes_log"))
+    }
+
+    /// Opens the log, emptying what an earlier run left in it unless the
+    /// run resumed and replayed it
+    fn open_log(&self, append: bool) -> Result<MutexGuard<'_, Option<BufWriter<File>>>, String> {
+        let mut log = self.log.lock().unwrap();
+        if log.is_some() {
+            return Ok(log);
+        }
+        if let Some(path) = self.get_log_path() {
+            if let Some(parent) = path.parent() {
+                if let Err(err) = std::fs::create_dir_all(parent) {
+                    return Err(format!("open_log Error: {:?}", err));
+                }
+            }
+            let opened = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(path);
+            match opened {
+                Ok(file) => *log = Some(BufWriter::new(file)),
+                Err(err) => return Err(format!("open_log Error: {:?}", err)),
+            }
+        }
+        Ok(log)
+    }
+
+    fn append_log(&self, dest: &str, file: &VictoryFile, entry: &HashEntry) -> Result<(), String> {
+        let mut log = self.open_log(false)?;
+        let Some(log) = log.as_mut() else {
+            return Ok(());
+        };
+        let logged = LoggedHash {
+            dest: dest.to_string(),
+            path: HashIndex::key(file),
+            entry: entry.clone(),
+        };
+        match bincode::serialize_into(log, &logged) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("append_log Error: {:?}", err)),
+        }
+    }
+
+    /// Writes every logged hash to disk, so a checkpoint can rely on them
+    fn sync_log(&self) -> Result<(), String> {
+        let mut log = self.open_log(false)?;
+        let Some(log) = log.as_mut() else {
+            return Ok(());
+        };
+        match log.flush().and_then(|_| log.get_ref().sync_data()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("sync_log Error: {:?}", err)),
+        }
+    }
+
+    /// Adds the hashes logged by an interrupted run to the recorded ones.
+    /// A record cut short by the interruption ends the log.
+    fn replay_log(&self) -> Result<usize, String> {
+        let Some(path) = self.get_log_path() else {
+            return Ok(0);
+        };
+        let logged = match std::fs::read(&path) {
+            Ok(logged) => logged,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!("HashFilter: No log at {:?} to resume from", path);
+                Vec::new()
+            }
+            Err(err) => return Err(format!("replay_log Error: {:?}", err)),
+        };
+        let mut reader = logged.as_slice();
+        let mut replayed = 0;
+        let mut current = self.current.lock().unwrap();
+        while !reader.is_empty() {
+            match bincode::deserialize_from::<_, LoggedHash>(&mut reader) {
+                Ok(hash) => current.insert_key(&hash.dest, hash.path, hash.entry),
+                Err(err) => {
+                    warn!("HashFilter: Log {:?} ends early: {:?}", path, err);
+                    break;
+                }
+            }
+            replayed += 1;
+        }
+        drop(current);
+        // Carries on after the replayed hashes
+        drop(self.open_log(true)?);
+        Ok(replayed)
+    }
+
+    /// Copy of every hash recorded so far
+    pub fn get_recorded(&self) -> HashIndex {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Carries over hashes recorded by an interrupted run
+    pub fn resume_recorded(&self, recorded: &HashIndex) {
+        self.current.lock().unwrap().merge(recorded);
+    }
+
+    /// Saves every recorded hash for the next run
+    pub fn save(&self, path: PathBuf) -> Result<usize, String> {
+        self.current.lock().unwrap().save_index(path)
+    }
+}
+
+impl Middleware for HashFilter {
+    fn get_name(&self) -> String {
+        "hash".to_string()
+    }
+
+    fn inspect(&self, _file: &VictoryFile) -> Option<Box<dyn Inspector>> {
+        Some(Box::new(HashInspector::new()))
+    }
+
+    fn may_skip_destination(&self, file: &VictoryFile, dest: &str) -> bool {
+        self.may_be_unchanged(file, dest)
+    }
+
+    fn skip_destination(&self, file: &VictoryFile, dest: &str) -> Option<String> {
+        self.is_unchanged(file, dest)
+            .then(|| "unchanged since the previous run".to_string())
+    }
+
+    /// Left out files are recorded too, so the next run compares against
+    /// them again. Destinations that failed are not, so they are retried.
+    fn after_write(&self, file: &VictoryFile, stored: &[String]) {
+        // Never read, e.g. as every destination kept its existing file
+        if file.hash.is_empty() {
+            return;
+        }
+        for dest in stored {
+            self.record(dest, file);
+        }
+    }
+
+    /// Hashes are in the log, the checkpoint only keeps how many were
+    /// recorded. Filters without an index keep all of them in the checkpoint.
+    fn get_checkpoint(&self) -> Option<serde_yaml::Value> {
+        if self.get_log_path().is_none() {
+            return serde_yaml::to_value(self.get_recorded()).ok();
+        }
+        if let Err(err) = self.sync_log() {
+            warn!("HashFilter: Error syncing log: {:?}", err);
+        }
+        Some(serde_yaml::Value::from(
+            self.current.lock().unwrap().get_length(),
+        ))
+    }
+
+    fn resume(&self, state: &serde_yaml::Value) -> Result<(), String> {
+        if let Some(recorded) = state.as_u64() {
+            let replayed = self.replay_log()?;
+            info!(
+                "HashFilter: Replayed {} hashes, {} at the last checkpoint",
+                replayed, recorded
+            );
+            return Ok(());
+        }
+        // Checkpoints holding every hash themselves
+        match serde_yaml::from_value::<HashIndex>(state.clone()) {
+            Ok(recorded) => {
+                self.resume_recorded(&recorded);
+                Ok(())
+            }
+            Err(err) => Err(format!("HashFilter Error: invalid checkpoint: {:?}", err)),
+        }
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        match &self.index_path {
+            Some(path) => {
+                let size = self.save(path.clone())?;
+                debug!("HashFilter: Saved {} kb index to {:?}", size / 1024, path);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod filter_hash_tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use super::*;
+    use crate::utils::file_utils::{file_remove_all, file_test_dir};
+
+    /// A file as the executor hands it to `skip_destination`, hashed by the filter's inspector
+    fn read_file(filter: &HashFilter, name: &str, contents: &[u8]) -> VictoryFile {
+        let mut file = VictoryFile::new(&PathBuf::from(name));
+        file.source = "src".to_string();
+        let mut inspector = filter.inspect(&file).unwrap();
+        for chunk in contents.chunks(3) {
+            inspector.update(chunk);
+        }
+        inspector.finish(&mut file);
+        file
+    }
+
+    #[test]
+    fn test_hash_contents() {
+        assert_eq!(hash_contents(b"abc"), hash_contents(b"abc"));
+        assert_ne!(hash_contents(b"abc"), hash_contents(b"abd"));
+        assert_eq!(hash_contents(b"").len(), 64);
+    }
+
+    #[test]
+    fn test_skips_unchanged() {
+        let first = HashFilter::new(HashIndex::new(), true);
+        let file = read_file(&first, "a.txt", b"hello");
+        assert_eq!(file.hash, hash_contents(b"hello"));
+        assert_eq!(first.skip_destination(&file, "usb"), None);
+        first.after_write(&file, &["usb".to_string()]);
+
+        let second = HashFilter::new(first.get_recorded(), true);
+        let same = read_file(&second, "a.txt", b"hello");
+        assert!(second.skip_destination(&same, "usb").is_some());
+        second.after_write(&same, &["usb".to_string()]);
+        // Left out files stay in the index for the next run
+        assert_eq!(
+            second.get_recorded().get("usb", &same).unwrap().hash,
+            same.hash
+        );
+
+        let changed = read_file(&second, "a.txt", b"hello world");
+        assert!(!second.is_unchanged(&changed, "usb"));
+
+        let other_path = read_file(&second, "b.txt", b"hello");
+        assert!(!second.is_unchanged(&other_path, "usb"));
+
+        // A name spelling out the escape of a non UTF-8 name is another file
+        let latin1 = PathBuf::from(OsStr::from_bytes(b"caf\xe9.txt"));
+        let mut index = HashIndex::new();
+        index.insert("usb", &VictoryFile::new(&latin1), HashEntry::new(&same));
+        let spelled = VictoryFile::new(&PathBuf::from("caf\\xE9.txt"));
+        assert_eq!(index.get("usb", &spelled), None);
+
+        // A destination added since gets the file anyway
+        assert!(!second.is_unchanged(&same, "nas"));
+
+        // Destinations that failed are not recorded
+        let third = HashFilter::new(HashIndex::new(), true);
+        third.after_write(&changed, &["nas".to_string()]);
+        assert_eq!(third.get_recorded().get_length(), 1);
+        assert_eq!(third.get_recorded().get("usb", &changed), None);
+    }
+
+    #[test]
+    fn test_skip_disabled() {
+        let mut index = HashIndex::new();
+        let file = read_file(&HashFilter::new(HashIndex::new(), true), "a.txt", b"hello");
+        assert_eq!(file.hash, hash_contents(b"hello"));
+        index.insert("usb", &file, HashEntry::new(&file));
+
+        let filter = HashFilter::new(index, false);
+        assert!(!filter.is_unchanged(&file, "usb"));
+        assert_eq!(filter.skip_destination(&file, "usb"), None);
+    }
+
+    #[test]
+    fn test_may_be_unchanged() {
+        let first = HashFilter::new(HashIndex::new(), true);
+        let mut file = read_file(&first, "a.txt", b"hello");
+        file.size = 5;
+        file.metadata = Some(crate::metadata::FileMetadata::default());
+        assert!(!first.may_be_unchanged(&file, "usb"));
+        first.after_write(&file, &["usb".to_string()]);
+
+        let second = HashFilter::new(first.get_recorded(), true);
+        assert!(second.may_be_unchanged(&file, "usb"));
+        assert!(!second.may_be_unchanged(&file, "nas"));
+
+        // A different size or modification time is not worth reading twice
+        let mut grown = file.clone();
+        grown.size = 11;
+        assert!(!second.may_be_unchanged(&grown, "usb"));
+        let mut touched = file.clone();
+        touched.metadata.as_mut().unwrap().mtime_nsec += 1;
+        assert!(!second.may_be_unchanged(&touched, "usb"));
+
+        // Files the destinations kept without reading them are not recorded
+        let mut unread = file.clone();
+        unread.hash = String::new();
+        let third = HashFilter::new(HashIndex::new(), true);
+        third.after_write(&unread, &["usb".to_string()]);
+        assert_eq!(third.get_recorded().get_length(), 0);
+    }
+
+    #[test]
+    fn test_transforms_changed() {
+        let first = HashFilter::new(HashIndex::new(), true);
+        let file = read_file(&first, "a.txt", b"hello");
+        first.after_write(&file, &["usb".to_string()]);
+
+        // Stored as is, then compressed
+        let compressed =
+            HashFilter::new(first.get_recorded(), true).with_transforms("zstd".to_string());
+        assert!(!compressed.is_unchanged(&file, "usb"));
+        assert!(!compressed.may_be_unchanged(&file, "usb"));
+        compressed.after_write(&file, &["usb".to_string()]);
+
+        let again =
+            HashFilter::new(compressed.get_recorded(), true).with_transforms("zstd".to_string());
+        assert!(again.is_unchanged(&file, "usb"));
+        assert!(!HashFilter::new(compressed.get_recorded(), true).is_unchanged(&file, "usb"));
+    }
+
+    #[test]
+    fn test_resume_from_log() {
+        let test_dir = file_test_dir("test_hash_resume_from_log".to_string());
+        file_remove_all(&test_dir).unwrap();
+        let path = test_dir.join("plan.vhashes");
+
+        let interrupted = HashFilter::load(path.clone(), true).unwrap();
+        let first = read_file(&interrupted, "a.txt", b"hello");
+        interrupted.after_write(&first, &["usb".to_string()]);
+        let second = read_file(&interrupted, "b.txt", b"world");
+        interrupted.after_write(&second, &["usb".to_string(), "nas".to_string()]);
+        // Only a count is kept in the checkpoint
+        let state = interrupted.get_checkpoint().unwrap();
+        assert_eq!(state.as_u64(), Some(3));
+        // Killed while appending a record
+        let log_path = interrupted.get_log_path().unwrap();
+        drop(interrupted);
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(&[7, 0, 0]).unwrap();
+
+        let resumed = HashFilter::load(path.clone(), true).unwrap();
+        resumed.resume(&state).unwrap();
+        assert_eq!(resumed.get_recorded().get_length(), 3);
+        assert!(resumed.get_recorded().get("nas", &second).is_some());
+        let third = read_file(&resumed, "c.txt", b"again");
+        resumed.after_write(&third, &["usb".to_string()]);
+        resumed.finish().unwrap();
+        assert_eq!(HashIndex::load_index(path.clone()).unwrap().get_length(), 4);
+
+        // A fresh run starts a new log
+        let fresh = HashFilter::load(path, true).unwrap();
+        fresh.after_write(&third, &["usb".to_string()]);
+        fresh.get_checkpoint();
+        let resumed = HashFilter::load(test_dir.join("plan.vhashes"), true).unwrap();
+        resumed.resume(&serde_yaml::Value::from(1)).unwrap();
+        assert_eq!(resumed.get_recorded().get_length(), 1);
+
+        file_remove_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_load_index() {
+        let test_dir = file_test_dir("test_save_load_index".to_string());
+        let path = test_dir.join("plan.vhashes");
+
+        // Missing index is a first run
+        let empty = HashIndex::load_index(path.clone()).unwrap();
+        assert_eq!(empty.get_length(), 0);
+
+        let filter = HashFilter::load(path.clone(), true).unwrap();
+        let file = read_file(&filter, "a.txt", b"hello");
+        filter.record("usb", &file);
+        filter.finish().unwrap();
+
+        let loaded = HashIndex::load_index(path).unwrap();
+        assert_eq!(
+            loaded.get("usb", &file).unwrap().hash,
+            hash_contents(b"hello")
+        );
+
+        file_remove_all(&test_dir).unwrap();
+    }
+}
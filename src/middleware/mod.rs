@@ -1,4 +1,696 @@
+use std::{collections::BTreeMap, io::Read};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{file::VictoryFile, plan::BackupPlan};
+
+use self::{
+    encrypt::{Encryption, EncryptionConfig},
+    filter_glob::{GlobFilter, GlobFilterConfig},
+    filter_hash::{hash_contents, HashFilter},
+    zip::{Compression, CompressionConfig},
+};
+
 pub mod checkpoint;
-pub mod zip;
+pub mod encrypt;
+pub mod filter_glob;
 pub mod filter_hash;
-pub mod filter_glob;
\ No newline at end of file
+pub mod zip;
+
+/// What should happen to a file after a middleware stage looked at it
+#[derive(Debug, Clone, PartialEq)]
+pub enum MiddlewareAction {
+    /// Hand the file to the next stage
+    Continue,
+    /// Stop processing the file without treating it as a failure
+    Skip(String),
+    /// Stop processing the file, counting it as failed for every destination
+    Error(String),
+}
+
+/// Sees the contents of a file chunk by chunk while it is read, see [`Middleware::inspect`]
+pub trait Inspector {
+    fn update(&mut self, chunk: &[u8]);
+    /// Called once every chunk was seen, e.g. to store a hash on the file
+    fn finish(self: Box<Self>, file: &mut VictoryFile);
+}
+
+/// A stage of the backup pipeline, called for every file that flows through
+/// [`crate::executor::Executor`]
+///
+/// Contents are streamed, never held in memory as a whole: stages look at them
+/// through an [`Inspector`] and transform them by wrapping the reader they
+/// flow through. Every hook defaults to passing the file on unchanged, so a
+/// stage only implements the hooks it cares about. Stages are shared by the
+/// threads discovering sources.
+pub trait Middleware: Send + Sync {
+    fn get_name(&self) -> String;
+
+    /// Include/exclude patterns sources can apply while walking, so excluded
+    /// directories are never entered
+    fn get_discovery_filter(&self) -> Option<&GlobFilterConfig> {
+        None
+    }
+
+    /// Called for every listed file, before it is added to a batch.
+    /// Skipped files are left out of the batch and counted as filtered.
+    fn on_discover(&self, _file: &mut VictoryFile) -> MiddlewareAction {
+        MiddlewareAction::Continue
+    }
+
+    /// Called before a file is read, to see its contents as they are read
+    fn inspect(&self, _file: &VictoryFile) -> Option<Box<dyn Inspector>> {
+        None
+    }
+
+    /// Called once a file was opened at its source, before it is written.
+    /// Inspectors only finished if the contents were seen in a first pass,
+    /// see [`Middleware::may_skip_destination`].
+    fn on_read(&self, _file: &mut VictoryFile) -> MiddlewareAction {
+        MiddlewareAction::Continue
+    }
+
+    /// Called before a file is read, for every destination it goes to (by
+    /// [`crate::destination::Backend::get_id`]). If `skip_destination` may
+    /// leave the file out at any of them, inspectors see the contents in a
+    /// first pass before anything is written. Otherwise they see them while
+    /// the file is written, so it is read only once.
+    fn may_skip_destination(&self, _file: &VictoryFile, _dest: &str) -> bool {
+        false
+    }
+
+    /// Called once a file was opened, for every destination it goes to.
+    /// Returning a reason leaves the file out at that destination, e.g. as it
+    /// already holds the same contents. Only asked for destinations that
+    /// still have the file.
+    fn skip_destination(&self, _file: &VictoryFile, _dest: &str) -> Option<String> {
+        None
+    }
+
+    /// Called right before a file is written to the destinations, wraps the
+    /// contents on their way to the destinations, e.g. to compress them
+    fn before_write<'a>(
+        &self,
+        _file: &VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        Ok(reader)
+    }
+
+    /// Called after a file was written, with the id of every destination that
+    /// holds it now (written or left out by `skip_destination`)
+    fn after_write(&self, _file: &VictoryFile, _stored: &[String]) {}
+
+    /// Called when a file is read back from a backup, wraps the contents to undo `before_write`
+    fn on_restore<'a>(
+        &self,
+        _file: &VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        Ok(reader)
+    }
+
+    /// State to keep in the checkpoint of an interrupted run
+    fn get_checkpoint(&self) -> Option<serde_yaml::Value> {
+        None
+    }
+
+    /// Restores the state saved by [`Middleware::get_checkpoint`]
+    fn resume(&self, _state: &serde_yaml::Value) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called once every batch of a run was processed
+    fn finish(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Savable middleware stage, see [`MiddlewareChain::from_plan`]
+/// # Variants:
+/// - glob: Include/exclude patterns, applied during discovery
+/// - hash: Hash files as they are read, skipping unchanged files when `skip_unchanged` (default: true)
+/// - compress: Compress contents before they are written
+/// - encrypt: Encrypt contents before they are written
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MiddlewareConfig {
+    Glob(GlobFilterConfig),
+    Hash {
+        #[serde(default = "default_skip_unchanged")]
+        skip_unchanged: bool,
+    },
+    Compress(CompressionConfig),
+    Encrypt(EncryptionConfig),
+}
+
+fn default_skip_unchanged() -> bool {
+    true
+}
+
+impl MiddlewareConfig {
+    /// Stages a new plan starts with
+    pub fn defaults() -> Vec<MiddlewareConfig> {
+        vec![MiddlewareConfig::Hash {
+            skip_unchanged: default_skip_unchanged(),
+        }]
+    }
+
+    pub fn build(&self, plan: &BackupPlan) -> Result<Box<dyn Middleware>, String> {
+        match self {
+            MiddlewareConfig::Glob(config) => Ok(Box::new(GlobFilter::new(config)?)),
+            MiddlewareConfig::Hash { skip_unchanged } => Ok(Box::new(
+                HashFilter::load(plan.get_hash_index_path(), *skip_unchanged)?
+                    .with_transforms(MiddlewareConfig::transforms_fingerprint(&plan.middleware)?),
+            )),
+            MiddlewareConfig::Compress(config) => Ok(Box::new(Compression::new(config)?)),
+            MiddlewareConfig::Encrypt(config) => {
+                debug!("Middleware: Deriving encryption key");
+                Ok(Box::new(Encryption::from_config(config)?))
+            }
+        }
+    }
+
+    /// Whether the stage does anything before files are read
+    pub fn is_discovery_stage(&self) -> bool {
+        matches!(self, MiddlewareConfig::Glob(_))
+    }
+
+    /// Whether the stage changes the contents on their way to the destinations
+    pub fn is_transform(&self) -> bool {
+        matches!(
+            self,
+            MiddlewareConfig::Compress(_) | MiddlewareConfig::Encrypt(_)
+        )
+    }
+
+    /// Hash of the settings of every stage changing the contents, in order,
+    /// empty if none does. Secrets are not part of the settings, so a changed
+    /// passphrase does not change the fingerprint.
+    pub fn transforms_fingerprint(configs: &[MiddlewareConfig]) -> Result<String, String> {
+        let transforms: Vec<&MiddlewareConfig> = configs
+            .iter()
+            .filter(|config| config.is_transform())
+            .collect();
+        if transforms.is_empty() {
+            return Ok(String::new());
+        }
+        match serde_yaml::to_string(&transforms) {
+            Ok(yaml) => Ok(hash_contents(yaml.as_bytes())),
+            Err(err) => Err(format!("Middleware Error: {:?}", err)),
+        }
+    }
+}
+
+/// Ordered list of middleware stages, files go through them in order and
+/// through `on_restore` in reverse order
+#[derive(Default)]
+pub struct MiddlewareChain {
+    stages: Vec<Box<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> MiddlewareChain {
+        MiddlewareChain::default()
+    }
+
+    /// Builds every stage configured in the plan
+    pub fn from_plan(plan: &BackupPlan) -> Result<MiddlewareChain, String> {
+        let mut chain = MiddlewareChain::new();
+        for config in &plan.middleware {
+            chain.push(config.build(plan)?);
+        }
+        Ok(chain)
+    }
+
+    /// Builds only the stages used during discovery, skipping the setup of
+    /// the others (e.g. loading the hash index or deriving keys)
+    pub fn discovery_from_plan(plan: &BackupPlan) -> Result<MiddlewareChain, String> {
+        let mut chain = MiddlewareChain::new();
+        for config in &plan.middleware {
+            if config.is_discovery_stage() {
+                chain.push(config.build(plan)?);
+            }
+        }
+        Ok(chain)
+    }
+
+    pub fn push(&mut self, stage: Box<dyn Middleware>) {
+        debug!("Middleware: Adding stage {:?}", stage.get_name());
+        self.stages.push(stage);
+    }
+
+    pub fn get_length(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Every stage's discovery patterns merged together
+    pub fn get_discovery_filter(&self) -> GlobFilterConfig {
+        self.stages
+            .iter()
+            .filter_map(|stage| stage.get_discovery_filter())
+            .fold(GlobFilterConfig::default(), |merged, filter| {
+                merged.merge(filter)
+            })
+    }
+
+    pub fn on_discover(&self, file: &mut VictoryFile) -> MiddlewareAction {
+        self.apply(file, |stage, file| stage.on_discover(file))
+    }
+
+    /// Inspectors of every stage that wants to see the contents of the file
+    pub fn inspect(&self, file: &VictoryFile) -> Vec<Box<dyn Inspector>> {
+        self.stages
+            .iter()
+            .filter_map(|stage| stage.inspect(file))
+            .collect()
+    }
+
+    pub fn on_read(&self, file: &mut VictoryFile) -> MiddlewareAction {
+        self.apply(file, |stage, file| stage.on_read(file))
+    }
+
+    /// Wraps the contents with every stage in order, marking the file `Error`
+    /// if a stage fails
+    pub fn before_write<'a>(
+        &self,
+        file: &mut VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let mut reader = reader;
+        for stage in &self.stages {
+            reader = match stage.before_write(file, reader) {
+                Ok(reader) => reader,
+                Err(err) => {
+                    let action = MiddlewareAction::Error(err.clone());
+                    Self::mark(stage.as_ref(), file, &action);
+                    return Err(err);
+                }
+            };
+        }
+        Ok(reader)
+    }
+
+    pub fn may_skip_destination(&self, file: &VictoryFile, dest: &str) -> bool {
+        self.stages
+            .iter()
+            .any(|stage| stage.may_skip_destination(file, dest))
+    }
+
+    /// Reason of the first stage leaving the file out at a destination,
+    /// prefixed with the stage's name
+    pub fn skip_destination(&self, file: &VictoryFile, dest: &str) -> Option<String> {
+        self.stages.iter().find_map(|stage| {
+            let reason = stage.skip_destination(file, dest)?;
+            Some(format!("{}: {}", stage.get_name(), reason))
+        })
+    }
+
+    pub fn after_write(&self, file: &VictoryFile, stored: &[String]) {
+        for stage in &self.stages {
+            stage.after_write(file, stored);
+        }
+    }
+
+    /// Undoes `before_write` by wrapping the contents with every stage in reverse order
+    pub fn on_restore<'a>(
+        &self,
+        file: &mut VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let mut reader = reader;
+        for stage in self.stages.iter().rev() {
+            reader = match stage.on_restore(file, reader) {
+                Ok(reader) => reader,
+                Err(err) => {
+                    let action = MiddlewareAction::Error(err.clone());
+                    Self::mark(stage.as_ref(), file, &action);
+                    return Err(err);
+                }
+            };
+        }
+        Ok(reader)
+    }
+
+    /// Runs a hook on every stage in order, stopping at the first stage that
+    /// skips the file or fails, and marks the file with that stage's reason
+    fn apply<F>(&self, file: &mut VictoryFile, hook: F) -> MiddlewareAction
+    where
+        F: Fn(&dyn Middleware, &mut VictoryFile) -> MiddlewareAction,
+    {
+        for stage in &self.stages {
+            let action = hook(stage.as_ref(), file);
+            if action != MiddlewareAction::Continue {
+                Self::mark(stage.as_ref(), file, &action);
+                return action;
+            }
+        }
+        MiddlewareAction::Continue
+    }
+
+    fn mark(stage: &dyn Middleware, file: &mut VictoryFile, action: &MiddlewareAction) {
+        match action {
+            MiddlewareAction::Continue => (),
+            MiddlewareAction::Skip(reason) => {
+                debug!(
+                    "Middleware: {} skipped {:?}: {}",
+                    stage.get_name(),
+                    file.path,
+                    reason
+                );
+                file.mark_skipped(format!("{}: {}", stage.get_name(), reason));
+            }
+            MiddlewareAction::Error(reason) => {
+                warn!(
+                    "Middleware: {} failed on {:?}: {}",
+                    stage.get_name(),
+                    file.path,
+                    reason
+                );
+                file.mark_error(format!("{}: {}", stage.get_name(), reason));
+            }
+        }
+    }
+
+    /// State of every stage to keep in a checkpoint, keyed by stage name
+    pub fn get_checkpoint(&self) -> BTreeMap<String, serde_yaml::Value> {
+        self.stages
+            .iter()
+            .filter_map(|stage| Some((stage.get_name(), stage.get_checkpoint()?)))
+            .collect()
+    }
+
+    pub fn resume(&self, states: &BTreeMap<String, serde_yaml::Value>) -> Result<(), String> {
+        for stage in &self.stages {
+            if let Some(state) = states.get(&stage.get_name()) {
+                stage.resume(state)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        for stage in &self.stages {
+            stage.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod middleware_tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{file::FileState, middleware::zip::CompressionAlgorithm};
+
+    /// Skips files with a given extension and fails files with another
+    struct ExtensionStage {
+        skip: &'static str,
+        fail: &'static str,
+    }
+
+    impl Middleware for ExtensionStage {
+        fn get_name(&self) -> String {
+            "extension".to_string()
+        }
+
+        fn on_read(&self, file: &mut VictoryFile) -> MiddlewareAction {
+            if file.extension == self.skip {
+                return MiddlewareAction::Skip("skipped extension".to_string());
+            }
+            if file.extension == self.fail {
+                return MiddlewareAction::Error("failed extension".to_string());
+            }
+            MiddlewareAction::Continue
+        }
+    }
+
+    /// Maps every byte of the contents, e.g. to add to or xor them
+    struct MapReader<'a, F: Fn(u8) -> u8> {
+        inner: Box<dyn Read + 'a>,
+        map: F,
+    }
+
+    impl<F: Fn(u8) -> u8> Read for MapReader<'_, F> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.inner.read(buf)?;
+            for byte in &mut buf[..read] {
+                *byte = (self.map)(*byte);
+            }
+            Ok(read)
+        }
+    }
+
+    /// Adds a number to every byte before writing and subtracts it on restore
+    struct AddStage(u8);
+
+    impl Middleware for AddStage {
+        fn get_name(&self) -> String {
+            format!("add_{}", self.0)
+        }
+
+        fn before_write<'a>(
+            &self,
+            _file: &VictoryFile,
+            reader: Box<dyn Read + 'a>,
+        ) -> Result<Box<dyn Read + 'a>, String> {
+            let add = self.0;
+            Ok(Box::new(MapReader {
+                inner: reader,
+                map: move |byte: u8| byte.wrapping_add(add),
+            }))
+        }
+
+        fn on_restore<'a>(
+            &self,
+            _file: &VictoryFile,
+            reader: Box<dyn Read + 'a>,
+        ) -> Result<Box<dyn Read + 'a>, String> {
+            let add = self.0;
+            Ok(Box::new(MapReader {
+                inner: reader,
+                map: move |byte: u8| byte.wrapping_sub(add),
+            }))
+        }
+    }
+
+    /// Xors every byte, refusing to restore files named `locked`
+    struct XorStage(u8);
+
+    impl Middleware for XorStage {
+        fn get_name(&self) -> String {
+            "xor".to_string()
+        }
+
+        fn before_write<'a>(
+            &self,
+            _file: &VictoryFile,
+            reader: Box<dyn Read + 'a>,
+        ) -> Result<Box<dyn Read + 'a>, String> {
+            let key = self.0;
+            Ok(Box::new(MapReader {
+                inner: reader,
+                map: move |byte: u8| byte ^ key,
+            }))
+        }
+
+        fn on_restore<'a>(
+            &self,
+            file: &VictoryFile,
+            reader: Box<dyn Read + 'a>,
+        ) -> Result<Box<dyn Read + 'a>, String> {
+            if file.name == "locked" {
+                return Err("locked".to_string());
+            }
+            self.before_write(file, reader)
+        }
+    }
+
+    /// Counts the bytes of every file
+    struct CountInspector(usize);
+
+    impl Inspector for CountInspector {
+        fn update(&mut self, chunk: &[u8]) {
+            self.0 += chunk.len();
+        }
+
+        fn finish(self: Box<Self>, file: &mut VictoryFile) {
+            file.hash = self.0.to_string();
+        }
+    }
+
+    struct CountStage;
+
+    impl Middleware for CountStage {
+        fn get_name(&self) -> String {
+            "count".to_string()
+        }
+
+        fn inspect(&self, _file: &VictoryFile) -> Option<Box<dyn Inspector>> {
+            Some(Box::new(CountInspector(0)))
+        }
+    }
+
+    fn read_file(name: &str) -> VictoryFile {
+        let mut file = VictoryFile::new(&PathBuf::from(name));
+        file.load_contents(b"data".to_vec()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_marks_files_with_reason() {
+        let mut chain = MiddlewareChain::new();
+        chain.push(Box::new(ExtensionStage {
+            skip: "tmp",
+            fail: "bad",
+        }));
+
+        let mut file = read_file("a.tmp");
+        assert!(matches!(
+            chain.on_read(&mut file),
+            MiddlewareAction::Skip(_)
+        ));
+        assert_eq!(file.state, FileState::Skipped);
+        assert_eq!(
+            file.reason,
+            Some("extension: skipped extension".to_string())
+        );
+
+        let mut file = read_file("a.bad");
+        assert!(matches!(
+            chain.on_read(&mut file),
+            MiddlewareAction::Error(_)
+        ));
+        assert_eq!(file.state, FileState::Error);
+        assert!(file.contents.is_none());
+
+        let mut file = read_file("a.txt");
+        assert_eq!(chain.on_read(&mut file), MiddlewareAction::Continue);
+        assert_eq!(file.state, FileState::Read);
+        assert_eq!(file.reason, None);
+    }
+
+    fn read_all(reader: Box<dyn Read + '_>) -> Vec<u8> {
+        let mut reader = reader;
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_restore_in_reverse_order() {
+        let mut chain = MiddlewareChain::new();
+        chain.push(Box::new(AddStage(1)));
+        chain.push(Box::new(XorStage(0xff)));
+
+        let mut file = read_file("a.txt");
+        let data = b"data".to_vec();
+        let stored = read_all(chain.before_write(&mut file, Box::new(&data[..])).unwrap());
+        let expected: Vec<u8> = data.iter().map(|byte| byte.wrapping_add(1) ^ 0xff).collect();
+        assert_eq!(stored, expected);
+
+        let restored = read_all(chain.on_restore(&mut file, Box::new(&stored[..])).unwrap());
+        assert_eq!(restored, data);
+
+        let mut locked = read_file("locked");
+        assert!(chain
+            .on_restore(&mut locked, Box::new(&stored[..]))
+            .is_err());
+        assert_eq!(locked.state, FileState::Error);
+        assert_eq!(locked.reason, Some("xor: locked".to_string()));
+    }
+
+    #[test]
+    fn test_inspectors() {
+        let mut chain = MiddlewareChain::new();
+        chain.push(Box::new(AddStage(1)));
+        chain.push(Box::new(CountStage));
+
+        let mut file = read_file("a.txt");
+        let mut inspectors = chain.inspect(&file);
+        assert_eq!(inspectors.len(), 1);
+        for chunk in [&b"abc"[..], &b"de"[..]] {
+            for inspector in &mut inspectors {
+                inspector.update(chunk);
+            }
+        }
+        for inspector in inspectors {
+            inspector.finish(&mut file);
+        }
+        assert_eq!(file.hash, "5");
+    }
+
+    #[test]
+    fn test_discovery_filter_merges_stages() {
+        let mut chain = MiddlewareChain::new();
+        chain.push(Box::new(
+            GlobFilter::new(&GlobFilterConfig::new(vec![], vec!["target/".to_string()])).unwrap(),
+        ));
+        chain.push(Box::new(
+            GlobFilter::new(&GlobFilterConfig::new(vec!["*.rs".to_string()], vec![])).unwrap(),
+        ));
+        let filter = chain.get_discovery_filter();
+        assert_eq!(filter.include, vec!["*.rs"]);
+        assert_eq!(filter.exclude, vec!["target/"]);
+
+        let mut file = VictoryFile::new(&PathBuf::from("src/main.py"));
+        assert!(matches!(
+            chain.on_discover(&mut file),
+            MiddlewareAction::Skip(_)
+        ));
+    }
+
+    #[test]
+    fn test_config_yaml() {
+        let configs: Vec<MiddlewareConfig> = serde_yaml::from_str(
+            "- kind: glob\n  exclude: [target/]\n- kind: hash\n- kind: compress\n  algorithm: zstd\n",
+        )
+        .unwrap();
+        assert_eq!(
+            configs[0],
+            MiddlewareConfig::Glob(GlobFilterConfig::new(vec![], vec!["target/".to_string()]))
+        );
+        assert_eq!(
+            configs[1],
+            MiddlewareConfig::Hash {
+                skip_unchanged: true
+            }
+        );
+        assert!(matches!(configs[2], MiddlewareConfig::Compress(_)));
+        assert!(configs[0].is_discovery_stage());
+        assert!(!configs[2].is_discovery_stage());
+    }
+
+    #[test]
+    fn test_transforms_fingerprint() {
+        let plain = MiddlewareConfig::defaults();
+        assert_eq!(
+            MiddlewareConfig::transforms_fingerprint(&plain).unwrap(),
+            ""
+        );
+
+        let zstd = CompressionConfig::new(CompressionAlgorithm::Zstd);
+        let mut compressed = plain.clone();
+        compressed.push(MiddlewareConfig::Compress(zstd.clone()));
+        let fingerprint = MiddlewareConfig::transforms_fingerprint(&compressed).unwrap();
+        assert_eq!(fingerprint.len(), 64);
+
+        // Only stages changing the contents count
+        let mut filtered = compressed.clone();
+        filtered.insert(0, MiddlewareConfig::Glob(GlobFilterConfig::default()));
+        assert_eq!(
+            MiddlewareConfig::transforms_fingerprint(&filtered).unwrap(),
+            fingerprint
+        );
+
+        let mut deflate = compressed.clone();
+        deflate[1] =
+            MiddlewareConfig::Compress(CompressionConfig::new(CompressionAlgorithm::Deflate));
+        assert_ne!(
+            MiddlewareConfig::transforms_fingerprint(&deflate).unwrap(),
+            fingerprint
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use std::io::{Cursor, Read};
+
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::file::VictoryFile;
+
+use super::Middleware;
+
+/// Magic bytes every file written with compression enabled starts with
+pub const COMPRESS_MAGIC: &[u8; 4] = b"VZIP";
+const HEADER_LEN: usize = COMPRESS_MAGIC.len() + 1;
+
+/// Extensions of formats that are already compressed, stored as is by default
+pub const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "m4a", "mkv", "mov",
+    "mp3", "mp4", "ogg", "png", "rar", "tgz", "webm", "webp", "xz", "zip", "zst",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionAlgorithm {
+    /// Stored without compression
+    None,
+    Zstd,
+    /// Raw deflate, for compatibility with zip tooling
+    Deflate,
+}
+
+impl CompressionAlgorithm {
+    fn to_byte(self) -> u8 {
+        match self {
+            CompressionAlgorithm::None => 0,
+            CompressionAlgorithm::Zstd => 1,
+            CompressionAlgorithm::Deflate => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<CompressionAlgorithm, String> {
+        match byte {
+            0 => Ok(CompressionAlgorithm::None),
+            1 => Ok(CompressionAlgorithm::Zstd),
+            2 => Ok(CompressionAlgorithm::Deflate),
+            other => Err(format!("Decompression Error: unknown algorithm {}", other)),
+        }
+    }
+}
+
+/// Savable compression settings
+/// # Fields:
+/// - algorithm: `zstd` or `deflate`
+/// - level: Compression level, zstd 1-22 (default 3) or deflate 0-9 (default 6)
+/// - skip_extensions: Extensions stored without compression (default: common media and archive formats)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    pub algorithm: CompressionAlgorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+    #[serde(default = "default_skip_extensions")]
+    pub skip_extensions: Vec<String>,
+}
+
+fn default_skip_extensions() -> Vec<String> {
+    COMPRESSED_EXTENSIONS
+        .iter()
+        .map(|ext| ext.to_string())
+        .collect()
+}
+
+impl CompressionConfig {
+    pub fn new(algorithm: CompressionAlgorithm) -> CompressionConfig {
+        CompressionConfig {
+            algorithm,
+            level: None,
+            skip_extensions: default_skip_extensions(),
+        }
+    }
+}
+
+/// Per file compression of contents before they are written to destinations
+///
+/// Every file gets a small header naming the algorithm used, so restore can
+/// decompress without knowing how the backup was configured. Files with a
+/// skipped extension are stored with the `none` algorithm. Files without the
+/// header, stored before compression was enabled, are restored as they are.
+pub struct Compression {
+    algorithm: CompressionAlgorithm,
+    level: i32,
+    skip_extensions: Vec<String>,
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Result<Compression, String> {
+        let (level, range) = match config.algorithm {
+            CompressionAlgorithm::None => (0, 0..=0),
+            CompressionAlgorithm::Zstd => {
+                (config.level.unwrap_or(3), zstd::compression_level_range())
+            }
+            CompressionAlgorithm::Deflate => (config.level.unwrap_or(6), 0..=9),
+        };
+        if !range.contains(&level) {
+            return Err(format!(
+                "Compression Error: level {} is out of range {:?} for {:?}",
+                level, range, config.algorithm
+            ));
+        }
+        Ok(Compression {
+            algorithm: config.algorithm,
+            level,
+            skip_extensions: config
+                .skip_extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .collect(),
+        })
+    }
+
+    fn algorithm_for(&self, extension: &str) -> CompressionAlgorithm {
+        if self.skip_extensions.contains(&extension.to_lowercase()) {
+            return CompressionAlgorithm::None;
+        }
+        self.algorithm
+    }
+
+    /// Wraps a reader so it yields the compressed form of its contents, header included
+    pub fn compress_reader<'a>(
+        &self,
+        extension: &str,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let algorithm = self.algorithm_for(extension);
+        let mut header = COMPRESS_MAGIC.to_vec();
+        header.push(algorithm.to_byte());
+        let body: Box<dyn Read + 'a> = match algorithm {
+            CompressionAlgorithm::None => reader,
+            CompressionAlgorithm::Zstd => match zstd::stream::read::Encoder::new(reader, self.level)
+            {
+                Ok(encoder) => Box::new(encoder),
+                Err(err) => return Err(format!("Compression Error: {:?}", err)),
+            },
+            CompressionAlgorithm::Deflate => Box::new(DeflateEncoder::new(
+                reader,
+                flate2::Compression::new(self.level as u32),
+            )),
+        };
+        Ok(Box::new(Cursor::new(header).chain(body)))
+    }
+
+    /// Wraps a reader over compressed contents so it yields the original contents
+    pub fn decompress_reader<'a>(
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let mut reader = reader;
+        let mut header = [0u8; HEADER_LEN];
+        if reader.read_exact(&mut header).is_err() || &header[..COMPRESS_MAGIC.len()] != COMPRESS_MAGIC
+        {
+            return Err("Decompression Error: missing compression header".to_string());
+        }
+        match CompressionAlgorithm::from_byte(header[COMPRESS_MAGIC.len()])? {
+            CompressionAlgorithm::None => Ok(reader),
+            CompressionAlgorithm::Zstd => match zstd::stream::read::Decoder::new(reader) {
+                Ok(decoder) => Ok(Box::new(decoder)),
+                Err(err) => Err(format!("Decompression Error: {:?}", err)),
+            },
+            CompressionAlgorithm::Deflate => Ok(Box::new(DeflateDecoder::new(reader))),
+        }
+    }
+
+    pub fn compress(&self, extension: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut compressed = Vec::with_capacity(HEADER_LEN + data.len() / 2);
+        match self
+            .compress_reader(extension, Box::new(data))?
+            .read_to_end(&mut compressed)
+        {
+            Ok(_) => Ok(compressed),
+            Err(err) => Err(format!("Compression Error: {:?}", err)),
+        }
+    }
+
+    pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut decompressed = Vec::with_capacity(data.len() * 2);
+        match Compression::decompress_reader(Box::new(data))?.read_to_end(&mut decompressed) {
+            Ok(_) => Ok(decompressed),
+            Err(err) => Err(format!("Decompression Error: {:?}", err)),
+        }
+    }
+}
+
+impl Middleware for Compression {
+    fn get_name(&self) -> String {
+        "compress".to_string()
+    }
+
+    fn before_write<'a>(
+        &self,
+        file: &VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        debug!("Compression: Compressing {:?}", file.path);
+        self.compress_reader(&file.extension, reader)
+    }
+
+    fn on_restore<'a>(
+        &self,
+        file: &VictoryFile,
+        reader: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let mut reader = reader;
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        if let Err(err) = reader
+            .by_ref()
+            .take(HEADER_LEN as u64)
+            .read_to_end(&mut header)
+        {
+            return Err(format!("Decompression Error: {:?}", err));
+        }
+        let compressed = header.starts_with(COMPRESS_MAGIC);
+        let reader = Box::new(Cursor::new(header).chain(reader));
+        match compressed {
+            true => Compression::decompress_reader(reader),
+            false => {
+                debug!("Compression: {:?} was stored uncompressed", file.path);
+                Ok(reader)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod zip_tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn text(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n"
+            .iter()
+            .cycle()
+            .take(len)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for algorithm in [
+            CompressionAlgorithm::Zstd,
+            CompressionAlgorithm::Deflate,
+            CompressionAlgorithm::None,
+        ] {
+            let compression = Compression::new(&CompressionConfig::new(algorithm)).unwrap();
+            for len in [0, 1, 100_000] {
+                let data = text(len);
+                let compressed = compression.compress("txt", &data).unwrap();
+                assert_eq!(&compressed[..4], COMPRESS_MAGIC);
+                assert_eq!(Compression::decompress(&compressed).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compresses_text() {
+        let mut config = CompressionConfig::new(CompressionAlgorithm::Zstd);
+        config.level = Some(19);
+        let compression = Compression::new(&config).unwrap();
+
+        let compressed = compression.compress("txt", &text(100_000)).unwrap();
+        assert!(compressed.len() < 10_000);
+        assert_eq!(Compression::decompress(&compressed).unwrap(), text(100_000));
+    }
+
+    #[test]
+    fn test_stream_in_small_reads() {
+        let compression =
+            Compression::new(&CompressionConfig::new(CompressionAlgorithm::Deflate)).unwrap();
+        let file = VictoryFile::new(&PathBuf::from("notes.txt"));
+        let data = text(300_000);
+
+        let mut stored = Vec::new();
+        let mut reader = compression.before_write(&file, Box::new(&data[..])).unwrap();
+        let mut buf = [0u8; 7];
+        loop {
+            let read = reader.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            stored.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(&stored[..4], COMPRESS_MAGIC);
+
+        let mut restored = Vec::new();
+        compression
+            .on_restore(&file, Box::new(&stored[..]))
+            .unwrap()
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_restores_uncompressed() {
+        let compression =
+            Compression::new(&CompressionConfig::new(CompressionAlgorithm::Zstd)).unwrap();
+        let file = VictoryFile::new(&PathBuf::from("notes.txt"));
+        // Stored before compression was enabled, shorter than a header too
+        for stored in [&text(10_000)[..], b"VZ", b""] {
+            let mut restored = Vec::new();
+            compression
+                .on_restore(&file, Box::new(stored))
+                .unwrap()
+                .read_to_end(&mut restored)
+                .unwrap();
+            assert_eq!(restored, stored);
+        }
+    }
+
+    #[test]
+    fn test_skips_compressed_extensions() {
+        let compression =
+            Compression::new(&CompressionConfig::new(CompressionAlgorithm::Deflate)).unwrap();
+
+        let compressed = compression.compress("JPG", &text(10_000)).unwrap();
+        assert_eq!(compressed.len(), 10_000 + HEADER_LEN);
+        assert_eq!(compressed[4], 0);
+        assert_eq!(Compression::decompress(&compressed).unwrap(), text(10_000));
+    }
+
+    #[test]
+    fn test_invalid_level() {
+        let mut config = CompressionConfig::new(CompressionAlgorithm::Deflate);
+        config.level = Some(12);
+        assert!(Compression::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        assert!(Compression::decompress(b"plain").is_err());
+        assert!(Compression::decompress(b"VZIP\x07data").is_err());
+        assert!(Compression::decompress(b"VZIP\x01not zstd").is_err());
+
+        assert!(Compression::decompress_reader(Box::new(&b"VZ"[..])).is_err());
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config: CompressionConfig = serde_yaml::from_str("algorithm: zstd\n").unwrap();
+        assert_eq!(config, CompressionConfig::new(CompressionAlgorithm::Zstd));
+        assert!(config.skip_extensions.contains(&"mp4".to_string()));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    io::Write,
+    fmt,
     path::{Path, PathBuf},
 };
 
@@ -7,74 +7,140 @@ use log::*;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    batch::FileBatch,
-    destination::{filesystem_dest::FileSystemDestination, Destination},
+    destination::{
+        registry::{BackendConfig, BackendRegistry},
+        Sink, Source,
+    },
+    middleware::MiddlewareConfig,
+    utils::file_utils::{file_resolve, file_write_atomic},
+    workers::WorkerConfig,
 };
-use num_format::{Locale, ToFormattedString};
 
 /// A backup plan is a collection of sources and batches
 ///
-
+/// Sources are only ever read from and destinations are only ever written to,
+/// so each side only needs to implement the matching half of a backend.
 pub struct BackupPlan {
     pub name: String,
     pub path: PathBuf,
-    pub sources: Vec<Box<dyn Destination>>,
-    pub destinations: Vec<Box<dyn Destination>>,
+    pub sources: Vec<Box<dyn Source>>,
+    pub destinations: Vec<Box<dyn Sink>>,
     pub batches: Vec<String>,
+    /// Middleware stages every file goes through, in order
+    pub middleware: Vec<MiddlewareConfig>,
+    /// Threads and limits of the worker pool processing the batches
+    pub workers: WorkerConfig,
 }
 
 /// Savable version of the BackupPlan
 /// # Fields:
 /// - name: The name of the backup plan
-/// - sources: The sources of the backup plan
+/// - sources: Backend configs of the sources of the backup plan
 /// - batches: The batches of the backup plan
+/// - destinations: Backend configs of the destinations of the backup plan
+/// - middleware: Ordered middleware stages, e.g. glob filters, hashing, compression and encryption
+///   (default: hashing, skipping unchanged files)
+/// - workers: Threads processing batches and the bytes and open files they may hold at once
+///   (default: one thread per CPU, 256 MiB, 256 files)
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct BackupPlanSave {
     pub name: String,
-    pub path: String,
-    pub sources: Vec<String>,
+    #[serde(with = "crate::utils::os_path")]
+    pub path: PathBuf,
+    pub sources: Vec<BackendConfig>,
     pub batches: Vec<String>,
-    pub destinations: Vec<String>,
+    pub destinations: Vec<BackendConfig>,
+    #[serde(default = "MiddlewareConfig::defaults")]
+    pub middleware: Vec<MiddlewareConfig>,
+    #[serde(default)]
+    pub workers: WorkerConfig,
+}
+
+/// A source sharing folders with a path the plan writes to, e.g. a destination
+/// inside the folder being backed up. Found by [`BackupPlan::find_overlaps`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathOverlap {
+    /// Written inside a source, at `path` relative to its root
+    Nested {
+        source: String,
+        target: String,
+        path: PathBuf,
+    },
+    /// A source is, or is inside of, a path written to
+    Contains { source: String, target: String },
 }
+
+impl fmt::Display for PathOverlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathOverlap::Nested {
+                source,
+                target,
+                path,
+            } => write!(f, "{} is inside source {:?} at {:?}", target, source, path),
+            PathOverlap::Contains { source, target } => {
+                write!(f, "source {:?} is inside {}", source, target)
+            }
+        }
+    }
+}
+
 impl BackupPlan {
     pub fn new(name: String) -> BackupPlan {
         BackupPlan {
-            name: name,
+            name,
             sources: Vec::new(),
             batches: Vec::new(),
             destinations: Vec::new(),
             path: PathBuf::new(),
+            middleware: MiddlewareConfig::defaults(),
+            workers: WorkerConfig::default(),
         }
     }
 
     /// Generates a new backup plan from a saved backup plan (loaded from a file)
+    /// using the backends built into the crate
     ///
     /// # Arguments
     ///
     /// * `plan` - The saved backup plan
-    pub fn from_saved(plan: BackupPlanSave) -> BackupPlan {
+    pub fn from_saved(plan: BackupPlanSave) -> Result<BackupPlan, String> {
+        BackupPlan::from_saved_with_registry(plan, &BackendRegistry::default())
+    }
+
+    /// Generates a new backup plan from a saved backup plan, building each
+    /// source and destination with the given registry
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The saved backup plan
+    /// * `registry` - Registry used to build backends from their configs
+    pub fn from_saved_with_registry(
+        plan: BackupPlanSave,
+        registry: &BackendRegistry,
+    ) -> Result<BackupPlan, String> {
         let mut sources = Vec::new();
-        //TODO: Dynamic way (enums?) to store what destinations are used
-        for source in plan.sources {
-            sources.push(Box::new(FileSystemDestination::new(source)) as Box<dyn Destination>);
+        for source in &plan.sources {
+            sources.push(registry.build_source(source)?);
         }
         let mut destinations = Vec::new();
-        for destination in plan.destinations {
-            destinations
-                .push(Box::new(FileSystemDestination::new(destination)) as Box<dyn Destination>);
+        for destination in &plan.destinations {
+            destinations.push(registry.build_sink(destination)?);
         }
 
         let mut batches = Vec::new();
         for batch in plan.batches {
             batches.push(batch);
         }
-        BackupPlan {
+        Ok(BackupPlan {
             name: plan.name,
-            sources: sources,
-            batches: batches,
-            destinations: destinations,
-            path: PathBuf::from(plan.path),
-        }
+            sources,
+            batches,
+            destinations,
+            path: plan.path,
+            middleware: plan.middleware,
+            workers: plan.workers,
+        })
     }
 
     /// Generates a saved backup plan from a backup plan
@@ -85,12 +151,12 @@ impl BackupPlan {
     pub fn get_saved(&self) -> BackupPlanSave {
         let mut sources = Vec::new();
         for source in &self.sources {
-            sources.push(source.get_name());
+            sources.push(source.get_config());
         }
 
         let mut destinations = Vec::new();
         for destination in &self.destinations {
-            destinations.push(destination.get_name());
+            destinations.push(destination.get_config());
         }
 
         let mut batches = Vec::new();
@@ -99,21 +165,28 @@ impl BackupPlan {
         }
         BackupPlanSave {
             name: self.name.clone(),
-            sources: sources,
-            batches: batches,
-            path: self.path.to_str().unwrap().to_string(),
-            destinations: destinations,
+            sources,
+            batches,
+            path: self.path.clone(),
+            destinations,
+            middleware: self.middleware.clone(),
+            workers: self.workers.clone(),
         }
     }
 
-    pub fn add_source(&mut self, source: Box<dyn Destination>) {
+    pub fn add_source(&mut self, source: Box<dyn Source>) {
         self.sources.push(source);
     }
 
-    pub fn add_destination(&mut self, destination: Box<dyn Destination>) {
+    pub fn add_destination(&mut self, destination: Box<dyn Sink>) {
         self.destinations.push(destination);
     }
 
+    /// Appends a stage to the end of the middleware chain
+    pub fn add_middleware(&mut self, middleware: MiddlewareConfig) {
+        self.middleware.push(middleware);
+    }
+
     pub fn save_plan(&mut self, path: &PathBuf) -> Result<usize, String> {
         // Save path minus the file name
 
@@ -126,15 +199,15 @@ impl BackupPlan {
         }
 
         self.path = path.to_path_buf();
-        let mut file_path: PathBuf = path.clone();
-        file_path.push(format!("{}.yaml", self.name));
+        BackupPlan::write_saved(&self.get_saved(), path)
+    }
 
-        let mut file = match std::fs::File::create(file_path) {
-            Ok(file) => file,
-            Err(err) => return Err(format!("Error: {:?}", err)),
-        };
-        let yaml = serde_yaml::to_string(&self.get_saved()).expect("Error serializing plan");
-        match file.write_all(yaml.as_bytes()) {
+    /// Writes a saved plan to `<path>/<name>.yaml` through a temp file, so an
+    /// interruption leaves the previous plan in place. The folder must exist.
+    pub fn write_saved(plan: &BackupPlanSave, path: &Path) -> Result<usize, String> {
+        let file_path = path.join(format!("{}.yaml", plan.name));
+        let yaml = serde_yaml::to_string(plan).expect("Error serializing plan");
+        match file_write_atomic(&file_path, yaml.as_bytes()) {
             Ok(_) => Ok(yaml.len()),
             Err(err) => Err(format!("plan_save_error: {:?}", err)),
         }
@@ -152,133 +225,64 @@ impl BackupPlan {
         Ok(plan_save)
     }
 
-    pub fn discover(&mut self, batch_size: u64, save_path: String) -> Result<(), String> {
-        // Store start time
-        let total_start_time = std::time::Instant::now();
-
-        let mut total_files = 0;
-        let mut batch_idx = 0;
-        //TODO: Multithread this
-        for source in &mut self.sources {
-            //TODO: Make ID also show destintation, such as plan_dest_batch..
-            loop {
-                let batch_start_time = std::time::Instant::now();
-                let mut batch =
-                    FileBatch::new(self.name.to_string() + "_" + batch_idx.to_string().as_str());
-                let files = match source.list_files_next(batch_size) {
-                    Ok(files) => files,
-                    Err(err) => {
-                        error!("list_files_next ERROR: {:?}", err);
-                        Vec::new()
-                    }
-                };
-                if files.len() == 0 {
-                    break;
-                }
-                batch.add_files(files);
-                let batch_end_time = std::time::Instant::now();
-                batch_idx += 1;
-                total_files += batch.get_length();
-
-                self.batches.push(batch.get_name());
-
-                let batch_path = Path::new(save_path.as_str())
-                    .join(batch.get_name().to_string() + ".vbak_batch");
-                // Save batch
-                let save_size = match batch.save_batch(batch_path) {
-                    Ok(res) => res,
-                    Err(err) => {
-                        error!("save_batch ERROR: {:?}", err);
-                        0
-                    }
-                };
-                let batch_save_time = std::time::Instant::now();
-
-                debug!(
-                    "Batch {}:
-                    \t- Length: {}
-                    \t- Disk size: {} kb
-                    \t- Time to discover: {:.2}ms
-                    \t- Time to save: {:.2}ms
-                    \t- Path: {:?}",
-                    batch.get_name(),
-                    (batch.get_length() as u64).to_formatted_string(&Locale::en),
-                    save_size / 1024,
-                    batch_end_time.duration_since(batch_start_time).as_micros() as f64 / 1000.,
-                    batch_save_time.duration_since(batch_end_time).as_micros() as f64 / 1000.,
-                    save_path.clone()
-                );
-            }
-        }
-
-        let total_end_time = std::time::Instant::now();
-
-        info!(
-            "Total time to discover {} batches with {} files: {}ms",
-            batch_idx,
-            total_files.to_formatted_string(&Locale::en),
-            total_end_time.duration_since(total_start_time).as_millis()
-        );
-
-        Ok(())
+    /// Where the hashes of the last run are kept, next to the plan
+    pub fn get_hash_index_path(&self) -> PathBuf {
+        self.path.join(format!("{}.vhashes", self.name))
     }
-    fn process_batch(&self, batch_name: String) {
-        let plan_path = self.path.clone();
-        let batch_start_time = std::time::Instant::now();
-        let batch_path = plan_path.join(batch_name.to_string() + ".vbak_batch");
 
-        info!("Loading batch {:?}", batch_path);
+    /// Where the progress of an interrupted run is kept, next to the plan
+    pub fn get_checkpoint_path(&self) -> PathBuf {
+        self.path.join(format!("{}.vcheckpoint", self.name))
+    }
 
-        let mut batch = match FileBatch::load_batch(batch_path) {
-            Ok(batch) => batch,
-            Err(err) => {
-                error!("Error loading batch: {:?}", err);
-                return;
+    /// Finds sources overlapping with a destination or with the folder the plan
+    /// keeps its metadata (batches, hashes, checkpoints) in. Paths are compared
+    /// once symlinks are resolved. Backends not stored locally never overlap.
+    pub fn find_overlaps(&self) -> Result<Vec<PathOverlap>, String> {
+        // Backing up into a parent of the source writes into the source itself,
+        // the metadata folder only ever gets its own files
+        let mut targets = Vec::new();
+        for destination in &self.destinations {
+            if let Some(path) = destination.get_local_path() {
+                let target = format!("destination {:?}", destination.get_name());
+                targets.push((target, file_resolve(&path)?, true));
             }
-        };
-
-        let mut writen = 0;
-        for file in batch.get_files() {
-            // Read file from source
+        }
+        if self.path != Path::new("") {
+            let target = format!("plan metadata {:?}", self.path);
+            targets.push((target, file_resolve(&self.path)?, false));
+        }
 
-            match self.sources[0].read_file(file) {
-                Ok(file_contents) => file_contents,
-                Err(err) => {
-                    error!("Error reading file {:?}: {:?}", file.name, err);
-                    continue;
-                }
+        let mut overlaps = Vec::new();
+        for source in &self.sources {
+            let source_path = match source.get_local_path() {
+                Some(path) => file_resolve(&path)?,
+                None => continue,
             };
-
-            // replace name by replacing source path with destination path
-            //file.path = file.path.replace(self.sources[0].get_name().as_str(), self.destinations[0].get_name().as_str());
-            //debug!("Writing file {:?}", file.path);
-
-            match self.destinations[0].write_file(file) {
-                Ok(_) => {
-                    writen += 1;
+            for (target, target_path, written_into) in &targets {
+                let (source, target) = (source.get_id(), target.clone());
+                if *target_path == source_path {
+                    overlaps.push(PathOverlap::Contains { source, target });
+                } else if let Ok(path) = target_path.strip_prefix(&source_path) {
+                    let path = path.to_path_buf();
+                    overlaps.push(PathOverlap::Nested {
+                        source,
+                        target,
+                        path,
+                    });
+                } else if *written_into && source_path.starts_with(target_path) {
+                    overlaps.push(PathOverlap::Contains { source, target });
                 }
-                Err(err) => {
-                    error!("Error writing file {:?}: {:?}", file.path, err);
-                    continue;
-                }
-            };
+            }
         }
-
-        info!(
-            "Wrote {} files in {:.2}s",
-            writen,
-            batch_start_time.elapsed().as_secs_f64()
-        );
+        Ok(overlaps)
     }
-    // 1. Load Batch
-    // 2. Read file from batch using destination
-    // 3. Read file contents
-    // 4. Write file contents to destination
-    pub fn run(&mut self) {
-        info!("Running backup plan {}", self.name);
-        let _plan_path = self.path.clone();
-        for batch_name in &self.batches {
-            self.process_batch(batch_name.to_string());
-        }
+
+    /// Finds the source files and batches were discovered from by its id
+    pub fn get_source(&self, id: &str) -> Option<&dyn Source> {
+        self.sources
+            .iter()
+            .find(|source| source.get_id() == id)
+            .map(|source| source.as_ref())
     }
 }
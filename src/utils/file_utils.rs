@@ -1,4 +1,4 @@
-use std::{path::PathBuf, io::Write};
+use std::{path::{Path, PathBuf}, io::Write};
 
 use log::debug;
 use walkdir::WalkDir;
@@ -53,6 +53,71 @@ pub fn file_clear_test_dirs() -> PathBuf{
 }
 
 
+/// Turns a path or name into something usable as a single path component,
+/// e.g. `/data/photos` becomes `data_photos`
+pub fn file_safe_name(name: &str) -> String{
+    let safe: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+    let safe = safe.trim_matches(|c| c == '_' || c == '.');
+    if safe.is_empty(){
+        return "root".to_string();
+    }
+    safe.to_string()
+}
+
+/// Absolute path with every symlink resolved, as far as the path exists.
+/// Folders that are not created yet are appended as given, so two
+/// resolved paths can be compared even before a first run.
+pub fn file_resolve(path: &Path) -> Result<PathBuf, String>{
+    let absolute = match std::path::absolute(path){
+        Ok(absolute) => absolute,
+        Err(err) => return Err(format!("Error: {:?}", err)),
+    };
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    loop{
+        if let Ok(resolved) = existing.canonicalize(){
+            return Ok(missing.iter().rev().fold(resolved, |resolved, name| resolved.join(name)));
+        }
+        match (existing.parent(), existing.file_name()){
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return Ok(absolute),
+        }
+    }
+}
+
+/// Replaces a file with new contents without ever leaving a partial file behind.
+/// The contents go to a temp file next to it, synced to disk, then renamed over it.
+pub fn file_write_atomic(path: &Path, contents: &[u8]) -> Result<(), String>{
+    let parent = match path.parent(){
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Err(err) = std::fs::create_dir_all(parent){
+        return Err(format!("write_atomic Error: {:?}", err));
+    }
+    let mut temp = match tempfile::Builder::new().prefix(".vtmp_").tempfile_in(parent){
+        Ok(temp) => temp,
+        Err(err) => return Err(format!("write_atomic Error: {:?}", err)),
+    };
+    if let Err(err) = temp.write_all(contents).and_then(|_| temp.as_file().sync_all()){
+        return Err(format!("write_atomic Error: {:?}", err));
+    }
+    if let Err(err) = temp.persist(path){
+        return Err(format!("write_atomic Error: {:?}", err.error));
+    }
+    // Makes the rename itself survive a crash
+    match std::fs::File::open(parent).and_then(|dir| dir.sync_all()){
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("write_atomic Error: {:?}", err)),
+    }
+}
+
 pub fn file_cwd() -> String{
     let cwd = PathBuf::from("./");
     cwd.to_str().unwrap().to_string()
@@ -88,6 +153,39 @@ mod file_utils_tests{
     use log::info;
 
 
+    #[test]
+    fn test_file_safe_name(){
+        assert_eq!(super::file_safe_name("/data/photos"), "data_photos");
+        assert_eq!(super::file_safe_name("./src/destination"), "src_destination");
+        assert_eq!(super::file_safe_name("C:\\Users\\alex"), "C__Users_alex");
+        assert_eq!(super::file_safe_name("./"), "root");
+    }
+
+    #[test]
+    fn test_file_resolve(){
+        let path = super::file_test_dir("test_file_resolve".to_string());
+        std::fs::create_dir_all(path.join("real")).unwrap();
+        std::os::unix::fs::symlink("real", path.join("linked")).unwrap_or(());
+        let real = path.join("real").canonicalize().unwrap();
+        assert_eq!(super::file_resolve(&path.join("linked")).unwrap(), real);
+        assert_eq!(
+            super::file_resolve(&path.join("linked/../real/not/yet")).unwrap(),
+            real.join("not").join("yet")
+        );
+    }
+
+    #[test]
+    fn test_file_write_atomic(){
+        let path = super::file_test_dir("test_file_write_atomic".to_string());
+        let file = path.join("nested").join("index.yaml");
+        super::file_write_atomic(&file, b"first").unwrap();
+        super::file_write_atomic(&file, b"second").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"second");
+        // No temp file is left behind
+        assert_eq!(super::file_files_in_dir(file.parent().unwrap().to_path_buf()).unwrap().len(), 2);
+        super::file_remove_all(&path).unwrap();
+    }
+
     #[test]
     fn test_dir(){
         let path = super::file_test_dir("test_dir".to_string());
@@ -105,8 +203,8 @@ mod file_utils_tests{
 
         // Load the file and check the contents
         let contents = std::fs::read(path.clone()).unwrap();
-        for i in 0..1000{
-            assert_eq!(contents[i], (i + i % 255) as u8);
+        for (i, byte) in contents.iter().enumerate(){
+            assert_eq!(*byte, (i + i % 255) as u8);
         }
 
         // Remove the file
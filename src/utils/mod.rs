@@ -1 +1,2 @@
-pub mod file_utils;
\ No newline at end of file
+pub mod file_utils;
+pub mod os_path;
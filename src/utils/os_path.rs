@@ -0,0 +1,131 @@
+//! Lossless (de)serialization of paths as the bytes the OS gave them
+//!
+//! Serde's own `PathBuf` impl fails on names that are not UTF-8, e.g. Latin-1
+//! names from an old NAS. With `#[serde(with = "crate::utils::os_path")]`,
+//! UTF-8 paths are still stored as plain strings, so existing batches and
+//! plans keep loading, and any other path is stored as its raw bytes.
+
+use std::{
+    ffi::OsString,
+    fmt,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserializer, Serializer,
+};
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    let bytes = path.as_os_str().as_bytes();
+    match (serializer.is_human_readable(), path.to_str()) {
+        (true, Some(path)) => serializer.serialize_str(path),
+        // Text formats like YAML have no byte strings, a list of bytes instead
+        (true, None) => serializer.collect_seq(bytes),
+        (false, _) => serializer.serialize_bytes(bytes),
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    match deserializer.is_human_readable() {
+        true => deserializer.deserialize_any(OsPathVisitor),
+        false => deserializer.deserialize_byte_buf(OsPathVisitor),
+    }
+}
+
+struct OsPathVisitor;
+
+impl<'de> Visitor<'de> for OsPathVisitor {
+    type Value = PathBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a path, as a string or its bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, path: &str) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(path))
+    }
+
+    fn visit_bytes<E: de::Error>(self, path: &[u8]) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(OsString::from_vec(path.to_vec())))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, path: Vec<u8>) -> Result<PathBuf, E> {
+        Ok(PathBuf::from(OsString::from_vec(path)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+        let mut path = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            path.push(byte);
+        }
+        Ok(PathBuf::from(OsString::from_vec(path)))
+    }
+}
+
+/// Readable and lossless text form of a path, for logs and as a key in
+/// indexes. Bytes that are not UTF-8 are escaped as `\xNN` and backslashes
+/// as `\\`, so no two paths get the same text.
+pub fn escaped(path: &Path) -> String {
+    let mut escaped = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        escaped.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod os_path_tests {
+    use std::ffi::OsStr;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Named {
+        #[serde(with = "super")]
+        path: PathBuf,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let latin1 = Named {
+            path: PathBuf::from(OsStr::from_bytes(b"photos/caf\xe9.jpg")),
+        };
+        let yaml = serde_yaml::to_string(&latin1).unwrap();
+        assert_eq!(serde_yaml::from_str::<Named>(&yaml).unwrap(), latin1);
+        let binary = bincode::serialize(&latin1).unwrap();
+        assert_eq!(bincode::deserialize::<Named>(&binary).unwrap(), latin1);
+
+        // UTF-8 paths stay plain strings
+        let utf8 = Named {
+            path: PathBuf::from("photos/café.jpg"),
+        };
+        let yaml = serde_yaml::to_string(&utf8).unwrap();
+        assert_eq!(yaml, "path: photos/café.jpg\n");
+        assert_eq!(serde_yaml::from_str::<Named>(&yaml).unwrap(), utf8);
+        let binary = bincode::serialize(&utf8).unwrap();
+        assert_eq!(bincode::deserialize::<Named>(&binary).unwrap(), utf8);
+    }
+
+    #[test]
+    fn test_escaped() {
+        assert_eq!(escaped(Path::new("photos/café.jpg")), "photos/café.jpg");
+        assert_eq!(
+            escaped(Path::new(OsStr::from_bytes(b"photos/caf\xe9.jpg"))),
+            "photos/caf\\xE9.jpg"
+        );
+        // A name spelling out an escape is not taken for the byte
+        let spelled = escaped(Path::new("photos/caf\\xE9.jpg"));
+        assert_eq!(spelled, "photos/caf\\\\xE9.jpg");
+        assert_ne!(
+            spelled,
+            escaped(Path::new(OsStr::from_bytes(b"photos/caf\xe9.jpg")))
+        );
+    }
+}
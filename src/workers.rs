@@ -0,0 +1,392 @@
+//! Bounded pool of threads processing the files of a run
+//!
+//! Workers take files off a shared [`WorkQueue`] in the order they were
+//! discovered. Each file holds a share of the pool's [`Budget`]s while it is
+//! processed, so the bytes and open files of a run stay bounded no matter how
+//! many threads there are.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::{Condvar, Mutex, MutexGuard},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Settings of the worker pool processing batches during a run
+/// # Fields:
+/// - threads: Threads reading and writing files at once (default: 0, one per CPU)
+/// - max_in_flight_bytes: Bytes held in memory by the files being processed at once: a chunk
+///   read from the source, plus what each destination buffers before writing (up to the
+///   whole file for some). 0 for no limit (default: 256 MiB). A larger file is processed on its own.
+/// - max_open_files: Files open at sources and destinations at once, 0 for no limit (default: 256)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub threads: usize,
+    pub max_in_flight_bytes: usize,
+    pub max_open_files: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            threads: 0,
+            max_in_flight_bytes: 256 * 1024 * 1024,
+            max_open_files: 256,
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub fn new() -> WorkerConfig {
+        WorkerConfig::default()
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> WorkerConfig {
+        self.threads = threads;
+        self
+    }
+
+    pub fn with_max_in_flight_bytes(mut self, max_in_flight_bytes: usize) -> WorkerConfig {
+        self.max_in_flight_bytes = max_in_flight_bytes;
+        self
+    }
+
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> WorkerConfig {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Threads to start, one per CPU unless set
+    pub fn get_threads(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            threads => threads,
+        }
+    }
+}
+
+/// A limited amount of something (bytes, open files) shared by the workers
+///
+/// Workers wait in [`Budget::acquire`] until enough was released by the
+/// others. Asking for more than the limit waits for the whole budget instead,
+/// so a single large file still gets processed. Workers holding more than one
+/// budget must always acquire them in the same order.
+pub struct Budget {
+    limit: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Share of a [`Budget`], handed back when dropped
+pub struct BudgetPermit<'a> {
+    budget: &'a Budget,
+    amount: usize,
+}
+
+impl Budget {
+    /// A budget of `limit`, 0 never makes anyone wait
+    pub fn new(limit: usize) -> Budget {
+        Budget {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self, amount: usize) -> BudgetPermit<'_> {
+        if self.limit == 0 {
+            return BudgetPermit {
+                budget: self,
+                amount: 0,
+            };
+        }
+        let amount = amount.min(self.limit);
+        let mut used = self.used.lock().unwrap();
+        while *used + amount > self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += amount;
+        BudgetPermit {
+            budget: self,
+            amount,
+        }
+    }
+
+    pub fn get_used(&self) -> usize {
+        *self.used.lock().unwrap()
+    }
+}
+
+impl Drop for BudgetPermit<'_> {
+    fn drop(&mut self) {
+        if self.amount == 0 {
+            return;
+        }
+        *self.budget.used.lock().unwrap() -= self.amount;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Items not handed out yet, taken by one worker at a time so they stay in order
+struct Pending<I: Iterator> {
+    items: I,
+    /// Whether `items` ran out
+    taken: bool,
+    /// Items taken off `items` that wait for an item in flight, in order
+    waiting: VecDeque<I::Item>,
+}
+
+struct QueueState<K> {
+    /// Keys of the items in flight, with how many hold each
+    in_flight: HashMap<K, usize>,
+    closed: bool,
+}
+
+impl<K: Eq + Hash> QueueState<K> {
+    fn is_in_flight(&self, key: &Option<K>) -> bool {
+        match key {
+            Some(key) => self.in_flight.contains_key(key),
+            None => false,
+        }
+    }
+}
+
+/// Hands the items of an iterator to the workers, in order
+///
+/// Items waiting for another one (e.g. a hardlink, which needs the file it
+/// links to) are held back while the item with that key is in flight, the
+/// items after them are handed out meanwhile. Items are keyed by `key`, and
+/// wait for the key `waits_for` gives them.
+pub struct WorkQueue<I: Iterator, K> {
+    pending: Mutex<Pending<I>>,
+    state: Mutex<QueueState<K>>,
+    done: Condvar,
+    key: fn(&I::Item) -> Option<K>,
+    waits_for: fn(&I::Item) -> Option<K>,
+}
+
+/// An item being worked on, done once dropped
+pub struct InFlight<'a, I: Iterator, K: Eq + Hash> {
+    queue: &'a WorkQueue<I, K>,
+    key: Option<K>,
+}
+
+impl<I: Iterator, K: Eq + Hash + Clone> WorkQueue<I, K> {
+    pub fn new(
+        items: I,
+        key: fn(&I::Item) -> Option<K>,
+        waits_for: fn(&I::Item) -> Option<K>,
+    ) -> WorkQueue<I, K> {
+        WorkQueue {
+            pending: Mutex::new(Pending {
+                items,
+                taken: false,
+                waiting: VecDeque::new(),
+            }),
+            state: Mutex::new(QueueState {
+                in_flight: HashMap::new(),
+                closed: false,
+            }),
+            done: Condvar::new(),
+            key,
+            waits_for,
+        }
+    }
+
+    /// Next item to work on, none once the iterator ran out or the queue was closed
+    pub fn next(&self) -> Option<(I::Item, InFlight<'_, I, K>)> {
+        let mut pending = self.pending.lock().unwrap();
+        let pending = &mut *pending;
+        loop {
+            let state = self.state.lock().unwrap();
+            if state.closed {
+                return None;
+            }
+            // Held back items go first once their wait is over
+            let ready = pending
+                .waiting
+                .iter()
+                .position(|item| !state.is_in_flight(&(self.waits_for)(item)));
+            if let Some(ready) = ready {
+                let item = pending.waiting.remove(ready).unwrap();
+                return Some(self.start(state, item));
+            }
+            if pending.taken {
+                if pending.waiting.is_empty() {
+                    return None;
+                }
+                drop(self.done.wait(state).unwrap());
+                continue;
+            }
+            drop(state);
+
+            // Taking an item can be slow (e.g. loading the next batch), items
+            // in flight still finish meanwhile
+            let item = match pending.items.next() {
+                Some(item) => item,
+                None => {
+                    pending.taken = true;
+                    continue;
+                }
+            };
+            let state = self.state.lock().unwrap();
+            if state.is_in_flight(&(self.waits_for)(&item)) {
+                pending.waiting.push_back(item);
+                continue;
+            }
+            return Some(self.start(state, item));
+        }
+    }
+
+    fn start(
+        &self,
+        mut state: MutexGuard<QueueState<K>>,
+        item: I::Item,
+    ) -> (I::Item, InFlight<'_, I, K>) {
+        let key = (self.key)(&item);
+        if let Some(key) = &key {
+            *state.in_flight.entry(key.clone()).or_default() += 1;
+        }
+        (item, InFlight { queue: self, key })
+    }
+
+    /// Stops handing out items, e.g. after an error. Items in flight still finish.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.done.notify_all();
+    }
+}
+
+impl<I: Iterator, K: Eq + Hash> Drop for InFlight<'_, I, K> {
+    fn drop(&mut self) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        let mut state = self.queue.state.lock().unwrap();
+        if let Some(holders) = state.in_flight.get_mut(&key) {
+            *holders -= 1;
+            if *holders == 0 {
+                state.in_flight.remove(&key);
+                self.queue.done.notify_all();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod workers_tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_budget_limits_in_flight() {
+        let budget = Budget::new(10);
+        let peak = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for amount in [4, 6, 8, 3, 25] {
+                let (budget, peak) = (&budget, &peak);
+                scope.spawn(move || {
+                    let _permit = budget.acquire(amount);
+                    peak.fetch_max(budget.get_used(), Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                });
+            }
+        });
+        // The oversized request ran on its own instead of waiting forever
+        assert_eq!(peak.load(Ordering::SeqCst), 10);
+        assert_eq!(budget.get_used(), 0);
+
+        let unlimited = Budget::new(0);
+        let _permit = unlimited.acquire(usize::MAX);
+        assert_eq!(unlimited.get_used(), 0);
+    }
+
+    /// Multiples of 5 wait for the item 4 before them
+    fn waits_for(item: &usize) -> Option<usize> {
+        match item % 5 {
+            0 if *item > 0 => Some(item - 4),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_queue_waits_for_items() {
+        let queue = WorkQueue::new(0..20usize, |item| Some(*item), waits_for);
+        let done = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while let Some((item, _in_flight)) = queue.next() {
+                        if let Some(waited) = waits_for(&item) {
+                            assert!(done.lock().unwrap().contains(&waited));
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                        done.lock().unwrap().push(item);
+                    }
+                });
+            }
+        });
+        let mut done = done.into_inner().unwrap();
+        done.sort();
+        assert_eq!(done, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_queue_hands_out_items_past_waiting_ones() {
+        let queue = WorkQueue::new(1..8usize, |item| Some(*item), waits_for);
+        let (first, first_in_flight) = queue.next().unwrap();
+        assert_eq!(first, 1);
+        let mut handed_out = Vec::new();
+        for _ in 0..3 {
+            let (item, _in_flight) = queue.next().unwrap();
+            handed_out.push(item);
+        }
+        // 5 waits for 1, the items after it do not
+        assert_eq!(handed_out, vec![2, 3, 4]);
+        let (item, _in_flight) = queue.next().unwrap();
+        assert_eq!(item, 6);
+        drop(first_in_flight);
+        let rest: Vec<usize> = std::iter::from_fn(|| queue.next().map(|(item, _)| item)).collect();
+        assert_eq!(rest, vec![5, 7]);
+    }
+
+    #[test]
+    fn test_queue_finishes_while_taking_items() {
+        // Taking the second item waits for the first one to be done
+        let (done, wait_done) = std::sync::mpsc::channel();
+        let items = (0..2).inspect(move |item| {
+            if *item == 1 {
+                wait_done.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+        });
+        let queue = WorkQueue::new(items, |_| None::<usize>, |_| None);
+        let (first, in_flight) = queue.next().unwrap();
+        assert_eq!(first, 0);
+        thread::scope(|scope| {
+            let second = scope.spawn(|| queue.next().map(|(item, _)| item));
+            thread::sleep(Duration::from_millis(20));
+            drop(in_flight);
+            done.send(()).unwrap();
+            assert_eq!(second.join().unwrap(), Some(1));
+        });
+    }
+
+    #[test]
+    fn test_queue_close() {
+        let queue = WorkQueue::new(0..10, |_| None::<usize>, |_| None);
+        let (first, _in_flight) = queue.next().unwrap();
+        assert_eq!(first, 0);
+        queue.close();
+        assert!(queue.next().is_none());
+    }
+}
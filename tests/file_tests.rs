@@ -1,6 +1,3 @@
-use std::{path::PathBuf, io::Write};
-
-
 #[test]
 fn test_add() {
     